@@ -0,0 +1,792 @@
+//! Compile signatures written as x86-64 assembly into patterns
+//!
+//! Instructions use Intel syntax and are separated by `;`:
+//!
+//! ```text
+//! lea rdx, [rip + X0x1234]; mov r8d, ?; | call ?
+//! ```
+//!
+//! - `?` matches any immediate, displacement or branch target
+//! - `?r8`, `?r16`, `?r32`, `?r64` and `?xmm` match any register of that class
+//! - `X0x...`, `S0x...`, `I0x...` and `A0x...` are xrefs as in [`Pattern::new`], a `X` xref in a
+//!   memory operand implies `rip` as the base
+//! - memory operands are written `[base + index*scale + disp]` with an optional `qword ptr` style
+//!   size and segment (`gs:[0x58]`)
+//! - `|` before an instruction sets the custom offset of the pattern
+//!
+//! Each instruction is encoded with every opcode of its mnemonic that accepts the operands. Only
+//! the shortest encodings are kept, per immediate and displacement size if any are wildcards,
+//! which matches what compilers emit, and indirect `jmp` also matches with the redundant REX.W MSVC
+//! emits for jumps out of a function. Register wildcards are merged into bit masks or byte
+//! alternatives and the patterns returned are every combination of the instruction encodings.
+
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Context, Result};
+use iced_x86::{Code, Encoder, Instruction, MemoryOperand, OpCodeOperandKind, Register};
+use itertools::Itertools;
+
+use crate::scanner::{Pattern, PatternSimple, Xref, XrefKind};
+
+/// Max number of patterns a signature can expand to
+const MAX_PATTERNS: usize = 256;
+
+/// Address instructions are encoded at, rip-relative and branch targets point here
+const IP: u64 = 0x1000_0000;
+
+/// Compile an assembly signature into the patterns matching every encoding of it
+pub fn compile(s: &str) -> Result<Vec<Pattern>> {
+    let mut patterns = vec![(Fragment::default(), 0)];
+
+    for statement in s.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+        let statement = match statement.strip_prefix('|') {
+            Some(rest) => {
+                for (fragment, custom_offset) in &mut patterns {
+                    *custom_offset = fragment.sig.len();
+                }
+                rest.trim()
+            }
+            None => statement,
+        };
+        if statement.is_empty() {
+            continue;
+        }
+
+        let variants = compile_instruction(statement)
+            .with_context(|| format!("failed to compile \"{statement}\""))?;
+        if patterns.len() * variants.len() > MAX_PATTERNS {
+            bail!("signature expands to more than {MAX_PATTERNS} patterns");
+        }
+        patterns = patterns
+            .iter()
+            .cartesian_product(&variants)
+            .map(|((fragment, custom_offset), variant)| {
+                let mut fragment = fragment.clone();
+                fragment.append(variant);
+                (fragment, *custom_offset)
+            })
+            .unique()
+            .collect();
+    }
+
+    if patterns[0].0.sig.is_empty() {
+        bail!("signature must contain at least one instruction");
+    }
+    Ok(patterns
+        .into_iter()
+        .map(|(fragment, custom_offset)| Pattern {
+            simple: PatternSimple {
+                sig: fragment.sig,
+                mask: fragment.mask,
+            },
+            custom_offset,
+            captures: vec![],
+            xrefs: fragment.xrefs,
+            jumps: vec![],
+            alternatives: fragment.alternatives,
+            constraints: vec![],
+            limit: None,
+            align: 1,
+            function_start: false,
+        })
+        .collect())
+}
+
+/// Part of a pattern matching one or more instructions
+#[derive(Debug, Default, Clone, Hash, Eq, PartialEq)]
+struct Fragment {
+    sig: Vec<u8>,
+    mask: Vec<u8>,
+    alternatives: Vec<(usize, Vec<(u8, u8)>)>,
+    xrefs: Vec<(usize, XrefKind, Xref)>,
+}
+
+impl Fragment {
+    fn append(&mut self, other: &Fragment) {
+        let offset = self.sig.len();
+        self.sig.extend(&other.sig);
+        self.mask.extend(&other.mask);
+        self.alternatives.extend(
+            other
+                .alternatives
+                .iter()
+                .map(|(i, alts)| (offset + i, alts.clone())),
+        );
+        self.xrefs.extend(
+            other
+                .xrefs
+                .iter()
+                .map(|(i, kind, xref)| (offset + i, *kind, *xref)),
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RegisterClass {
+    R8,
+    R16,
+    R32,
+    R64,
+    Xmm,
+}
+
+impl RegisterClass {
+    fn registers(self) -> Vec<Register> {
+        Register::values()
+            .filter(|r| match self {
+                Self::R8 => r.is_gpr8(),
+                Self::R16 => r.is_gpr16(),
+                Self::R32 => r.is_gpr32(),
+                Self::R64 => r.is_gpr64(),
+                // higher registers are only encodable with EVEX
+                Self::Xmm => r.is_xmm() && r.number() < 16,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reg {
+    Exact(Register),
+    Any(RegisterClass),
+}
+
+impl Reg {
+    fn registers(self) -> Vec<Register> {
+        match self {
+            Self::Exact(r) => vec![r],
+            Self::Any(class) => class.registers(),
+        }
+    }
+}
+
+/// Immediate or displacement value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Exact(i64),
+    Any,
+    Xref(XrefKind, Xref),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    Register(Reg),
+    Immediate(Value),
+    Memory {
+        size: Option<usize>,
+        segment: Register,
+        base: Option<Reg>,
+        index: Option<(Reg, u32)>,
+        displacement: Value,
+    },
+}
+
+impl Operand {
+    fn has_wildcard_field(&self) -> bool {
+        match self {
+            Self::Register(_) => false,
+            Self::Immediate(value)
+            | Self::Memory {
+                displacement: value,
+                ..
+            } => !matches!(value, Value::Exact(_)),
+        }
+    }
+
+    /// Every concrete operand this can be encoded as
+    fn expand(&self) -> Vec<Concrete> {
+        match *self {
+            Self::Register(reg) => reg
+                .registers()
+                .into_iter()
+                .map(Concrete::Register)
+                .collect(),
+            Self::Immediate(value) => {
+                let sample = match value {
+                    Value::Exact(v) => v,
+                    _ => 0,
+                };
+                vec![Concrete::Immediate(sample, value)]
+            }
+            Self::Memory {
+                size,
+                segment,
+                base,
+                index,
+                displacement,
+            } => {
+                let bases = base.map(Reg::registers).unwrap_or(vec![Register::None]);
+                let indexes = index
+                    .map(|(reg, scale)| reg.registers().into_iter().map(|r| (r, scale)).collect())
+                    .unwrap_or(vec![(Register::None, 1)]);
+                bases
+                    .into_iter()
+                    .cartesian_product(indexes)
+                    .flat_map(|(base, (index, scale))| {
+                        // (displacement, displ_size) to encode, wildcards are tried as both disp8
+                        // and disp32
+                        let samples = match displacement {
+                            _ if base == Register::RIP => vec![(IP as i64, 1)],
+                            Value::Exact(0) => vec![(0, 0)],
+                            Value::Exact(v) => vec![(v, 1)],
+                            _ => vec![(0x10, 1), (0x1000, 1)],
+                        };
+                        samples.into_iter().map(move |(displ, displ_size)| {
+                            let memory = MemoryOperand::new(
+                                base, index, scale, displ, displ_size, false, segment,
+                            );
+                            Concrete::Memory(memory, size, displacement)
+                        })
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Operand with register wildcards resolved
+#[derive(Debug, Clone, Copy)]
+enum Concrete {
+    Register(Register),
+    /// Value to encode and the value it stands for
+    Immediate(i64, Value),
+    Memory(MemoryOperand, Option<usize>, Value),
+}
+
+fn compile_instruction(s: &str) -> Result<Vec<Fragment>> {
+    let (mnemonic, operands) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+    let operands = operands
+        .split(',')
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+        .map(|o| parse_operand(o).with_context(|| format!("bad operand \"{o}\"")))
+        .collect::<Result<Vec<_>>>()?;
+
+    let codes = Code::values()
+        .filter(|code| {
+            let op_code = code.op_code();
+            op_code.is_instruction()
+                && op_code.mode64()
+                && op_code.op_count() as usize == operands.len()
+                && format!("{:?}", code.mnemonic()).eq_ignore_ascii_case(mnemonic)
+        })
+        .collect_vec();
+    if codes.is_empty() {
+        bail!(
+            "no \"{mnemonic}\" instruction with {} operands",
+            operands.len()
+        );
+    }
+
+    let wildcard_fields = operands.iter().any(Operand::has_wildcard_field);
+    let assignments = if operands.is_empty() {
+        vec![vec![]]
+    } else {
+        operands
+            .iter()
+            .map(Operand::expand)
+            .multi_cartesian_product()
+            .collect_vec()
+    };
+
+    // encodings of every register assignment grouped by everything but the varying bytes
+    let mut groups: Vec<(Code, Fragment, Vec<Vec<u8>>)> = vec![];
+    for assignment in assignments {
+        let encodings = codes
+            .iter()
+            .filter_map(|code| encode(*code, &assignment))
+            .collect_vec();
+        let shortest = encodings
+            .iter()
+            .into_group_map_by(|e| {
+                wildcard_fields.then_some((e.immediate_size, e.displacement_size))
+            })
+            .into_values()
+            .flat_map(|group| {
+                let min = group.iter().map(|e| e.fragment.sig.len()).min().unwrap();
+                group
+                    .into_iter()
+                    .filter(move |e| e.fragment.sig.len() == min)
+            });
+        for encoding in shortest {
+            let key = Fragment {
+                sig: vec![],
+                ..encoding.fragment.clone()
+            };
+            match groups
+                .iter_mut()
+                .find(|(code, fragment, _)| *code == encoding.code && *fragment == key)
+            {
+                Some((_, _, sigs)) => sigs.push(encoding.fragment.sig.clone()),
+                None => groups.push((encoding.code, key, vec![encoding.fragment.sig.clone()])),
+            }
+        }
+    }
+    if groups.is_empty() {
+        bail!("no encoding of \"{mnemonic}\" accepts the operands");
+    }
+
+    Ok(groups
+        .into_iter()
+        .flat_map(|(code, fragment, sigs)| {
+            let fragment = merge(fragment, &sigs);
+            // MSVC marks indirect jumps out of a function with a redundant REX.W for the unwinder
+            let rex_w = (code == Code::Jmp_rm64).then(|| with_rex_w(&fragment));
+            std::iter::once(fragment).chain(rex_w)
+        })
+        .unique()
+        .collect())
+}
+
+/// Same instruction with REX.W set, adding a REX prefix if there is none
+fn with_rex_w(fragment: &Fragment) -> Fragment {
+    const LEGACY_PREFIXES: [u8; 11] = [
+        0x26, 0x2e, 0x36, 0x3e, 0x64, 0x65, 0x66, 0x67, 0xf0, 0xf2, 0xf3,
+    ];
+    let i = fragment
+        .sig
+        .iter()
+        .zip(&fragment.mask)
+        .position(|(s, m)| *m != 0xff || !LEGACY_PREFIXES.contains(s))
+        .unwrap_or(fragment.sig.len());
+
+    let mut fragment = fragment.clone();
+    if fragment.sig[i] & 0xf0 == 0x40 && fragment.mask[i] & 0xf0 == 0xf0 {
+        fragment.sig[i] |= 0x08;
+        fragment.mask[i] |= 0x08;
+        for (offset, alts) in &mut fragment.alternatives {
+            if *offset == i {
+                alts.iter_mut().for_each(|(sig, _)| *sig |= 0x08);
+            }
+        }
+    } else {
+        fragment.sig.insert(i, 0x48);
+        fragment.mask.insert(i, 0xff);
+        for (offset, _) in &mut fragment.alternatives {
+            *offset += (*offset >= i) as usize;
+        }
+        for (offset, _, _) in &mut fragment.xrefs {
+            *offset += (*offset >= i) as usize;
+        }
+    }
+    fragment
+}
+
+struct Encoding {
+    code: Code,
+    fragment: Fragment,
+    immediate_size: usize,
+    displacement_size: usize,
+}
+
+fn is_branch(code: Code) -> bool {
+    matches!(
+        code.op_code().op_kind(0),
+        OpCodeOperandKind::br64_1 | OpCodeOperandKind::br64_4
+    )
+}
+
+fn is_relative(kind: XrefKind) -> bool {
+    matches!(kind, XrefKind::Rel32 | XrefKind::Rel8)
+}
+
+/// Construct an instruction from operands, `None` if the code does not accept them
+fn build(code: Code, operands: &[Concrete]) -> Option<Instruction> {
+    use Concrete::*;
+
+    if is_branch(code) {
+        // branch targets are relative to where the instruction is so can only be wildcards
+        return match operands {
+            [Immediate(_, value)] if !matches!(value, Value::Exact(_)) => {
+                Instruction::with_branch(code, IP).ok()
+            }
+            _ => None,
+        };
+    }
+
+    // pass immediates as the narrowest type so the encoder can check they fit
+    macro_rules! imm {
+        ($value:expr, |$i:ident| $e:expr) => {
+            if let Ok($i) = i32::try_from($value) {
+                $e
+            } else if let Ok($i) = u32::try_from($value) {
+                $e
+            } else {
+                return None;
+            }
+        };
+    }
+
+    let instruction = match *operands {
+        [] => Ok(Instruction::with(code)),
+        [Register(r)] => Instruction::with1(code, r),
+        [Immediate(i, _)] => imm!(i, |i| Instruction::with1(code, i)),
+        [Memory(m, ..)] => Instruction::with1(code, m),
+        [Register(r0), Register(r1)] => Instruction::with2(code, r0, r1),
+        [Register(r), Immediate(i, _)] => match i32::try_from(i) {
+            Ok(i) => Instruction::with2(code, r, i),
+            Err(_) => match u32::try_from(i) {
+                Ok(i) => Instruction::with2(code, r, i),
+                Err(_) => Instruction::with2(code, r, i),
+            },
+        },
+        [Register(r), Memory(m, ..)] => Instruction::with2(code, r, m),
+        [Immediate(i, _), Register(r)] => imm!(i, |i| Instruction::with2(code, i, r)),
+        [Memory(m, ..), Register(r)] => Instruction::with2(code, m, r),
+        [Memory(m, ..), Immediate(i, _)] => imm!(i, |i| Instruction::with2(code, m, i)),
+        [Register(r0), Register(r1), Register(r2)] => Instruction::with3(code, r0, r1, r2),
+        [Register(r0), Register(r1), Immediate(i, _)] => {
+            imm!(i, |i| Instruction::with3(code, r0, r1, i))
+        }
+        [Register(r0), Register(r1), Memory(m, ..)] => Instruction::with3(code, r0, r1, m),
+        [Register(r0), Memory(m, ..), Register(r1)] => Instruction::with3(code, r0, m, r1),
+        [Register(r), Memory(m, ..), Immediate(i, _)] => {
+            imm!(i, |i| Instruction::with3(code, r, m, i))
+        }
+        [Memory(m, ..), Register(r0), Register(r1)] => Instruction::with3(code, m, r0, r1),
+        [Memory(m, ..), Register(r), Immediate(i, _)] => {
+            imm!(i, |i| Instruction::with3(code, m, r, i))
+        }
+        _ => return None,
+    }
+    .ok()?;
+
+    let size_matches = operands.iter().all(|o| match o {
+        Memory(_, Some(size), _) => instruction.memory_size().size() == *size,
+        _ => true,
+    });
+    size_matches.then_some(instruction)
+}
+
+/// Encode and wildcard the fields standing for wildcards or xrefs
+fn encode(code: Code, operands: &[Concrete]) -> Option<Encoding> {
+    let instruction = build(code, operands)?;
+    let mut encoder = Encoder::new(64);
+    encoder.encode(&instruction, IP).ok()?;
+    let offsets = encoder.get_constant_offsets();
+    let sig = encoder.take_buffer();
+
+    let mut fragment = Fragment {
+        mask: vec![0xff; sig.len()],
+        sig,
+        ..Default::default()
+    };
+    for operand in operands {
+        // branch displacements are reported as immediates
+        let (value, field, relative) = match operand {
+            Concrete::Immediate(_, value) => (
+                value,
+                offsets
+                    .has_immediate()
+                    .then(|| (offsets.immediate_offset(), offsets.immediate_size())),
+                is_branch(code),
+            ),
+            Concrete::Memory(memory, _, value) => (
+                value,
+                offsets
+                    .has_displacement()
+                    .then(|| (offsets.displacement_offset(), offsets.displacement_size())),
+                memory.base == Register::RIP,
+            ),
+            Concrete::Register(_) => continue,
+        };
+        if let Value::Exact(_) = value {
+            continue;
+        }
+        let (offset, size) = field?;
+        fragment.sig[offset..offset + size].fill(0);
+        fragment.mask[offset..offset + size].fill(0);
+        if let Value::Xref(kind, xref) = value {
+            if kind.len() != size || is_relative(*kind) != relative {
+                return None;
+            }
+            fragment.xrefs.push((offset, *kind, *xref));
+        }
+    }
+
+    Some(Encoding {
+        code,
+        fragment,
+        immediate_size: offsets.immediate_size(),
+        displacement_size: offsets.displacement_size(),
+    })
+}
+
+/// Merge encodings differing only by register into a fragment matching all of them
+fn merge(mut fragment: Fragment, sigs: &[Vec<u8>]) -> Fragment {
+    fragment.sig = sigs[0].clone();
+    for i in 0..fragment.sig.len() {
+        if fragment.mask[i] == 0 {
+            continue;
+        }
+        let values = sigs.iter().map(|s| s[i]).collect::<BTreeSet<_>>();
+        let first = sigs[0][i];
+        let shared = values.iter().fold(0xff, |m, v| m & !(v ^ first));
+        fragment.sig[i] = first & shared;
+        fragment.mask[i] = shared;
+        // use a bit mask if the values are every combination of the differing bits
+        if values.len() != 1 << (!shared).count_ones() {
+            fragment
+                .alternatives
+                .push((i, values.into_iter().map(|v| (v, 0xff)).collect()));
+        }
+    }
+    fragment
+}
+
+fn parse_operand(s: &str) -> Result<Operand> {
+    if s.contains('[') {
+        return parse_memory(s);
+    }
+    if let Some(reg) = parse_reg(s) {
+        return Ok(Operand::Register(reg));
+    }
+    Ok(Operand::Immediate(parse_value(s)?))
+}
+
+fn parse_memory(s: &str) -> Result<Operand> {
+    let (prefix, rest) = s.split_once('[').unwrap();
+    let inner = rest.strip_suffix(']').context("expected closing ]")?;
+
+    let mut prefix = prefix.trim().to_ascii_lowercase();
+    let mut segment = Register::None;
+    if let Some(p) = prefix.strip_suffix(':') {
+        let (size, seg) = p.rsplit_once(char::is_whitespace).unwrap_or(("", p));
+        segment = match parse_reg(seg) {
+            Some(Reg::Exact(r)) if r.is_segment_register() => r,
+            _ => bail!("bad segment \"{seg}\""),
+        };
+        prefix = size.trim().to_string();
+    }
+    let size = match prefix.strip_suffix("ptr").unwrap_or(&prefix).trim() {
+        "" => None,
+        "byte" => Some(1),
+        "word" => Some(2),
+        "dword" => Some(4),
+        "qword" => Some(8),
+        "xmmword" => Some(16),
+        p => bail!("unknown memory size \"{p}\""),
+    };
+
+    let mut terms = vec![];
+    let mut negative = false;
+    let mut start = 0;
+    for (i, c) in inner.char_indices().chain([(inner.len(), '+')]) {
+        if c == '+' || c == '-' {
+            let term = inner[start..i].trim();
+            if !term.is_empty() {
+                terms.push((negative, term));
+            } else if i != 0 {
+                bail!("empty term in memory operand");
+            }
+            negative = c == '-';
+            start = i + 1;
+        }
+    }
+
+    let mut base = None;
+    let mut index = None;
+    let mut displacement = None;
+    for (negative, term) in terms {
+        if let Some((a, b)) = term.split_once('*') {
+            let (reg, scale) = match (parse_reg(a.trim()), parse_reg(b.trim())) {
+                (Some(reg), None) => (reg, b.trim()),
+                (None, Some(reg)) => (reg, a.trim()),
+                _ => bail!("bad scaled index \"{term}\""),
+            };
+            let scale = match scale {
+                "1" => 1,
+                "2" => 2,
+                "4" => 4,
+                "8" => 8,
+                _ => bail!("bad scale \"{scale}\""),
+            };
+            if negative || index.replace((reg, scale)).is_some() {
+                bail!("bad index \"{term}\"");
+            }
+        } else if let Some(reg) = parse_reg(term) {
+            if negative {
+                bail!("registers cannot be subtracted");
+            }
+            if base.is_none() {
+                base = Some(reg);
+            } else if index.is_none() {
+                index = Some((reg, 1));
+            } else {
+                bail!("too many registers");
+            }
+        } else {
+            let value = match parse_value(term)? {
+                Value::Exact(v) if negative => Value::Exact(v.wrapping_neg()),
+                _ if negative => bail!("wildcards cannot be subtracted"),
+                value => value,
+            };
+            if displacement.replace(value).is_some() {
+                bail!("more than one displacement");
+            }
+        }
+    }
+
+    let displacement = displacement.unwrap_or(Value::Exact(0));
+    if let Value::Xref(kind, _) = displacement {
+        if kind != XrefKind::Rel32 {
+            bail!("only X xrefs can be used as a displacement");
+        }
+        match base {
+            None if index.is_none() => base = Some(Reg::Exact(Register::RIP)),
+            Some(Reg::Exact(Register::RIP)) => {}
+            _ => bail!("xref displacements must be rip-relative"),
+        }
+    }
+    if base == Some(Reg::Exact(Register::RIP)) && matches!(displacement, Value::Exact(_)) {
+        bail!("rip-relative displacement must be ? or an xref");
+    }
+
+    Ok(Operand::Memory {
+        size,
+        segment,
+        base,
+        index,
+        displacement,
+    })
+}
+
+fn parse_reg(s: &str) -> Option<Reg> {
+    let s = s.to_ascii_lowercase();
+    let class = match s.as_str() {
+        "?r8" => Some(RegisterClass::R8),
+        "?r16" => Some(RegisterClass::R16),
+        "?r32" => Some(RegisterClass::R32),
+        "?r64" => Some(RegisterClass::R64),
+        "?xmm" => Some(RegisterClass::Xmm),
+        _ => None,
+    };
+    if let Some(class) = class {
+        return Some(Reg::Any(class));
+    }
+    // iced names the low byte of r8-r15 r8l-r15l
+    let s = match s.strip_prefix('r').and_then(|n| n.strip_suffix('b')) {
+        Some(n) if n.parse::<u8>().is_ok() => format!("r{n}l"),
+        _ => s,
+    };
+    Register::values()
+        .filter(|r| *r != Register::None)
+        .find(|r| format!("{r:?}").eq_ignore_ascii_case(&s))
+        .map(Reg::Exact)
+}
+
+fn parse_value(s: &str) -> Result<Value> {
+    if s == "?" {
+        return Ok(Value::Any);
+    }
+    if let Some(value) = parse_number(s) {
+        return Ok(Value::Exact(value));
+    }
+    if let Some((kind, address)) = XrefKind::split_prefix(s) {
+        if let Some(address) = parse_number(address).and_then(|a| usize::try_from(a).ok()) {
+            return Ok(Value::Xref(kind, Xref(address)));
+        }
+    }
+    bail!("expected register, number, ? or xref")
+}
+
+fn parse_number(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let value = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => s.parse::<u64>().ok()?,
+    } as i64;
+    Some(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scanner::PatternSet;
+
+    /// Addresses of every match of any of the patterns
+    fn scan(patterns: &[Pattern], base_address: usize, data: &[u8]) -> Vec<usize> {
+        let set = PatternSet::new(&patterns.iter().collect::<Vec<_>>());
+        let mut matches = set.scan(base_address, data).concat();
+        matches.sort();
+        matches.dedup();
+        matches
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // FFrame::Step
+        let patterns = compile(
+            "mov rax, qword ptr [rcx + 0x20]; mov r10, rdx; mov rdx, rcx; \
+             movzx r9d, byte ptr [rax]; inc rax; mov qword ptr [rcx + 0x20], rax; mov eax, r9d; \
+             lea r9, [rip + ?]; mov rcx, r10; jmp qword ptr [r9 + rax*8]",
+        )
+        .unwrap();
+        let data = Pattern::new(
+            "48 8B 41 20 4C 8B D2 48 8B D1 44 0F B6 08 48 FF C0 48 89 41 20 41 8B C1 \
+             4C 8D 0D 12 34 56 78 49 8B CA 49 FF 24 C1",
+        )
+        .unwrap()
+        .simple
+        .sig;
+        assert_eq!(vec![0x1000], scan(&patterns, 0x1000, &data));
+        // r10 in place of r9
+        let mut other = data.clone();
+        other[26] = 0x15;
+        assert_eq!(Vec::<usize>::new(), scan(&patterns, 0x1000, &other));
+
+        // lea rdx, [rip + 0xff9]; mov r8d, 1; call 0x1012
+        let data = [
+            0x48, 0x8d, 0x15, 0xf9, 0x0f, 0x00, 0x00, 0x41, 0xb8, 0x01, 0x00, 0x00, 0x00, 0xe8,
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        let patterns = compile("lea rdx, [rip + X0x2000]; mov r8d, ?; | call ?").unwrap();
+        assert_eq!(vec![0x100d], scan(&patterns, 0x1000, &data));
+        // the xref resolves relative to where the data is
+        assert_eq!(Vec::<usize>::new(), scan(&patterns, 0x1001, &data));
+    }
+
+    #[test]
+    fn test_register_wildcards() {
+        let patterns = compile("mov ?r64, qword ptr gs:[0x58]").unwrap();
+        // mov rax, gs:[0x58]; mov r9, gs:[0x58]; mov eax, gs:[0x58]
+        let data = [
+            0x65, 0x48, 0x8b, 0x04, 0x25, 0x58, 0x00, 0x00, 0x00, 0x65, 0x4c, 0x8b, 0x0c, 0x25,
+            0x58, 0x00, 0x00, 0x00, 0x65, 0x8b, 0x04, 0x25, 0x58, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(vec![0, 9], scan(&patterns, 0, &data));
+
+        // jmp rax; rex.w jmp rax; jmp [r9 + rax*8]; rex.w jmp [r9 + rax*8]
+        let patterns = compile("jmp ?r64").unwrap();
+        // the plain jmp also matches inside the prefixed one
+        assert_eq!(
+            vec![0, 2, 3],
+            scan(&patterns, 0, &[0xff, 0xe0, 0x48, 0xff, 0xe0])
+        );
+        let patterns = compile("jmp qword ptr [r9 + rax*8]").unwrap();
+        let data = [0x41, 0xff, 0x24, 0xc1, 0x49, 0xff, 0x24, 0xc1];
+        assert_eq!(vec![0, 4], scan(&patterns, 0, &data));
+
+        // both encodings of a register to register move
+        let patterns = compile("mov ?r32, ecx").unwrap();
+        assert_eq!(vec![0, 2], scan(&patterns, 0, &[0x89, 0xc8, 0x8b, 0xc1]));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(compile("").is_err());
+        assert!(compile("mov rax").is_err());
+        assert!(compile("nop rax, rax, rax").is_err());
+        assert!(compile("lea rax, [rip + 0x10]").is_err());
+        assert!(compile("lea rax, [rcx + X0x10]").is_err());
+        assert!(compile("mov rax, qword ptr [rcx - ?]").is_err());
+        assert!(compile("mov rax, dword ptr [rcx]").is_err());
+    }
+}
@@ -0,0 +1,642 @@
+//! Control flow graphs of functions
+//!
+//! ```ignore
+//! let cfg = image.control_flow_graph(function)?;
+//! for call in cfg.calls_to(callee) {
+//!     // e.g. find the argument loaded into rcx before the call
+//!     let rcx = cfg
+//!         .instructions_before(call.ip)
+//!         .find(|inst| inst.op0_register() == Register::RCX);
+//! }
+//! ```
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ops::Range,
+    sync::OnceLock,
+};
+
+use iced_x86::{Decoder, DecoderOptions, FlowControl, Instruction};
+
+use crate::{Image, MemoryAccessError, MemoryTrait};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// Execution continues into the next block
+    Fallthrough,
+    /// Taken side of a conditional jump
+    Conditional,
+    /// Unconditional jump
+    Unconditional,
+    /// Direct call, the target is a callee rather than a block of the graph
+    Call,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    /// Start of the block the edge leaves
+    pub from: usize,
+    /// Address of the instruction the edge leaves from
+    pub ip: usize,
+    /// Target address
+    pub to: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub range: Range<usize>,
+    pub instructions: Vec<Instruction>,
+}
+
+/// Basic blocks reachable from the start of a function without leaving it. Branches into other
+/// functions are treated as tail calls.
+#[derive(Debug, Clone)]
+pub struct ControlFlowGraph {
+    pub start: usize,
+    /// Blocks keyed by start address
+    pub blocks: BTreeMap<usize, BasicBlock>,
+    pub edges: Vec<Edge>,
+    /// Addresses of instructions which leave the function: returns, indirect jumps and tail calls
+    pub exits: Vec<usize>,
+    /// Jumps to other functions
+    pub tail_calls: Vec<Edge>,
+    dominators: OnceLock<HashMap<usize, usize>>,
+}
+
+impl ControlFlowGraph {
+    /// Decode the function starting at `start`. Code is considered part of the function while it
+    /// has the same root function as `start` in the image's function table (or both have none).
+    pub fn build(image: &Image<'_>, start: usize) -> Result<Self, MemoryAccessError> {
+        Self::build_with_slack(image, start, 0)
+    }
+
+    /// Like [`ControlFlowGraph::build`] but code less than `slack` bytes past `start` is also
+    /// considered part of the function, for when the function table splits a function into
+    /// several root functions
+    pub fn build_with_slack(
+        image: &Image<'_>,
+        start: usize,
+        slack: usize,
+    ) -> Result<Self, MemoryAccessError> {
+        let root = image.get_root_function(start)?.map(|f| f.range);
+        let inside = |address: usize| -> Result<bool, MemoryAccessError> {
+            // most code lies in the root function's own range so avoid the lookup
+            if root.as_ref().is_some_and(|r| r.contains(&address))
+                || (start..start.saturating_add(slack)).contains(&address)
+            {
+                return Ok(true);
+            }
+            Ok(image.get_root_function(address)?.map(|f| f.range.start)
+                == root.as_ref().map(|r| r.start))
+        };
+
+        // discover reachable instructions and the addresses blocks must start at
+        let mut instructions = BTreeMap::new();
+        let mut leaders = BTreeSet::from([start]);
+        let mut queue = vec![start];
+        while let Some(address) = queue.pop() {
+            let data = image.memory.range_from(address..)?;
+            let mut decoder = Decoder::with_ip(64, data, address as u64, DecoderOptions::NONE);
+            let mut inst = Instruction::default();
+            while decoder.can_decode() {
+                decoder.decode_out(&mut inst);
+                let ip = inst.ip() as usize;
+                if instructions.contains_key(&ip) {
+                    leaders.insert(ip);
+                    break;
+                }
+                if !inside(ip)? {
+                    // fell through into another function
+                    break;
+                }
+                instructions.insert(ip, inst);
+
+                match inst.flow_control() {
+                    FlowControl::ConditionalBranch | FlowControl::UnconditionalBranch => {
+                        let target = inst.near_branch_target() as usize;
+                        if inside(target)? && leaders.insert(target) {
+                            queue.push(target);
+                        }
+                        if inst.flow_control() == FlowControl::ConditionalBranch {
+                            leaders.insert(inst.next_ip() as usize);
+                        } else {
+                            break;
+                        }
+                    }
+                    FlowControl::Return
+                    | FlowControl::IndirectBranch
+                    | FlowControl::Interrupt
+                    | FlowControl::Exception => break,
+                    _ => {}
+                }
+            }
+        }
+
+        // split into blocks at leaders, terminators and gaps
+        let mut blocks: BTreeMap<usize, BasicBlock> = BTreeMap::new();
+        let mut current: Option<BasicBlock> = None;
+        for (&ip, inst) in &instructions {
+            let mut block = match current.take() {
+                Some(block) if block.range.end == ip && !leaders.contains(&ip) => block,
+                previous => {
+                    if let Some(block) = previous {
+                        blocks.insert(block.range.start, block);
+                    }
+                    BasicBlock {
+                        range: ip..ip,
+                        instructions: vec![],
+                    }
+                }
+            };
+            block.range.end = inst.next_ip() as usize;
+            block.instructions.push(*inst);
+            if matches!(
+                inst.flow_control(),
+                FlowControl::Next | FlowControl::Call | FlowControl::IndirectCall
+            ) {
+                current = Some(block);
+            } else {
+                blocks.insert(block.range.start, block);
+            }
+        }
+        if let Some(block) = current {
+            blocks.insert(block.range.start, block);
+        }
+
+        let mut edges = vec![];
+        let mut exits = vec![];
+        let mut tail_calls = vec![];
+        for block in blocks.values() {
+            let from = block.range.start;
+            for inst in &block.instructions {
+                if inst.flow_control() == FlowControl::Call {
+                    edges.push(Edge {
+                        kind: EdgeKind::Call,
+                        from,
+                        ip: inst.ip() as usize,
+                        to: inst.near_branch_target() as usize,
+                    });
+                }
+            }
+
+            let last = block.instructions.last().unwrap();
+            let ip = last.ip() as usize;
+            let fallthrough = match last.flow_control() {
+                FlowControl::ConditionalBranch | FlowControl::UnconditionalBranch => {
+                    let to = last.near_branch_target() as usize;
+                    let conditional = last.flow_control() == FlowControl::ConditionalBranch;
+                    let kind = if conditional {
+                        EdgeKind::Conditional
+                    } else {
+                        EdgeKind::Unconditional
+                    };
+                    let edge = Edge { kind, from, ip, to };
+                    if blocks.contains_key(&to) {
+                        edges.push(edge);
+                    } else {
+                        tail_calls.push(edge);
+                        exits.push(ip);
+                    }
+                    conditional
+                }
+                FlowControl::Return | FlowControl::IndirectBranch => {
+                    exits.push(ip);
+                    false
+                }
+                FlowControl::Interrupt | FlowControl::Exception => false,
+                _ => true,
+            };
+            if fallthrough && blocks.contains_key(&block.range.end) {
+                edges.push(Edge {
+                    kind: EdgeKind::Fallthrough,
+                    from,
+                    ip,
+                    to: block.range.end,
+                });
+            }
+        }
+
+        Ok(Self {
+            start,
+            blocks,
+            edges,
+            exits,
+            tail_calls,
+            dominators: Default::default(),
+        })
+    }
+
+    /// Block containing instruction at `address`
+    pub fn block_containing(&self, address: usize) -> Option<&BasicBlock> {
+        self.blocks
+            .range(..=address)
+            .next_back()
+            .map(|(_, block)| block)
+            .filter(|block| block.range.contains(&address))
+    }
+
+    /// Start addresses of blocks control can flow to from the block starting at `block`
+    pub fn successors(&self, block: usize) -> impl Iterator<Item = usize> + '_ {
+        self.edges
+            .iter()
+            .filter(move |e| e.kind != EdgeKind::Call && e.from == block)
+            .map(|e| e.to)
+    }
+
+    /// Start addresses of blocks control can flow from into the block starting at `block`
+    pub fn predecessors(&self, block: usize) -> impl Iterator<Item = usize> + '_ {
+        self.edges
+            .iter()
+            .filter(move |e| e.kind != EdgeKind::Call && e.to == block)
+            .map(|e| e.from)
+    }
+
+    /// Direct calls to `callee`
+    pub fn calls_to(&self, callee: usize) -> impl Iterator<Item = &Edge> {
+        self.edges
+            .iter()
+            .filter(move |e| e.kind == EdgeKind::Call && e.to == callee)
+    }
+
+    /// Instructions executed before the instruction at `ip`, nearest first. Continues into
+    /// preceding blocks only while there is a single predecessor.
+    pub fn instructions_before(&self, ip: usize) -> impl Iterator<Item = &Instruction> {
+        let mut instructions = vec![];
+        let mut visited = HashSet::new();
+        let mut end = ip;
+        let mut block = self.block_containing(ip);
+        while let Some(b) = block.filter(|b| visited.insert(b.range.start)) {
+            instructions.extend(
+                b.instructions
+                    .iter()
+                    .rev()
+                    .skip_while(|i| i.ip() as usize >= end),
+            );
+            end = usize::MAX;
+            block = match self.predecessors(b.range.start).collect::<Vec<_>>()[..] {
+                [single] => self.blocks.get(&single),
+                _ => None,
+            };
+        }
+        instructions.into_iter()
+    }
+
+    /// Immediate dominator of every block keyed by block start. The entry block is its own
+    /// immediate dominator.
+    pub fn dominators(&self) -> &HashMap<usize, usize> {
+        self.dominators.get_or_init(|| {
+            // "A Simple, Fast Dominance Algorithm" (Cooper, Harvey, Kennedy)
+            let mut postorder = vec![];
+            let mut visited = HashSet::from([self.start]);
+            let mut stack = vec![(self.start, self.successors(self.start).collect::<Vec<_>>())];
+            while let Some((block, successors)) = stack.last_mut() {
+                if let Some(next) = successors.pop() {
+                    if visited.insert(next) {
+                        stack.push((next, self.successors(next).collect()));
+                    }
+                } else {
+                    postorder.push(*block);
+                    stack.pop();
+                }
+            }
+            let index = postorder
+                .iter()
+                .enumerate()
+                .map(|(i, b)| (*b, i))
+                .collect::<HashMap<_, _>>();
+
+            let mut idom = HashMap::from([(self.start, self.start)]);
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for &block in postorder.iter().rev().skip(1) {
+                    let mut new = None;
+                    for pred in self.predecessors(block) {
+                        if !idom.contains_key(&pred) {
+                            continue;
+                        }
+                        new = Some(match new {
+                            None => pred,
+                            Some(mut a) => {
+                                let mut b = pred;
+                                while a != b {
+                                    while index[&a] < index[&b] {
+                                        a = idom[&a];
+                                    }
+                                    while index[&b] < index[&a] {
+                                        b = idom[&b];
+                                    }
+                                }
+                                a
+                            }
+                        });
+                    }
+                    if let Some(new) = new {
+                        if idom.insert(block, new) != Some(new) {
+                            changed = true;
+                        }
+                    }
+                }
+            }
+            idom
+        })
+    }
+
+    /// Whether every path from the function start to the instruction at `b` passes through the
+    /// instruction at `a`
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        let (Some(block_a), Some(block_b)) = (self.block_containing(a), self.block_containing(b))
+        else {
+            return false;
+        };
+        let (block_a, mut block) = (block_a.range.start, block_b.range.start);
+        if block_a == block {
+            return a <= b;
+        }
+        let dominators = self.dominators();
+        while let Some(&idom) = dominators.get(&block) {
+            if idom == block_a {
+                return true;
+            }
+            if idom == block {
+                break;
+            }
+            block = idom;
+        }
+        false
+    }
+}
+
+#[cfg(all(test, feature = "image-pe"))]
+mod test {
+    use std::sync::Arc;
+
+    use iced_x86::Register;
+
+    use super::*;
+    use crate::image::pe::PEImage;
+
+    /// Image with `code` written at each address of a `.text` section at 0x1000 and a root
+    /// function for each range of `functions`
+    fn image(code: &[(usize, &[u8])], functions: &[Range<usize>]) -> Image<'static> {
+        let mut text = vec![0xcc; 0x600];
+        for (address, bytes) in code {
+            text[address - 0x1000..address - 0x1000 + bytes.len()].copy_from_slice(bytes);
+        }
+        PEImage::synthetic(
+            vec![(".text", object::SectionKind::Text, 0x1000, text)],
+            functions,
+        )
+    }
+
+    fn edge(kind: EdgeKind, from: usize, ip: usize, to: usize) -> Edge {
+        Edge { kind, from, ip, to }
+    }
+
+    fn ips<'a>(instructions: impl Iterator<Item = &'a Instruction>) -> Vec<usize> {
+        instructions.map(|i| i.ip() as usize).collect()
+    }
+
+    #[test]
+    fn test_diamond() {
+        let image = image(
+            &[(
+                0x1000,
+                &[
+                    0x85, 0xc9, // test ecx, ecx
+                    0x74, 0x07, // je 0x100b
+                    0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+                    0xeb, 0x05, // jmp 0x1010
+                    0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, 2
+                    0xc3, // ret
+                ],
+            )],
+            &[0x1000..0x1100, 0x1100..0x1200],
+        );
+        let cfg = image.control_flow_graph(0x1000).unwrap();
+
+        assert_eq!(
+            vec![
+                0x1000..0x1004,
+                0x1004..0x100b,
+                0x100b..0x1010,
+                0x1010..0x1011
+            ],
+            cfg.blocks
+                .values()
+                .map(|b| b.range.clone())
+                .collect::<Vec<_>>()
+        );
+        let mut edges = cfg.edges.clone();
+        edges.sort_by_key(|e| (e.from, e.to));
+        assert_eq!(
+            vec![
+                edge(EdgeKind::Fallthrough, 0x1000, 0x1002, 0x1004),
+                edge(EdgeKind::Conditional, 0x1000, 0x1002, 0x100b),
+                edge(EdgeKind::Unconditional, 0x1004, 0x1009, 0x1010),
+                edge(EdgeKind::Fallthrough, 0x100b, 0x100b, 0x1010),
+            ],
+            edges
+        );
+        assert_eq!(vec![0x1010], cfg.exits);
+        assert!(cfg.tail_calls.is_empty());
+
+        assert_eq!(
+            HashMap::from([
+                (0x1000, 0x1000),
+                (0x1004, 0x1000),
+                (0x100b, 0x1000),
+                (0x1010, 0x1000),
+            ]),
+            *cfg.dominators()
+        );
+        assert!(cfg.dominates(0x1002, 0x1010));
+        assert!(cfg.dominates(0x1002, 0x1004));
+        // neither side of the diamond dominates the join
+        assert!(!cfg.dominates(0x1004, 0x1010));
+        assert!(!cfg.dominates(0x100b, 0x1010));
+        assert!(!cfg.dominates(0x1004, 0x100b));
+        // within a block only earlier instructions dominate
+        assert!(cfg.dominates(0x1000, 0x1002));
+        assert!(!cfg.dominates(0x1002, 0x1000));
+        assert!(!cfg.dominates(0x1000, 0x1100));
+
+        // the join has two predecessors so nothing before it is known
+        assert!(ips(cfg.instructions_before(0x1010)).is_empty());
+        assert_eq!(
+            vec![0x1004, 0x1002, 0x1000],
+            ips(cfg.instructions_before(0x1009))
+        );
+
+        // graphs are cached
+        assert!(Arc::ptr_eq(
+            &cfg,
+            &image.control_flow_graph(0x1000).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_loop() {
+        let image = image(
+            &[(
+                0x1100,
+                &[
+                    0x31, 0xc0, // xor eax, eax
+                    0xff, 0xc0, // inc eax
+                    0x39, 0xc8, // cmp eax, ecx
+                    0x7c, 0xfa, // jl 0x1102
+                    0xc3, // ret
+                ],
+            )],
+            &[0x1000..0x1100, 0x1100..0x1200],
+        );
+        let cfg = image.control_flow_graph(0x1100).unwrap();
+
+        assert_eq!(
+            vec![0x1100..0x1102, 0x1102..0x1108, 0x1108..0x1109],
+            cfg.blocks
+                .values()
+                .map(|b| b.range.clone())
+                .collect::<Vec<_>>()
+        );
+        let mut predecessors = cfg.predecessors(0x1102).collect::<Vec<_>>();
+        predecessors.sort();
+        assert_eq!(vec![0x1100, 0x1102], predecessors);
+        let mut successors = cfg.successors(0x1102).collect::<Vec<_>>();
+        successors.sort();
+        assert_eq!(vec![0x1102, 0x1108], successors);
+
+        assert_eq!(
+            HashMap::from([(0x1100, 0x1100), (0x1102, 0x1100), (0x1108, 0x1102)]),
+            *cfg.dominators()
+        );
+        assert!(cfg.dominates(0x1104, 0x1108));
+        assert!(cfg.dominates(0x1100, 0x1106));
+        assert!(!cfg.dominates(0x1108, 0x1104));
+
+        // the loop head has two predecessors
+        assert_eq!(vec![0x1104, 0x1102], ips(cfg.instructions_before(0x1106)));
+    }
+
+    #[test]
+    fn test_conditional_tail_call() {
+        let image = image(
+            &[
+                (
+                    0x1200,
+                    &[
+                        0x85, 0xc9, // test ecx, ecx
+                        0x0f, 0x84, 0xf8, 0x00, 0x00, 0x00, // je 0x1300
+                        0xe8, 0xf3, 0x00, 0x00, 0x00, // call 0x1300
+                        0xc3, // ret
+                    ],
+                ),
+                (0x1300, &[0xc3]),
+            ],
+            &[0x1200..0x1300, 0x1300..0x1400],
+        );
+        let cfg = image.control_flow_graph(0x1200).unwrap();
+
+        assert_eq!(
+            vec![0x1200..0x1208, 0x1208..0x120e],
+            cfg.blocks
+                .values()
+                .map(|b| b.range.clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![edge(EdgeKind::Conditional, 0x1200, 0x1202, 0x1300)],
+            cfg.tail_calls
+        );
+        assert_eq!(vec![0x1202, 0x120d], cfg.exits);
+        assert_eq!(vec![0x1208], cfg.successors(0x1200).collect::<Vec<_>>());
+        assert_eq!(
+            vec![&edge(EdgeKind::Call, 0x1208, 0x1208, 0x1300)],
+            cfg.calls_to(0x1300).collect::<Vec<_>>()
+        );
+        assert!(cfg.dominates(0x1202, 0x120d));
+    }
+
+    #[test]
+    fn test_instructions_before_chain() {
+        let image = image(
+            &[
+                (
+                    0x1400,
+                    &[
+                        0xb9, 0x01, 0x00, 0x00, 0x00, // mov ecx, 1
+                        0xeb, 0x03, // jmp 0x140a
+                    ],
+                ),
+                (
+                    0x140a,
+                    &[
+                        0xba, 0x02, 0x00, 0x00, 0x00, // mov edx, 2
+                        0xeb, 0x01, // jmp 0x1412
+                    ],
+                ),
+                (
+                    0x1412,
+                    &[
+                        0xe8, 0xe9, 0xfe, 0xff, 0xff, // call 0x1300
+                        0xc3, // ret
+                    ],
+                ),
+            ],
+            &[0x1300..0x1400, 0x1400..0x1500],
+        );
+        let cfg = image.control_flow_graph(0x1400).unwrap();
+
+        let call = cfg.calls_to(0x1300).next().unwrap().ip;
+        assert_eq!(0x1412, call);
+        assert_eq!(
+            vec![0x140f, 0x140a, 0x1405, 0x1400],
+            ips(cfg.instructions_before(call))
+        );
+        assert_eq!(
+            Some(0x1400),
+            cfg.instructions_before(call)
+                .find(|i| i.op0_register() == Register::ECX)
+                .map(|i| i.ip() as usize)
+        );
+        // padding between the blocks isn't part of the graph
+        assert!(cfg.block_containing(0x1407).is_none());
+    }
+
+    #[test]
+    fn test_slack() {
+        let image = image(
+            &[(
+                0x1500,
+                &[
+                    0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+                    0x90, 0x90, 0x90, // nop
+                    0xc3, // ret
+                ],
+            )],
+            // the function table splits the function before the ret
+            &[0x1500..0x1508, 0x1508..0x1510],
+        );
+
+        let cfg = ControlFlowGraph::build(&image, 0x1500).unwrap();
+        assert_eq!(
+            vec![0x1500..0x1508],
+            cfg.blocks
+                .values()
+                .map(|b| b.range.clone())
+                .collect::<Vec<_>>()
+        );
+        assert!(cfg.exits.is_empty());
+
+        let cfg = ControlFlowGraph::build_with_slack(&image, 0x1500, 0x10).unwrap();
+        assert_eq!(
+            vec![0x1500..0x1509],
+            cfg.blocks
+                .values()
+                .map(|b| b.range.clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(vec![0x1508], cfg.exits);
+    }
+}
@@ -240,6 +240,7 @@ impl ElfImage {
             image_type: ImageType::ElfImage(ElfImage {
                 functions: Some(functions),
             }),
+            control_flow_graphs: Default::default(),
         })
     }
 
@@ -323,7 +324,7 @@ impl ElfImage {
                 })
                 .collect::<Vec<_>>();
 
-            let memory = Memory { sections };
+            let memory = Memory::from_sections(sections);
 
             Self::read_inner_memory(base_address, exe_path, linked, memory, object)
         } else {
@@ -6,10 +6,12 @@ pub mod pe;
 
 use crate::*;
 use anyhow::Error;
+use cfg::ControlFlowGraph;
 #[cfg(feature = "image-elf")]
 use elf::ElfImage;
 #[cfg(feature = "image-pe")]
 use pe::PEImage;
+use std::sync::{Arc, Mutex};
 
 use macros::*;
 
@@ -40,6 +42,8 @@ pub struct Image<'data> {
     pub symbols: Option<HashMap<usize, symbols::Symbol>>,
     pub imports: HashMap<String, HashMap<String, usize>>,
     pub image_type: ImageType,
+    /// Control flow graphs keyed by function start, built on demand
+    pub(crate) control_flow_graphs: Mutex<HashMap<usize, Arc<ControlFlowGraph>>>,
 }
 
 // Type-independent
@@ -80,74 +84,203 @@ impl<'data> Image<'data> {
         resolvers::resolve_many(self, resolvers)
     }
 
-    pub fn scan<'patterns, S>(
+    /// Range of the root function containing `address` which unlike
+    /// [`Image::get_root_function_range`] does not have to be the start of a function
+    pub fn get_root_function_range_containing(
         &self,
-        pattern_configs: &'patterns [PatternConfig<S>],
-    ) -> Result<ScanResult<'patterns, S>> {
-        let mut results = vec![];
+        address: usize,
+    ) -> Result<Option<Range<usize>>, MemoryAccessError> {
+        match self.get_root_function(address)? {
+            Some(f) => self.get_root_function_range(f.range.start),
+            None => Ok(None),
+        }
+    }
 
-        struct PendingScan {
-            original_config_index: usize,
-            scan: Scan,
+    /// Constraint requiring the rip target of a capture to be inside a section of `kind`
+    pub fn rip_in_section(&self, kind: object::SectionKind) -> scanner::Constraint {
+        scanner::Constraint::rip_in_ranges(
+            self.memory
+                .sections()
+                .iter()
+                .filter(|s| s.kind() == kind)
+                .map(|s| s.address()..s.address() + s.len()),
+        )
+    }
+    /// Constraint requiring the rip target of a capture to be the start of a root function
+    pub fn rip_is_function_start(&self) -> Result<scanner::Constraint, MemoryAccessError> {
+        Ok(scanner::Constraint::rip_in_set(self.function_starts()?))
+    }
+    /// Sorted start addresses of every root function from the exception directory (PE) or FDE
+    /// list (ELF), used to check [`scanner::Pattern::function_start`]
+    pub fn function_starts(&self) -> Result<Vec<usize>, MemoryAccessError> {
+        let mut starts = self
+            .get_root_functions()?
+            .into_iter()
+            .map(|f| f.start)
+            .collect::<Vec<_>>();
+        starts.sort();
+        Ok(starts)
+    }
+
+    /// Control flow graph of the function starting at `address`, built once and cached
+    pub fn control_flow_graph(
+        &self,
+        address: usize,
+    ) -> Result<Arc<ControlFlowGraph>, MemoryAccessError> {
+        if let Some(cfg) = self.control_flow_graphs.lock().unwrap().get(&address) {
+            return Ok(cfg.clone());
         }
+        // built without holding the lock so other functions aren't blocked
+        let cfg = Arc::new(ControlFlowGraph::build(self, address)?);
+        Ok(self
+            .control_flow_graphs
+            .lock()
+            .unwrap()
+            .entry(address)
+            .or_insert(cfg)
+            .clone())
+    }
 
-        let scan_queue = pattern_configs
+    /// Scan every section with a precompiled [`PatternSet`], returning the matches of each
+    /// pattern. Limits apply to the matches of the whole image.
+    pub fn scan_pattern_set(
+        &self,
+        pattern_set: &PatternSet<'_>,
+    ) -> Result<Vec<ScanMatches<usize>>, MemoryAccessError> {
+        let pattern_set = self.with_function_starts(pattern_set)?;
+        let mut results = pattern_set
+            .patterns()
             .iter()
-            .enumerate()
-            .map(|(index, config)| PendingScan {
-                original_config_index: index,
-                scan: config.scan.clone(), // TODO clone isn't ideal but makes handling multi-stage scans a lot easier
-            })
+            .map(|_| ScanMatches::default())
             .collect::<Vec<_>>();
+        for section in self.memory.sections() {
+            let scan = pattern_set.scan_limited(section.address(), section.data());
+            for (all, res) in results.iter_mut().zip(scan) {
+                all.matches.extend(res.matches);
+                all.limit_hit |= res.limit_hit;
+            }
+        }
+        for (pattern, all) in pattern_set.patterns().iter().zip(&mut results) {
+            all.matches.sort();
+            // limits apply to each section so apply again to the combined matches
+            if let Some(limit) = pattern.limit {
+                if all.matches.len() > limit {
+                    all.matches.truncate(limit);
+                    all.limit_hit = true;
+                }
+            }
+        }
+        Ok(results)
+    }
+    /// `pattern_set` checking [`scanner::Pattern::function_start`] against the function starts
+    /// of this image, sharing the compiled patterns if it needs to be copied
+    pub fn with_function_starts<'set, 'p>(
+        &self,
+        pattern_set: &'set PatternSet<'p>,
+    ) -> Result<Cow<'set, PatternSet<'p>>, MemoryAccessError> {
+        Ok(if pattern_set.patterns().iter().any(|p| p.function_start) {
+            Cow::Owned(pattern_set.clone().function_starts(self.function_starts()?))
+        } else {
+            Cow::Borrowed(pattern_set)
+        })
+    }
+
+    pub fn scan<'patterns, S>(
+        &self,
+        pattern_configs: &'patterns [PatternConfig<S>],
+    ) -> Result<ScanResult<'patterns, S>> {
+        self.scan_set(&ScanSet::new(pattern_configs))
+    }
+
+    /// Scan using a precompiled [`ScanSet`] which can be reused between images
+    pub fn scan_set<'patterns, S>(
+        &self,
+        scan_set: &ScanSet<'patterns, S>,
+    ) -> Result<ScanResult<'patterns, S>> {
+        let pattern_configs = scan_set.configs();
+        // the scan set is shared between images so its sets are given the function starts of
+        // this image, which keeps limits after the check
+        let pattern_sets = scan_set
+            .pattern_sets
+            .iter()
+            .map(|(_, _, set)| self.with_function_starts(set))
+            .collect::<Result<Vec<_>, _>>()?;
+        // matches of each pattern of each config from all sections
+        let mut matches: std::collections::BTreeMap<(usize, usize), Vec<usize>> =
+            Default::default();
 
         for section in self.memory.sections() {
             let base_address = section.address();
             let data = section.data();
 
-            let (pattern_scans, patterns): (Vec<_>, Vec<_>) = scan_queue
-                .iter()
-                .filter_map(|scan| {
-                    scan.scan
-                        .section
-                        .map(|s| s == section.kind())
-                        .unwrap_or(true)
-                        .then(|| {
-                            scan.scan
-                                .scan_type
-                                .get_pattern()
-                                .map(|pattern| (scan, pattern))
-                        })
-                        .flatten()
-                })
-                .unzip();
+            let set_index = scan_set.pattern_set_index(section.kind());
+            let (_, pattern_indexes, _) = &scan_set.pattern_sets[set_index];
+            let pattern_set = &pattern_sets[set_index];
 
-            let (xref_scans, xrefs): (Vec<_>, Vec<_>) = scan_queue
+            let (xref_indexes, xrefs): (Vec<_>, Vec<_>) = pattern_configs
                 .iter()
-                .filter_map(|scan| {
-                    scan.scan
+                .enumerate()
+                .filter_map(|(index, config)| {
+                    config
+                        .scan
                         .section
                         .map(|s| s == section.kind())
                         .unwrap_or(true)
-                        .then(|| scan.scan.scan_type.get_xref().map(|xref| (scan, xref)))
+                        .then(|| config.scan.scan_type.get_xref().map(|xref| (index, xref)))
                         .flatten()
                 })
                 .unzip();
 
-            let scan_results = scanner::scan_pattern(&patterns, base_address, data)
+            let scan_results = pattern_set
+                .scan(base_address, data)
                 .into_iter()
                 .chain(scanner::scan_xref(&xrefs, base_address, data))
-                .zip(pattern_scans.iter().chain(xref_scans.iter()));
+                .zip(
+                    pattern_indexes
+                        .iter()
+                        .copied()
+                        .chain(xref_indexes.iter().map(|i| (*i, 0))),
+                );
+
+            for (addresses, index) in scan_results {
+                matches.entry(index).or_default().extend(addresses);
+            }
+        }
 
-            for (addresses, scan) in scan_results {
+        let mut results = vec![];
+        // matches of each pattern of compound scans which are combined after all sections
+        let mut compound_matches: std::collections::BTreeMap<usize, [Vec<usize>; 2]> =
+            Default::default();
+        for ((index, part), mut addresses) in matches {
+            let config = &pattern_configs[index];
+            addresses.sort();
+            // limits apply to each section so apply again to the combined matches
+            if let Some(limit) = config
+                .scan
+                .scan_type
+                .patterns()
+                .get(part)
+                .and_then(|p| p.limit)
+            {
+                addresses.truncate(limit);
+            }
+            if config.scan.scan_type.get_compound().is_some() {
+                compound_matches.entry(index).or_default()[part] = addresses;
+            } else {
                 for address in addresses {
-                    results.push((
-                        &pattern_configs[scan.original_config_index],
-                        Resolution { address },
-                    ));
+                    results.push((config, Resolution { address }));
                 }
             }
         }
 
+        for (index, [first, second]) in compound_matches {
+            let config = &pattern_configs[index];
+            let compound = config.scan.scan_type.get_compound().unwrap();
+            for address in compound.filter_matches(self, &first, &second) {
+                results.push((config, Resolution { address }));
+            }
+        }
+
         Ok(ScanResult { results })
     }
 }
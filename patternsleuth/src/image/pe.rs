@@ -150,7 +150,7 @@ impl PEImage {
                     .get_child_functions(
                         image,
                         self.get_function(image, **function)?
-                            .ok_or(MemoryAccessError::MemoryOutOfBoundsError)?
+                            .ok_or(MemoryAccessError::MemoryOutOfBoundsError(**function))?
                             .range
                             .start,
                     )
@@ -298,6 +298,7 @@ impl PEImage {
                 exception_directory_range: get_ex_dir().unwrap_or_default(),
                 exception_children_cache: Default::default(),
             }),
+            control_flow_graphs: Default::default(),
         };
 
         if cache_functions {
@@ -317,3 +318,64 @@ impl PEImage {
         Self::read_inner_memory(base_address, exe_path, cache_functions, memory, object)
     }
 }
+
+#[cfg(test)]
+impl PEImage {
+    /// Image with base address 0 made of `(name, kind, address, data)` sections and unchained root
+    /// functions spanning `functions`. The unwind info and exception directory are placed in
+    /// sections after the last one.
+    pub(crate) fn synthetic(
+        sections: Vec<(&str, object::SectionKind, usize, Vec<u8>)>,
+        functions: &[Range<usize>],
+    ) -> Image<'static> {
+        use crate::NamedMemorySection;
+
+        let end = sections.iter().map(|s| s.2 + s.3.len()).max().unwrap_or(0);
+        let unwind = (end + 0xfff) & !0xfff;
+        let exception_directory = unwind + 0x1000;
+
+        let mut entries = functions.to_vec();
+        entries.sort_by_key(|f| f.start);
+        let directory = entries
+            .iter()
+            .flat_map(|f| [f.start as u32, f.end as u32, unwind as u32])
+            .flat_map(u32::to_le_bytes)
+            .collect::<Vec<_>>();
+
+        let mut sections = sections
+            .into_iter()
+            .map(|(name, kind, address, data)| {
+                NamedMemorySection::new(name.to_string(), address, kind, data)
+            })
+            .collect::<Vec<_>>();
+        // version 1 without chain info
+        sections.push(NamedMemorySection::new(
+            ".xdata".to_string(),
+            unwind,
+            object::SectionKind::ReadOnlyData,
+            vec![1, 0, 0, 0],
+        ));
+        sections.push(NamedMemorySection::new(
+            ".pdata".to_string(),
+            exception_directory,
+            object::SectionKind::ReadOnlyData,
+            directory.clone(),
+        ));
+
+        let mut image = Image {
+            base_address: 0,
+            memory: Memory::from_sections(sections),
+            #[cfg(feature = "symbols")]
+            symbols: None,
+            imports: Default::default(),
+            image_type: ImageType::PEImage(PEImage {
+                exception_directory_range: exception_directory
+                    ..exception_directory + directory.len(),
+                exception_children_cache: Default::default(),
+            }),
+            control_flow_graphs: Default::default(),
+        };
+        image.populate_exception_cache().unwrap();
+        image
+    }
+}
@@ -1,21 +1,30 @@
+pub mod asm;
+pub mod cfg;
 pub mod image;
 pub mod process;
+pub mod read;
 pub mod resolvers;
+pub mod sigmaker;
 #[cfg(feature = "symbols")]
 pub mod symbols;
 #[cfg(feature = "symbols")]
 pub mod uesym;
+pub mod yara;
+
+// lets derived impls refer to `::patternsleuth` from inside this crate
+extern crate self as patternsleuth;
 
 pub mod scanner {
     pub use patternsleuth_scanner::*;
 }
 
-use scanner::{Pattern, Xref};
+use scanner::{Pattern, PatternSet, ScanMatches, Xref, XrefKind};
 use std::{
     borrow::Cow,
     collections::HashMap,
-    ops::{Index, Range, RangeFrom, RangeTo},
+    ops::{Range, RangeFrom, RangeTo},
     path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use anyhow::{bail, Context, Result};
@@ -44,7 +53,8 @@ pub struct Scan {
 #[derive(Debug, Clone)]
 pub enum ScanType {
     Pattern(Pattern),
-    Xref(Xref),
+    Xref(XrefKind, Xref),
+    Compound(Box<CompoundPattern>),
 }
 impl ScanType {
     pub fn get_pattern(&self) -> Option<&Pattern> {
@@ -53,9 +63,23 @@ impl ScanType {
             _ => None,
         }
     }
-    pub fn get_xref(&self) -> Option<&Xref> {
+    /// All patterns which need to be scanned for this scan type
+    pub fn patterns(&self) -> Vec<&Pattern> {
         match self {
-            Self::Xref(xref) => Some(xref),
+            Self::Pattern(pattern) => vec![pattern],
+            Self::Compound(compound) => vec![&compound.first, &compound.second],
+            Self::Xref(..) => vec![],
+        }
+    }
+    pub fn get_compound(&self) -> Option<&CompoundPattern> {
+        match self {
+            Self::Compound(compound) => Some(compound),
+            _ => None,
+        }
+    }
+    pub fn get_xref(&self) -> Option<(XrefKind, &Xref)> {
+        match self {
+            Self::Xref(kind, xref) => Some((*kind, xref)),
             _ => None,
         }
     }
@@ -67,7 +91,82 @@ impl From<Pattern> for ScanType {
 }
 impl From<Xref> for ScanType {
     fn from(value: Xref) -> Self {
-        Self::Xref(value)
+        Self::Xref(XrefKind::Rel32, value)
+    }
+}
+impl From<(XrefKind, Xref)> for ScanType {
+    fn from((kind, xref): (XrefKind, Xref)) -> Self {
+        Self::Xref(kind, xref)
+    }
+}
+impl From<CompoundPattern> for ScanType {
+    fn from(value: CompoundPattern) -> Self {
+        Self::Compound(value.into())
+    }
+}
+
+/// Matches of `first` which have a match of `second` at a distance within `distance` (address of
+/// the `second` match minus address of the `first` match, negative if it comes before)
+#[derive(Debug, Clone)]
+pub struct CompoundPattern {
+    pub first: Pattern,
+    pub second: Pattern,
+    pub distance: std::ops::RangeInclusive<isize>,
+    /// Additionally require both matches to be in the same root function
+    pub same_function: bool,
+}
+impl CompoundPattern {
+    /// `second` occurs at most `n` bytes after `first`
+    pub fn within_after(first: Pattern, second: Pattern, n: usize) -> Self {
+        Self {
+            first,
+            second,
+            distance: 0..=n as isize,
+            same_function: false,
+        }
+    }
+    /// `second` occurs at most `n` bytes before `first`
+    pub fn within_before(first: Pattern, second: Pattern, n: usize) -> Self {
+        Self {
+            first,
+            second,
+            distance: -(n as isize)..=0,
+            same_function: false,
+        }
+    }
+    /// Require both matches to be in the same root function
+    pub fn same_function(mut self) -> Self {
+        self.same_function = true;
+        self
+    }
+    /// Filter matches of `first` by matches of `second`
+    pub fn filter_matches(
+        &self,
+        image: &Image<'_>,
+        first: &[usize],
+        second: &[usize],
+    ) -> Vec<usize> {
+        let mut second = second.to_vec();
+        second.sort();
+        first
+            .iter()
+            .copied()
+            .filter(|&a| {
+                let start = a.saturating_add_signed(*self.distance.start());
+                let end = a.saturating_add_signed(*self.distance.end());
+                let function = self
+                    .same_function
+                    .then(|| image.get_root_function_range_containing(a).ok().flatten());
+                let i = second.partition_point(|b| *b < start);
+                second[i..]
+                    .iter()
+                    .take_while(|b| **b <= end)
+                    .any(|b| match &function {
+                        Some(range) => range.as_ref().is_some_and(|r| r.contains(b)),
+                        None => true,
+                    })
+            })
+            .collect()
     }
 }
 
@@ -93,7 +192,12 @@ impl<S> PatternConfig<S> {
             },
         }
     }
-    pub fn xref(sig: S, name: String, section: Option<object::SectionKind>, xref: Xref) -> Self {
+    pub fn xref(
+        sig: S,
+        name: String,
+        section: Option<object::SectionKind>,
+        xref: impl Into<ScanType>,
+    ) -> Self {
         Self {
             sig,
             name,
@@ -105,6 +209,66 @@ impl<S> PatternConfig<S> {
     }
 }
 
+/// Set of [`PatternConfig`]s compiled once so that many images can be scanned without regrouping
+/// the patterns for every section
+pub struct ScanSet<'patterns, S> {
+    configs: &'patterns [PatternConfig<S>],
+    /// compiled patterns for sections of each kind targeted by a config (`None` for every other
+    /// section) along with the index of the config each pattern came from and the index of the
+    /// pattern within the config's [`ScanType::patterns`]
+    pattern_sets: Vec<SectionPatternSet<'patterns>>,
+}
+type SectionPatternSet<'patterns> = (
+    Option<object::SectionKind>,
+    Vec<(usize, usize)>,
+    PatternSet<'patterns>,
+);
+impl<'patterns, S> ScanSet<'patterns, S> {
+    pub fn new(configs: &'patterns [PatternConfig<S>]) -> Self {
+        let kinds = configs
+            .iter()
+            .filter_map(|c| c.scan.section)
+            .map(Some)
+            .chain([None])
+            .collect::<Vec<_>>();
+        let mut pattern_sets = vec![];
+        for kind in kinds {
+            if pattern_sets.iter().any(|(k, _, _)| *k == kind) {
+                continue;
+            }
+            let (indexes, patterns): (Vec<_>, Vec<_>) = configs
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| c.scan.section.is_none() || c.scan.section == kind)
+                .flat_map(|(i, c)| {
+                    c.scan
+                        .scan_type
+                        .patterns()
+                        .into_iter()
+                        .enumerate()
+                        .map(move |(j, p)| ((i, j), p))
+                })
+                .unzip();
+            pattern_sets.push((kind, indexes, PatternSet::new(&patterns)));
+        }
+        Self {
+            configs,
+            pattern_sets,
+        }
+    }
+    pub fn configs(&self) -> &'patterns [PatternConfig<S>] {
+        self.configs
+    }
+    /// Index into `pattern_sets` of the set to scan sections of `kind` with
+    fn pattern_set_index(&self, kind: object::SectionKind) -> usize {
+        self.pattern_sets
+            .iter()
+            .position(|(k, _, _)| *k == Some(kind))
+            .or_else(|| self.pattern_sets.iter().position(|(k, _, _)| k.is_none()))
+            .unwrap()
+    }
+}
+
 #[derive(Debug)]
 pub struct ScanResult<'a, S> {
     pub results: Vec<(&'a PatternConfig<S>, Resolution)>,
@@ -160,7 +324,8 @@ impl RuntimeFunction {
     derive(serde::Serialize, serde::Deserialize)
 )]
 pub enum MemoryAccessError {
-    MemoryOutOfBoundsError,
+    /// Address is not inside any block of memory
+    MemoryOutOfBoundsError(usize),
     Utf8Error,
     Utf16Error,
     MisalginedAddress(usize, usize),
@@ -169,7 +334,9 @@ impl std::error::Error for MemoryAccessError {}
 impl std::fmt::Display for MemoryAccessError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::MemoryOutOfBoundsError => write!(f, "MemoryOutOfBoundsError"),
+            Self::MemoryOutOfBoundsError(addr) => {
+                write!(f, "MemoryOutOfBoundsError: address {:#x}", addr)
+            }
             Self::Utf8Error => write!(f, "Utf8Error"),
             Self::Utf16Error => write!(f, "Utf16Error"),
             Self::MisalginedAddress(addr, align) => {
@@ -198,6 +365,9 @@ pub trait MemoryBlockTrait<'data> {
 }
 
 /// Potentially sparse section of memory
+///
+/// Every accessor is bounds checked and returns [`MemoryAccessError::MemoryOutOfBoundsError`]
+/// with the first address that could not be read rather than panicking.
 pub trait MemoryTrait<'data> {
     /// Return u8 at `address`
     fn index(&self, address: usize) -> Result<u8, MemoryAccessError>;
@@ -208,46 +378,31 @@ pub trait MemoryTrait<'data> {
     /// Return slice of u8 from end of `range` to start of block (not useful because start of block
     /// is unknown to caller)
     fn range_to(&self, range: RangeTo<usize>) -> Result<&[u8], MemoryAccessError>;
+    /// Return u8 at `range` which unlike [`MemoryTrait::range`] may span adjacent blocks, in
+    /// which case the data is copied
+    fn read(&self, range: Range<usize>) -> Result<Cow<'_, [u8]>, MemoryAccessError> {
+        self.range(range).map(Cow::Borrowed)
+    }
 
     /// Return i16 at `address`
     fn i16_le(&self, address: usize) -> Result<i16, MemoryAccessError> {
-        Ok(i16::from_le_bytes(
-            self.range(address..address + std::mem::size_of::<i16>())?
-                .try_into()
-                .unwrap(),
-        ))
+        Ok(i16::from_le_bytes(read_array(self, address)?))
     }
     /// Return u16 at `address`
     fn u16_le(&self, address: usize) -> Result<u16, MemoryAccessError> {
-        Ok(u16::from_le_bytes(
-            self.range(address..address + std::mem::size_of::<u16>())?
-                .try_into()
-                .unwrap(),
-        ))
+        Ok(u16::from_le_bytes(read_array(self, address)?))
     }
     /// Return i32 at `address`
     fn i32_le(&self, address: usize) -> Result<i32, MemoryAccessError> {
-        Ok(i32::from_le_bytes(
-            self.range(address..address + std::mem::size_of::<i32>())?
-                .try_into()
-                .unwrap(),
-        ))
+        Ok(i32::from_le_bytes(read_array(self, address)?))
     }
     /// Return u32 at `address`
     fn u32_le(&self, address: usize) -> Result<u32, MemoryAccessError> {
-        Ok(u32::from_le_bytes(
-            self.range(address..address + std::mem::size_of::<u32>())?
-                .try_into()
-                .unwrap(),
-        ))
+        Ok(u32::from_le_bytes(read_array(self, address)?))
     }
     /// Return u64 at `address`
     fn u64_le(&self, address: usize) -> Result<u64, MemoryAccessError> {
-        Ok(u64::from_le_bytes(
-            self.range(address..address + std::mem::size_of::<u64>())?
-                .try_into()
-                .unwrap(),
-        ))
+        Ok(u64::from_le_bytes(read_array(self, address)?))
     }
     /// Return ptr (usize) at `address`
     fn ptr(&self, address: usize) -> Result<usize, MemoryAccessError> {
@@ -255,9 +410,11 @@ pub trait MemoryTrait<'data> {
     }
     /// Return instruction relative address at `address`
     fn rip4(&self, address: usize) -> Result<usize, MemoryAccessError> {
-        Ok((address + 4)
-            .checked_add_signed(self.i32_le(address)? as isize)
-            .unwrap())
+        let displacement = self.i32_le(address)? as isize;
+        address
+            .checked_add(4)
+            .and_then(|next| next.checked_add_signed(displacement))
+            .ok_or(MemoryAccessError::MemoryOutOfBoundsError(address))
     }
 
     /// Read null terminated string from `address`
@@ -276,7 +433,7 @@ pub trait MemoryTrait<'data> {
     fn read_wstring(&self, address: usize) -> Result<String, MemoryAccessError> {
         let data = &self
             .range_from(address..)?
-            .chunks(2)
+            .chunks_exact(2)
             .map(|chunk| ((chunk[1] as u16) << 8) + chunk[0] as u16)
             .take_while(|n| *n != 0)
             .collect::<Vec<u16>>();
@@ -285,22 +442,55 @@ pub trait MemoryTrait<'data> {
     }
 }
 
+/// Read `N` bytes at `address`
+fn read_array<'data, const N: usize, M: MemoryTrait<'data> + ?Sized>(
+    memory: &M,
+    address: usize,
+) -> Result<[u8; N], MemoryAccessError> {
+    let end = address
+        .checked_add(N)
+        .ok_or(MemoryAccessError::MemoryOutOfBoundsError(address))?;
+    Ok(memory.read(address..end)?.as_ref().try_into().unwrap())
+}
+
+/// Translate `range` to offsets into a block of `len` bytes at `address`
+fn block_range(
+    address: usize,
+    len: usize,
+    range: Range<usize>,
+) -> Result<Range<usize>, MemoryAccessError> {
+    if range.start < address || range.start > range.end {
+        return Err(MemoryAccessError::MemoryOutOfBoundsError(range.start));
+    }
+    if range.end - address > len {
+        return Err(MemoryAccessError::MemoryOutOfBoundsError(
+            (address + len).max(range.start),
+        ));
+    }
+    Ok(range.start - address..range.end - address)
+}
+
 impl<'data, T: MemoryBlockTrait<'data>> MemoryTrait<'data> for T {
     fn index(&self, address: usize) -> Result<u8, MemoryAccessError> {
-        // TODO bounds
-        Ok(self.data()[address - self.address()])
+        address
+            .checked_sub(self.address())
+            .and_then(|offset| self.data().get(offset))
+            .copied()
+            .ok_or(MemoryAccessError::MemoryOutOfBoundsError(address))
     }
     fn range(&self, range: Range<usize>) -> Result<&[u8], MemoryAccessError> {
-        // TODO bounds
-        Ok(&self.data()[range.start - self.address()..range.end - self.address()])
+        let data = self.data();
+        Ok(&data[block_range(self.address(), data.len(), range)?])
     }
     fn range_from(&self, range: RangeFrom<usize>) -> Result<&[u8], MemoryAccessError> {
-        // TODO bounds
-        Ok(&self.data()[range.start - self.address()..])
+        let data = self.data();
+        let end = self.address() + data.len();
+        Ok(&data[block_range(self.address(), data.len(), range.start..end)?])
     }
     fn range_to(&self, range: RangeTo<usize>) -> Result<&[u8], MemoryAccessError> {
-        // TODO bounds
-        Ok(&self.data()[..range.end - self.address()])
+        let data = self.data();
+        let start = self.address().min(range.end);
+        Ok(&data[block_range(self.address(), data.len(), start..range.end)?])
     }
 }
 
@@ -315,7 +505,25 @@ impl<'data> MemoryTrait<'data> for Memory<'data> {
         self.get_section_containing(range.start)?.range_from(range)
     }
     fn range_to(&self, range: RangeTo<usize>) -> Result<&[u8], MemoryAccessError> {
-        self.get_section_containing(range.end)?.range_to(range)
+        // end is exclusive so find the section containing the last byte
+        self.get_section_containing(range.end.saturating_sub(1))?
+            .range_to(range)
+    }
+    fn read(&self, range: Range<usize>) -> Result<Cow<'_, [u8]>, MemoryAccessError> {
+        let section = self.get_section_containing(range.start)?;
+        let section_end = section.address() + section.len();
+        if range.end <= section_end {
+            return section.range(range).map(Cow::Borrowed);
+        }
+        // continue into the following sections as long as they are adjacent
+        let mut data = section.range(range.start..section_end)?.to_vec();
+        while data.len() < range.len() {
+            let address = range.start + data.len();
+            let section = self.get_section_containing(address)?;
+            let end = range.end.min(section.address() + section.len());
+            data.extend_from_slice(section.range(address..end)?);
+        }
+        Ok(Cow::Owned(data))
     }
 }
 
@@ -339,7 +547,7 @@ pub struct NamedMemorySection<'data> {
 }
 
 impl<'data> NamedMemorySection<'data> {
-    fn new<T: Into<Cow<'data, [u8]>>>(
+    pub(crate) fn new<T: Into<Cow<'data, [u8]>>>(
         name: String,
         address: usize,
         kind: object::SectionKind,
@@ -386,12 +594,16 @@ impl<'data> MemoryBlockTrait<'data> for NamedMemorySection<'data> {
 
 pub struct Memory<'data> {
     sections: Vec<NamedMemorySection<'data>>,
+    /// Sorted non-overlapping address ranges and the index of the section containing them
+    index: Vec<(Range<usize>, usize)>,
+    /// Entry of `index` which was last looked up as reads tend to hit the same section
+    last: AtomicUsize,
 }
 
 impl<'data> Memory<'data> {
     pub fn new(object: &File<'data>) -> Result<Self> {
-        Ok(Self {
-            sections: object
+        Ok(Self::from_sections(
+            object
                 .sections()
                 .map(|s| {
                     Ok(NamedMemorySection::new(
@@ -402,11 +614,11 @@ impl<'data> Memory<'data> {
                     ))
                 })
                 .collect::<Result<Vec<_>>>()?,
-        })
+        ))
     }
     pub fn new_external_data(sections: Vec<(object::Section<'_, '_>, Vec<u8>)>) -> Result<Self> {
-        Ok(Self {
-            sections: sections
+        Ok(Self::from_sections(
+            sections
                 .into_iter()
                 .map(|(s, d)| {
                     Ok(NamedMemorySection::new(
@@ -417,13 +629,13 @@ impl<'data> Memory<'data> {
                     ))
                 })
                 .collect::<Result<Vec<_>>>()?,
-        })
+        ))
     }
     pub fn new_internal_data(
         sections: Vec<(object::Section<'_, '_>, &'data [u8])>,
     ) -> Result<Self> {
-        Ok(Self {
-            sections: sections
+        Ok(Self::from_sections(
+            sections
                 .into_iter()
                 .map(|(s, d)| {
                     Ok(NamedMemorySection::new(
@@ -434,7 +646,35 @@ impl<'data> Memory<'data> {
                     ))
                 })
                 .collect::<Result<Vec<_>>>()?,
-        })
+        ))
+    }
+    pub(crate) fn from_sections(sections: Vec<NamedMemorySection<'data>>) -> Self {
+        // split at every section boundary so overlapping sections resolve to the first section
+        // containing an address, same as a linear search
+        let mut bounds = sections
+            .iter()
+            .flat_map(|s| [s.address(), s.address() + s.len()])
+            .collect::<Vec<_>>();
+        bounds.sort();
+        bounds.dedup();
+        let mut index: Vec<(Range<usize>, usize)> = vec![];
+        for w in bounds.windows(2) {
+            let Some(i) = sections
+                .iter()
+                .position(|s| s.address() <= w[0] && w[1] <= s.address() + s.len())
+            else {
+                continue;
+            };
+            match index.last_mut() {
+                Some((range, last)) if *last == i && range.end == w[0] => range.end = w[1],
+                _ => index.push((w[0]..w[1], i)),
+            }
+        }
+        Self {
+            sections,
+            index,
+            last: Default::default(),
+        }
     }
     pub fn sections(&self) -> &[NamedMemorySection] {
         &self.sections
@@ -443,13 +683,21 @@ impl<'data> Memory<'data> {
         &self,
         address: usize,
     ) -> Result<&NamedMemorySection<'data>, MemoryAccessError> {
-        self.sections
-            .iter()
-            .find(|section| {
-                address >= section.section.address
-                    && address < section.section.address + section.section.data.len()
-            })
-            .ok_or(MemoryAccessError::MemoryOutOfBoundsError)
+        let contains = |i: usize| {
+            self.index
+                .get(i)
+                .filter(|(range, _)| range.contains(&address))
+                .map(|(_, section)| &self.sections[*section])
+        };
+        if let Some(section) = contains(self.last.load(Ordering::Relaxed)) {
+            return Ok(section);
+        }
+        let i = self
+            .index
+            .partition_point(|(range, _)| range.end <= address);
+        let section = contains(i).ok_or(MemoryAccessError::MemoryOutOfBoundsError(address))?;
+        self.last.store(i, Ordering::Relaxed);
+        Ok(section)
     }
     pub fn find<F>(&self, kind: object::SectionKind, filter: F) -> Option<usize>
     where
@@ -471,36 +719,29 @@ impl<'data> Memory<'data> {
             }
         })
     }
-}
-impl Index<usize> for Memory<'_> {
-    type Output = u8;
-    fn index(&self, index: usize) -> &Self::Output {
-        self.sections
-            .iter()
-            .find_map(|section| section.section.data.get(index - section.section.address))
-            .unwrap()
+    /// Find the first match of `pattern` starting at most `max_distance` bytes after `address`
+    /// within the section containing `address`
+    pub fn find_forward(
+        &self,
+        pattern: &Pattern,
+        address: usize,
+        max_distance: usize,
+    ) -> Result<Option<usize>, MemoryAccessError> {
+        let s = self.get_section_containing(address)?;
+        Ok(pattern.find_forward(s.data(), s.address(), address - s.address(), max_distance))
     }
-}
-impl Index<Range<usize>> for Memory<'_> {
-    type Output = [u8];
-    fn index(&self, index: Range<usize>) -> &Self::Output {
-        self.sections
-            .iter()
-            .find_map(|section| {
-                if index.start >= section.section.address
-                    && index.end <= section.section.address + section.section.data.len()
-                {
-                    let relative_range =
-                        index.start - section.section.address..index.end - section.section.address;
-                    Some(&section.section.data[relative_range])
-                } else {
-                    None
-                }
-            })
-            .unwrap()
+    /// Find the closest match of `pattern` starting at most `max_distance` bytes before
+    /// `address` within the section containing `address`
+    pub fn find_backward(
+        &self,
+        pattern: &Pattern,
+        address: usize,
+        max_distance: usize,
+    ) -> Result<Option<usize>, MemoryAccessError> {
+        let s = self.get_section_containing(address)?;
+        Ok(pattern.find_backward(s.data(), s.address(), address - s.address(), max_distance))
     }
 }
-
 pub trait Addressable {
     fn rip(&self) -> usize;
     fn ptr(&self) -> usize;
@@ -508,9 +749,7 @@ pub trait Addressable {
 }
 impl Addressable for patternsleuth_scanner::Capture<'_> {
     fn rip(&self) -> usize {
-        (self.address + 4)
-            .checked_add_signed(i32::from_le_bytes(self.data.try_into().unwrap()) as isize)
-            .unwrap()
+        self.rip.unwrap()
     }
     fn ptr(&self) -> usize {
         usize::from_le_bytes(self.data.try_into().unwrap())
@@ -535,8 +774,11 @@ impl<'data> Matchable<'data> for Memory<'data> {
         address: usize,
     ) -> Result<Option<Vec<patternsleuth_scanner::Capture<'data>>>, MemoryAccessError> {
         let s = self.get_section_containing(address)?;
-        // TODO bounds check data passed to captures
-        Ok(pattern.captures(s.data(), s.address(), address - s.address()))
+        let index = address - s.address();
+        if index + pattern.min_len() > s.len() {
+            return Ok(None);
+        }
+        Ok(pattern.captures(s.data(), s.address(), index))
     }
 }
 
@@ -721,3 +963,323 @@ pub mod disassemble {
         Ok(())
     }
 }
+
+#[cfg(all(test, feature = "image-pe"))]
+mod test {
+    use super::*;
+    use image::pe::PEImage;
+
+    /// Memory of data sections at each address, with `(address, data)`
+    fn memory(sections: Vec<(usize, Vec<u8>)>) -> Memory<'static> {
+        Memory::from_sections(
+            sections
+                .into_iter()
+                .enumerate()
+                .map(|(i, (address, data))| {
+                    NamedMemorySection::new(
+                        format!(".data{i}"),
+                        address,
+                        object::SectionKind::Data,
+                        data,
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_memory_inverted_range() {
+        let mem = memory(vec![(0x1000, (0..0x10).collect())]);
+        let oob = Err(MemoryAccessError::MemoryOutOfBoundsError(0x1008));
+        #[allow(clippy::reversed_empty_ranges)]
+        let inverted = 0x1008..0x1004;
+        assert_eq!(oob, mem.range(inverted.clone()));
+        assert_eq!(oob, mem.sections()[0].range(inverted.clone()));
+        assert_eq!(oob, mem.read(inverted).as_deref().map_err(Clone::clone));
+        // empty ranges are fine
+        assert_eq!(Ok(&[][..]), mem.range(0x1008..0x1008));
+    }
+
+    #[test]
+    fn test_memory_range_to_section_end() {
+        let mem = memory(vec![
+            (0x1000, (0..0x10).collect()),
+            (0x1010, (0x10..0x20).collect()),
+        ]);
+        // the end is exclusive so this is the whole first section, not the empty start of the
+        // second
+        assert_eq!(
+            Ok(&(0..0x10).collect::<Vec<u8>>()[..]),
+            mem.range_to(..0x1010)
+        );
+        assert_eq!(Ok(&[0x10][..]), mem.range_to(..0x1011));
+        assert_eq!(
+            Ok(&(0x10..0x20).collect::<Vec<u8>>()[..]),
+            mem.range_to(..0x1020)
+        );
+        assert_eq!(
+            Err(MemoryAccessError::MemoryOutOfBoundsError(0x1020)),
+            mem.range_to(..0x1021)
+        );
+    }
+
+    #[test]
+    fn test_memory_rip4_overflow() {
+        let high = usize::MAX - 0x10;
+        let mem = memory(vec![
+            (0, (-0x10i32).to_le_bytes().to_vec()),
+            (high, i32::MAX.to_le_bytes().to_vec()),
+            (0x1000, (-0x10i32).to_le_bytes().to_vec()),
+        ]);
+        assert_eq!(
+            Err(MemoryAccessError::MemoryOutOfBoundsError(0)),
+            mem.rip4(0)
+        );
+        assert_eq!(
+            Err(MemoryAccessError::MemoryOutOfBoundsError(high)),
+            mem.rip4(high)
+        );
+        assert_eq!(Ok(0x1004 - 0x10), mem.rip4(0x1000));
+        assert_eq!(
+            Err(MemoryAccessError::MemoryOutOfBoundsError(usize::MAX)),
+            mem.rip4(usize::MAX)
+        );
+        // the displacement itself must be readable
+        assert_eq!(
+            Err(MemoryAccessError::MemoryOutOfBoundsError(0x1004)),
+            mem.rip4(0x1001)
+        );
+    }
+
+    #[test]
+    fn test_memory_read_adjacent_sections() {
+        let mem = memory(vec![
+            (0x1000, (0..0x10).collect()),
+            (0x1010, (0x10..0x20).collect()),
+            (0x1020, (0x20..0x30).collect()),
+        ]);
+        let read = mem.read(0x100c..0x1024).unwrap();
+        assert!(matches!(read, Cow::Owned(_)));
+        assert_eq!((0xc..0x24).collect::<Vec<u8>>(), read.to_vec());
+        // reads within a section are borrowed
+        assert!(matches!(
+            mem.read(0x1010..0x1020).unwrap(),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(Ok(0x1312_1110_0f0e_0d0c), mem.u64_le(0x100c));
+        // range only ever returns one section
+        assert_eq!(
+            Err(MemoryAccessError::MemoryOutOfBoundsError(0x1010)),
+            mem.range(0x100c..0x1014)
+        );
+    }
+
+    #[test]
+    fn test_memory_read_gap() {
+        let mem = memory(vec![
+            (0x1000, (0..0x10).collect()),
+            (0x1020, (0x20..0x30).collect()),
+        ]);
+        // the error points at the first byte which could not be read
+        assert_eq!(
+            Err(MemoryAccessError::MemoryOutOfBoundsError(0x1010)),
+            mem.read(0x100c..0x1024).map(|d| d.to_vec())
+        );
+        assert_eq!(
+            Err(MemoryAccessError::MemoryOutOfBoundsError(0x1010)),
+            mem.u64_le(0x100c)
+        );
+        assert_eq!(
+            Err(MemoryAccessError::MemoryOutOfBoundsError(0x1018)),
+            mem.read(0x1018..0x1024).map(|d| d.to_vec())
+        );
+    }
+
+    #[test]
+    fn test_memory_index_overlapping_sections() {
+        let mem = memory(vec![
+            (0x1000, vec![0; 0x20]),
+            // overlaps the end of the first
+            (0x1010, vec![1; 0x20]),
+            // entirely inside the first
+            (0x1008, vec![2; 0x8]),
+            // empty then followed by a section at the same address
+            (0x1040, vec![]),
+            (0x1040, vec![4; 0x10]),
+            (0x1060, vec![5; 0x10]),
+            // entirely covering the previous one
+            (0x1058, vec![6; 0x20]),
+        ]);
+        // same result as the linear search the index replaced
+        let linear = |address: usize| {
+            mem.sections()
+                .iter()
+                .find(|s| s.address() <= address && address < s.address() + s.len())
+                .map(|s| s.name())
+        };
+        for address in 0xff0..0x1090 {
+            assert_eq!(
+                linear(address),
+                mem.get_section_containing(address).ok().map(|s| s.name()),
+                "{address:#x}"
+            );
+        }
+        assert_eq!(Ok(1), mem.index(0x1020));
+        assert_eq!(Ok(5), mem.index(0x1060));
+        assert_eq!(Ok(6), mem.index(0x1058));
+    }
+
+    #[test]
+    fn test_memory_index_empty_sections() {
+        let mem = memory(vec![
+            (0x1000, vec![]),
+            (0x1010, vec![0; 0x10]),
+            (0x1020, vec![]),
+        ]);
+        for address in [0x1000, 0x100f, 0x1020] {
+            assert_eq!(
+                Err(MemoryAccessError::MemoryOutOfBoundsError(address)),
+                mem.get_section_containing(address).map(|s| s.name())
+            );
+        }
+        assert_eq!(
+            Ok(".data1"),
+            mem.get_section_containing(0x1010).map(|s| s.name())
+        );
+
+        let mem = memory(vec![]);
+        assert!(mem.get_section_containing(0).is_err());
+    }
+
+    #[test]
+    fn test_memory_index_last_cache() {
+        let mem = memory(vec![(0x1000, vec![0; 0x10]), (0x1020, vec![1; 0x10])]);
+        let name = |address| mem.get_section_containing(address).map(|s| s.name());
+
+        assert_eq!(Ok(".data1"), name(0x1028));
+        assert_eq!(1, mem.last.load(Ordering::Relaxed));
+        assert_eq!(Ok(".data1"), name(0x1020));
+        // misses leave the cache alone
+        assert!(name(0x1018).is_err());
+        assert!(name(0x1030).is_err());
+        assert_eq!(1, mem.last.load(Ordering::Relaxed));
+        // a stale cache is only a hint
+        assert_eq!(Ok(".data0"), name(0x1000));
+        assert_eq!(0, mem.last.load(Ordering::Relaxed));
+        assert_eq!(Ok(".data1"), name(0x102f));
+        assert_eq!(Ok(".data0"), name(0x100f));
+    }
+
+    #[test]
+    fn test_compound_filter_matches() {
+        let image = PEImage::synthetic(
+            vec![(
+                ".text",
+                object::SectionKind::Text,
+                0x1000,
+                vec![0xcc; 0x300],
+            )],
+            &[0x1000..0x1100, 0x1100..0x1200],
+        );
+        let a = Pattern::new("e8").unwrap();
+        let b = Pattern::new("e9").unwrap();
+        let first = [0x1010, 0x10f0, 0x1150];
+        let second = [0x1030, 0x1108, 0x1300];
+
+        let after = CompoundPattern::within_after(a.clone(), b.clone(), 0x20);
+        assert_eq!(
+            vec![0x1010, 0x10f0],
+            after.filter_matches(&image, &first, &second)
+        );
+        // 0x1108 is in the function after the one containing 0x10f0
+        assert_eq!(
+            vec![0x1010],
+            after
+                .clone()
+                .same_function()
+                .filter_matches(&image, &first, &second)
+        );
+        // one byte short of 0x1030
+        assert_eq!(
+            vec![0x10f0],
+            CompoundPattern::within_after(a.clone(), b.clone(), 0x1f)
+                .filter_matches(&image, &first, &second)
+        );
+
+        let before = CompoundPattern::within_before(a, b, 0x20);
+        assert_eq!(
+            vec![0x1030],
+            before.filter_matches(&image, &[0x1030, 0x1200], &[0x1010])
+        );
+        // matches outside of any function are never in the same function
+        assert_eq!(
+            Vec::<usize>::new(),
+            before
+                .same_function()
+                .filter_matches(&image, &[0x1220], &[0x1210])
+        );
+    }
+
+    #[test]
+    fn test_scan_function_start_limit() {
+        let mut text = vec![0xcc; 0x200];
+        text[0x3..0x6].copy_from_slice(&[0xe8, 0x12, 0x34]);
+        text[0x100..0x103].copy_from_slice(&[0xe8, 0x12, 0x34]);
+        let image = PEImage::synthetic(
+            vec![(".text", object::SectionKind::Text, 0x1000, text)],
+            &[0x1000..0x1100, 0x1100..0x1200],
+        );
+        let pattern = Pattern::new("e8 12 34").unwrap().function_start().limit(1);
+
+        // the limit counts only matches at function starts so 0x1003 doesn't use it up
+        let configs = [PatternConfig::new(
+            (),
+            "start".to_string(),
+            None,
+            pattern.clone(),
+        )];
+        let found = image.scan(&configs).unwrap().results;
+        assert_eq!(
+            vec![0x1100],
+            found.iter().map(|(_, r)| r.address).collect::<Vec<_>>()
+        );
+
+        let found = image
+            .scan_pattern_set(&PatternSet::new(&[&pattern]))
+            .unwrap();
+        assert_eq!(vec![0x1100], found[0].matches);
+    }
+
+    #[test]
+    fn test_scan_limit_across_sections() {
+        let mut text = vec![0xcc; 0x100];
+        text[0x10..0x13].copy_from_slice(&[0xe8, 0x12, 0x34]);
+        // the later section comes first so the lowest match has to be picked after all sections
+        let image = PEImage::synthetic(
+            vec![
+                (".text2", object::SectionKind::Text, 0x2000, text.clone()),
+                (".text", object::SectionKind::Text, 0x1000, text),
+            ],
+            &[],
+        );
+        let pattern = Pattern::new("e8 12 34").unwrap().first_match();
+
+        let configs = [PatternConfig::new(
+            (),
+            "first".to_string(),
+            Some(object::SectionKind::Text),
+            pattern.clone(),
+        )];
+        let found = image.scan(&configs).unwrap().results;
+        assert_eq!(
+            vec![0x1010],
+            found.iter().map(|(_, r)| r.address).collect::<Vec<_>>()
+        );
+
+        let found = image
+            .scan_pattern_set(&PatternSet::new(&[&pattern]))
+            .unwrap();
+        assert_eq!(vec![0x1010], found[0].matches);
+        assert!(found[0].limit_hit);
+    }
+}
@@ -8,7 +8,7 @@ mod linux {
     use anyhow::{bail, Context, Result};
     use object::{Object, ObjectSection};
 
-    use crate::{image, Image, Memory};
+    use crate::{image, Image, Memory, NamedMemorySection};
 
     fn read_process_mem(pid: i32, address: usize, buffer: &mut [u8]) -> Result<usize> {
         unsafe {
@@ -35,6 +35,23 @@ mod linux {
         }
     }
 
+    const PAGE_SIZE: usize = 0x1000;
+
+    /// Read `range` of process memory page by page, skipping pages which cannot be read. Intended
+    /// to be fed to [`crate::scanner::PatternSet::scan_chunks`].
+    pub fn read_pages(pid: i32, range: Range<usize>) -> impl Iterator<Item = (usize, Vec<u8>)> {
+        let start = range.start & !(PAGE_SIZE - 1);
+        (start..range.end)
+            .step_by(PAGE_SIZE)
+            .filter_map(move |page| {
+                let address = page.max(range.start);
+                let mut buffer = vec![0; (page + PAGE_SIZE).min(range.end) - address];
+                let read = read_process_mem(pid, address, &mut buffer).ok()?;
+                buffer.truncate(read);
+                Some((address, buffer))
+            })
+    }
+
     /// Read `/proc/<PID>/maps` and find region ending with ".exe" which is the main module for
     /// processes running under WINE
     fn find_main_module(pid: i32) -> Result<Range<usize>> {
@@ -76,12 +93,25 @@ mod linux {
 
         let mut sections = vec![];
         for section in object.sections() {
-            let mut data = vec![0; section.size() as usize];
-            read_process_mem(pid, section.address() as usize, &mut data)?;
-            sections.push((section, data));
+            // each run of readable pages becomes its own memory section so unreadable pages are
+            // missing from the image rather than failing the whole section or being scanned
+            let name = section.name()?;
+            let address = section.address() as usize;
+            let mut runs: Vec<(usize, Vec<u8>)> = vec![];
+            for (page, chunk) in read_pages(pid, address..address + section.size() as usize) {
+                match runs.last_mut() {
+                    Some((start, data)) if *start + data.len() == page => {
+                        data.extend_from_slice(&chunk)
+                    }
+                    _ => runs.push((page, chunk)),
+                }
+            }
+            sections.extend(runs.into_iter().map(|(start, data)| {
+                NamedMemorySection::new(name.to_string(), start, section.kind(), data)
+            }));
         }
 
-        let memory = Memory::new_external_data(sections)?;
+        let memory = Memory::from_sections(sections);
 
         image::pe::PEImage::read_inner_memory::<String>(
             object.relative_address_base() as usize,
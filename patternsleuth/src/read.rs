@@ -0,0 +1,362 @@
+//! Typed reads of fixed layout structures from memory
+//!
+//! ```ignore
+//! #[derive(MemoryRead)]
+//! struct FNameNativePtrPair {
+//!     #[memory(utf8)]
+//!     name: String,
+//!     pointer: usize,
+//! }
+//!
+//! let pairs = Ptr::<FNameNativePtrPair>::new(address).read_slice(memory, count)?;
+//! ```
+
+use std::marker::PhantomData;
+
+use crate::{MemoryAccessError, MemoryTrait};
+
+pub use patternsleuth_derive::MemoryRead;
+
+/// A type with a fixed size layout that can be read from any [`MemoryTrait`]
+pub trait MemoryRead: Sized {
+    /// Size in bytes including trailing padding
+    const SIZE: usize;
+    /// Alignment in bytes
+    const ALIGN: usize;
+    /// Read value at `address`
+    fn read_from<'data, M: MemoryTrait<'data> + ?Sized>(
+        memory: &M,
+        address: usize,
+    ) -> Result<Self, MemoryAccessError>;
+}
+
+/// Round `offset` up to the next multiple of `align`
+pub const fn align_up(offset: usize, align: usize) -> usize {
+    offset.next_multiple_of(align)
+}
+
+/// `const` version of [`std::cmp::max`]
+pub const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+macro_rules! impl_primitive {
+    ($($ty:ty),*) => {
+        $(
+            impl MemoryRead for $ty {
+                const SIZE: usize = std::mem::size_of::<$ty>();
+                const ALIGN: usize = std::mem::size_of::<$ty>();
+                fn read_from<'data, M: MemoryTrait<'data> + ?Sized>(
+                    memory: &M,
+                    address: usize,
+                ) -> Result<Self, MemoryAccessError> {
+                    let end = address
+                        .checked_add(Self::SIZE)
+                        .ok_or(MemoryAccessError::MemoryOutOfBoundsError(address))?;
+                    Ok(<$ty>::from_le_bytes(
+                        memory.read(address..end)?.as_ref().try_into().unwrap(),
+                    ))
+                }
+            }
+        )*
+    };
+}
+impl_primitive!(u8, i8, u16, i16, u32, i32, u64, i64, f32, f64);
+
+/// Pointers are 8 bytes regardless of host
+impl MemoryRead for usize {
+    const SIZE: usize = 8;
+    const ALIGN: usize = 8;
+    fn read_from<'data, M: MemoryTrait<'data> + ?Sized>(
+        memory: &M,
+        address: usize,
+    ) -> Result<Self, MemoryAccessError> {
+        memory.ptr(address)
+    }
+}
+
+impl MemoryRead for bool {
+    const SIZE: usize = 1;
+    const ALIGN: usize = 1;
+    fn read_from<'data, M: MemoryTrait<'data> + ?Sized>(
+        memory: &M,
+        address: usize,
+    ) -> Result<Self, MemoryAccessError> {
+        Ok(memory.index(address)? != 0)
+    }
+}
+
+impl<T: MemoryRead, const N: usize> MemoryRead for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+    const ALIGN: usize = T::ALIGN;
+    fn read_from<'data, M: MemoryTrait<'data> + ?Sized>(
+        memory: &M,
+        address: usize,
+    ) -> Result<Self, MemoryAccessError> {
+        let items = Ptr::<T>::new(address).read_slice(memory, N)?;
+        Ok(items.try_into().ok().unwrap())
+    }
+}
+
+/// Pairs are laid out like a C struct of their two fields (e.g. `TTuple`)
+impl<A: MemoryRead, B: MemoryRead> MemoryRead for (A, B) {
+    const SIZE: usize = align_up(align_up(A::SIZE, B::ALIGN) + B::SIZE, Self::ALIGN);
+    const ALIGN: usize = max(A::ALIGN, B::ALIGN);
+    fn read_from<'data, M: MemoryTrait<'data> + ?Sized>(
+        memory: &M,
+        address: usize,
+    ) -> Result<Self, MemoryAccessError> {
+        let second = address
+            .checked_add(align_up(A::SIZE, B::ALIGN))
+            .ok_or(MemoryAccessError::MemoryOutOfBoundsError(address))?;
+        Ok((
+            A::read_from(memory, address)?,
+            B::read_from(memory, second)?,
+        ))
+    }
+}
+
+/// Pointer to a `T` which is only followed when read
+pub struct Ptr<T> {
+    pub address: usize,
+    _type: PhantomData<fn() -> T>,
+}
+impl<T> Ptr<T> {
+    pub fn new(address: usize) -> Self {
+        Self {
+            address,
+            _type: PhantomData,
+        }
+    }
+    pub fn is_null(&self) -> bool {
+        self.address == 0
+    }
+}
+impl<T: MemoryRead> Ptr<T> {
+    /// Pointer to the `index`th `T` after this one
+    pub fn offset(&self, index: usize) -> Self {
+        Self::new(self.address.wrapping_add(index.wrapping_mul(T::SIZE)))
+    }
+    /// Read the `T` pointed to
+    pub fn read<'data, M: MemoryTrait<'data> + ?Sized>(
+        &self,
+        memory: &M,
+    ) -> Result<T, MemoryAccessError> {
+        T::read_from(memory, self.address)
+    }
+    /// Read `count` consecutive `T`s starting at the one pointed to
+    pub fn read_slice<'data, M: MemoryTrait<'data> + ?Sized>(
+        &self,
+        memory: &M,
+        count: usize,
+    ) -> Result<Vec<T>, MemoryAccessError> {
+        (0..count).map(|i| self.offset(i).read(memory)).collect()
+    }
+}
+impl<T> MemoryRead for Ptr<T> {
+    const SIZE: usize = 8;
+    const ALIGN: usize = 8;
+    fn read_from<'data, M: MemoryTrait<'data> + ?Sized>(
+        memory: &M,
+        address: usize,
+    ) -> Result<Self, MemoryAccessError> {
+        Ok(Self::new(memory.ptr(address)?))
+    }
+}
+impl<T> Clone for Ptr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for Ptr<T> {}
+impl<T> PartialEq for Ptr<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.address == other.address
+    }
+}
+impl<T> Eq for Ptr<T> {}
+impl<T> std::fmt::Debug for Ptr<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Ptr({:#x})", self.address)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::MemorySection;
+
+    /// Memory at 0x1000 where each byte is its offset, with `patches` written over it
+    fn memory(patches: &[(usize, &[u8])]) -> MemorySection<'static> {
+        let mut data = (0..=0xffu8).collect::<Vec<_>>();
+        for (offset, bytes) in patches {
+            data[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+        }
+        MemorySection {
+            address: 0x1000,
+            data: Cow::Owned(data),
+        }
+    }
+
+    #[derive(Debug, PartialEq, MemoryRead)]
+    struct Mixed {
+        a: u8,
+        b: u32,
+        c: u16,
+    }
+
+    #[test]
+    fn test_padding() {
+        assert_eq!((12, 4), (Mixed::SIZE, Mixed::ALIGN));
+        assert_eq!(
+            Ok(Mixed {
+                a: 0x10,
+                b: 0x17161514,
+                c: 0x1918,
+            }),
+            Mixed::read_from(&memory(&[]), 0x1010)
+        );
+    }
+
+    #[test]
+    fn test_fixed_offset() {
+        #[derive(Debug, PartialEq, MemoryRead)]
+        struct Fixed {
+            a: u8,
+            #[memory(offset = 0x10)]
+            b: u16,
+            c: u8,
+        }
+        assert_eq!((0x14, 2), (Fixed::SIZE, Fixed::ALIGN));
+        assert_eq!(
+            Ok(Fixed {
+                a: 0,
+                b: 0x1110,
+                c: 0x12,
+            }),
+            Fixed::read_from(&memory(&[]), 0x1000)
+        );
+    }
+
+    #[test]
+    fn test_nested() {
+        #[derive(Debug, PartialEq, MemoryRead)]
+        struct Outer {
+            a: u8,
+            inner: Mixed,
+            b: u8,
+        }
+        assert_eq!((20, 4), (Outer::SIZE, Outer::ALIGN));
+        assert_eq!(
+            Ok(Outer {
+                a: 0,
+                inner: Mixed {
+                    a: 4,
+                    b: 0x0b0a0908,
+                    c: 0x0d0c,
+                },
+                b: 0x10,
+            }),
+            Outer::read_from(&memory(&[]), 0x1000)
+        );
+
+        #[derive(Debug, PartialEq, MemoryRead)]
+        struct Tuple(u8, (u8, u64));
+        assert_eq!((24, 8), (Tuple::SIZE, Tuple::ALIGN));
+        assert_eq!(
+            Ok(Tuple(0, (8, 0x1716151413121110))),
+            Tuple::read_from(&memory(&[]), 0x1000)
+        );
+    }
+
+    #[test]
+    fn test_array() {
+        #[derive(Debug, PartialEq, MemoryRead)]
+        struct Array {
+            a: u8,
+            b: [u16; 3],
+            c: u8,
+        }
+        assert_eq!((10, 2), (Array::SIZE, Array::ALIGN));
+        assert_eq!(
+            Ok(Array {
+                a: 0,
+                b: [0x0302, 0x0504, 0x0706],
+                c: 8,
+            }),
+            Array::read_from(&memory(&[]), 0x1000)
+        );
+        assert_eq!(36, <[Mixed; 3]>::SIZE);
+    }
+
+    #[test]
+    fn test_ptr() {
+        #[derive(Debug, PartialEq, MemoryRead)]
+        struct Pointer {
+            a: u32,
+            p: Ptr<Mixed>,
+        }
+        assert_eq!((16, 8), (Pointer::SIZE, Pointer::ALIGN));
+        let mem = memory(&[(0x8, &0x1040usize.to_le_bytes())]);
+        let value = Pointer::read_from(&mem, 0x1000).unwrap();
+        assert_eq!((0x03020100, Ptr::new(0x1040)), (value.a, value.p));
+        assert_eq!(
+            Ok(Mixed {
+                a: 0x40,
+                b: 0x47464544,
+                c: 0x4948,
+            }),
+            value.p.read(&mem)
+        );
+        assert_eq!(
+            Ok(vec![0x40, 0x4c]),
+            value
+                .p
+                .read_slice(&mem, 2)
+                .map(|items| items.iter().map(|m| m.a).collect::<Vec<_>>())
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        #[derive(Debug, PartialEq, MemoryRead)]
+        struct Strings {
+            a: u8,
+            #[memory(utf8)]
+            narrow: String,
+            #[memory(utf16)]
+            wide: String,
+        }
+        assert_eq!((24, 8), (Strings::SIZE, Strings::ALIGN));
+        let wide = "wide\0"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<_>>();
+        let mem = memory(&[
+            (0x8, &0x1040usize.to_le_bytes()),
+            (0x10, &0x1050usize.to_le_bytes()),
+            (0x40, b"narrow\0"),
+            (0x50, &wide),
+        ]);
+        assert_eq!(
+            Ok(Strings {
+                a: 0,
+                narrow: "narrow".into(),
+                wide: "wide".into(),
+            }),
+            Strings::read_from(&mem, 0x1000)
+        );
+        // strings are read through the pointer so a bad pointer fails the whole read
+        let mem = memory(&[(0x8, &0x2000usize.to_le_bytes())]);
+        assert_eq!(
+            Err(MemoryAccessError::MemoryOutOfBoundsError(0x2000)),
+            Strings::read_from(&mem, 0x1000)
+        );
+    }
+}
@@ -1,21 +1,23 @@
 pub mod unreal;
 
-use crate::{Image, MemoryAccessError};
+use crate::{CompoundPattern, Image, MemoryAccessError};
 use futures::{
     channel::oneshot,
     executor::LocalPool,
     future::{join_all, BoxFuture},
+    join,
 };
 use futures_scopes::{
     relay::{new_relay_scope, RelayScopeLocalSpawning},
     ScopedSpawnExt, SpawnScope,
 };
-use patternsleuth_scanner::Pattern;
+use patternsleuth_scanner::{scan_xref, Match, Pattern, PatternSet, ScanMatches, Xref, XrefKind};
 use std::{
     any::{Any, TypeId},
     borrow::Cow,
     collections::HashMap,
     error::Error,
+    ops::Range,
     sync::{Arc, Mutex},
 };
 
@@ -455,20 +457,44 @@ pub trait Singleton {
 type AnyValue = Result<Arc<dyn Any + Send + Sync>>;
 
 #[derive(Debug)]
-struct PatternMatches {
+struct PatternMatches<'data> {
     pattern: Pattern,
-    matches: Vec<usize>,
+    matches: ScanMatches<Match<'data>>,
+}
+
+/// Part of the image an [`AsyncContext`] scan is restricted to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanScope {
+    /// Every section
+    All,
+    /// Only sections of a kind
+    Section(object::SectionKind),
+    /// Only inside an address range
+    Range(Range<usize>),
+    /// Only inside the root function containing the address
+    Function(usize),
+}
+impl ScanScope {
+    fn includes_section(&self, kind: object::SectionKind) -> bool {
+        match self {
+            Self::All => true,
+            Self::Section(k) => *k == kind,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Default)]
-struct AsyncContextInnerWrite {
+struct AsyncContextInnerWrite<'data> {
     resolvers: HashMap<TypeId, AnyValue>,
     pending_resolvers: HashMap<TypeId, Vec<oneshot::Sender<AnyValue>>>,
-    queue: Vec<(Pattern, oneshot::Sender<PatternMatches>)>,
+    /// pending scans, function scopes are resolved to ranges before being queued
+    queue: Vec<(ScanScope, Pattern, oneshot::Sender<PatternMatches<'data>>)>,
+    xref_queue: Vec<(XrefKind, Xref, oneshot::Sender<Vec<usize>>)>,
 }
 
 struct AsyncContextInnerRead<'data> {
-    write: Mutex<AsyncContextInnerWrite>,
+    write: Mutex<AsyncContextInnerWrite<'data>>,
     image: &'data Image<'data>,
 }
 
@@ -501,13 +527,130 @@ impl<'data> AsyncContext<'data> {
             .collect()
     }
     pub async fn scan_tagged<T>(&self, tag: T, pattern: Pattern) -> (T, Pattern, Vec<usize>) {
+        let (tag, pattern, matches) = self.scan_captures_tagged(tag, pattern).await;
+        (
+            tag,
+            pattern,
+            matches.into_iter().map(|m| m.address).collect(),
+        )
+    }
+    /// Scan for pattern honoring its match limit and reporting whether it was hit, useful to
+    /// detect ambiguous patterns without collecting every match
+    pub async fn scan_limited(&self, pattern: Pattern) -> ScanMatches<usize> {
+        let ScanMatches { matches, limit_hit } = self.queue_scan(ScanScope::All, pattern).await.1;
+        ScanMatches {
+            matches: matches.into_iter().map(|m| m.address).collect(),
+            limit_hit,
+        }
+    }
+    /// Scan for matches of `compound.first` with a match of `compound.second` nearby. Both
+    /// patterns are scanned in the same pass over memory.
+    pub async fn scan_compound(&self, compound: CompoundPattern) -> Vec<usize> {
+        let (first, second) = join!(
+            self.scan(compound.first.clone()),
+            self.scan(compound.second.clone())
+        );
+        compound.filter_matches(self.image(), &first, &second)
+    }
+    /// Scan for pattern returning each match along with its captures
+    pub async fn scan_captures(&self, pattern: Pattern) -> Vec<Match<'data>> {
+        self.scan_captures_tagged((), pattern).await.2
+    }
+    pub async fn scan_captures_tagged<T>(
+        &self,
+        tag: T,
+        pattern: Pattern,
+    ) -> (T, Pattern, Vec<Match<'data>>) {
+        let (pattern, matches) = self.queue_scan(ScanScope::All, pattern).await;
+        (tag, pattern, matches.matches)
+    }
+    /// Scan for pattern only in sections of `kind`
+    pub async fn scan_section(&self, kind: object::SectionKind, pattern: Pattern) -> Vec<usize> {
+        self.scan_scoped(ScanScope::Section(kind), pattern).await
+    }
+    /// Scan for pattern only inside `range`
+    pub async fn scan_range(&self, range: Range<usize>, pattern: Pattern) -> Vec<usize> {
+        self.scan_scoped(ScanScope::Range(range), pattern).await
+    }
+    /// Scan for pattern only inside the root function containing `address`
+    pub async fn scan_function(&self, address: usize, pattern: Pattern) -> Vec<usize> {
+        self.scan_scoped(ScanScope::Function(address), pattern)
+            .await
+    }
+    pub async fn scan_scoped(&self, scope: ScanScope, pattern: Pattern) -> Vec<usize> {
+        self.scan_captures_scoped(scope, pattern)
+            .await
+            .into_iter()
+            .map(|m| m.address)
+            .collect()
+    }
+    pub async fn scan_captures_scoped(
+        &self,
+        scope: ScanScope,
+        pattern: Pattern,
+    ) -> Vec<Match<'data>> {
+        self.queue_scan(scope, pattern).await.1.matches
+    }
+    /// Scan for rel32 references to `address` returning the address of each displacement
+    pub async fn scan_xref(&self, address: usize) -> Vec<usize> {
+        self.scan_xref_kind(XrefKind::Rel32, address).await
+    }
+    /// Scan for references to `address` encoded as `kind` returning the address of each reference
+    pub async fn scan_xref_kind(&self, kind: XrefKind, address: usize) -> Vec<usize> {
+        let (tx, rx) = oneshot::channel::<Vec<usize>>();
+        {
+            let mut lock = self.read.write.lock().unwrap();
+            lock.xref_queue.push((kind, Xref(address), tx));
+        }
+        rx.await.unwrap()
+    }
+    /// Find the first match of `pattern` starting at most `max_distance` bytes after `address`
+    /// without waiting for an image-wide scan
+    pub fn find_forward(
+        &self,
+        pattern: &Pattern,
+        address: usize,
+        max_distance: usize,
+    ) -> Result<Option<usize>> {
+        Ok(self
+            .image()
+            .memory
+            .find_forward(pattern, address, max_distance)?)
+    }
+    /// Find the closest match of `pattern` starting at most `max_distance` bytes before
+    /// `address` without waiting for an image-wide scan
+    pub fn find_backward(
+        &self,
+        pattern: &Pattern,
+        address: usize,
+        max_distance: usize,
+    ) -> Result<Option<usize>> {
+        Ok(self
+            .image()
+            .memory
+            .find_backward(pattern, address, max_distance)?)
+    }
+    async fn queue_scan(
+        &self,
+        scope: ScanScope,
+        pattern: Pattern,
+    ) -> (Pattern, ScanMatches<Match<'data>>) {
+        let scope = match scope {
+            ScanScope::Function(address) => {
+                match self.image().get_root_function_range_containing(address) {
+                    Ok(Some(range)) => ScanScope::Range(range),
+                    _ => return (pattern, Default::default()),
+                }
+            }
+            scope => scope,
+        };
         let (tx, rx) = oneshot::channel::<PatternMatches>();
         {
             let mut lock = self.read.write.lock().unwrap();
-            lock.queue.push((pattern, tx));
+            lock.queue.push((scope, pattern, tx));
         }
         let PatternMatches { pattern, matches } = rx.await.unwrap();
-        (tag, pattern, matches)
+        (pattern, matches)
     }
     pub async fn resolve<T: Send + Sync + 'static>(
         &self,
@@ -564,8 +707,30 @@ impl<'data> AsyncContext<'data> {
     }
 }
 
-#[tracing::instrument(level = "debug", skip_all, fields(stages))]
 pub fn eval<F, T: Send + Sync>(image: &Image<'_>, f: F) -> T
+where
+    F: for<'ctx> FnOnce(&'ctx AsyncContext<'_>) -> BoxFuture<'ctx, T> + Send + Sync,
+{
+    eval_inner(image, None, f)
+}
+
+/// Like [`eval`] but queued scans over the whole image of patterns in `pattern_set` use the
+/// precompiled set instead of compiling them again. The set is scanned at most once, in the pass
+/// of the first stage which queues any of its patterns, so it can be shared between many images
+/// without adding passes over memory.
+pub fn eval_with_pattern_set<F, T: Send + Sync>(
+    image: &Image<'_>,
+    pattern_set: &PatternSet<'_>,
+    f: F,
+) -> T
+where
+    F: for<'ctx> FnOnce(&'ctx AsyncContext<'_>) -> BoxFuture<'ctx, T> + Send + Sync,
+{
+    eval_inner(image, Some(pattern_set), f)
+}
+
+#[tracing::instrument(level = "debug", skip_all, fields(stages))]
+fn eval_inner<F, T: Send + Sync>(image: &Image<'_>, pattern_set: Option<&PatternSet<'_>>, f: F) -> T
 where
     F: for<'ctx> FnOnce(&'ctx AsyncContext<'_>) -> BoxFuture<'ctx, T> + Send + Sync,
 {
@@ -590,6 +755,8 @@ where
             .unwrap();
 
         let mut i = 0;
+        let function_starts = std::cell::OnceCell::new();
+        let mut precompiled = pattern_set.map(|set| Precompiled::new(image, set));
 
         loop {
             i += 1;
@@ -602,50 +769,251 @@ where
                 tracing::Span::current().record("stages", i);
                 break res;
             } else {
-                let queue: Vec<_> = std::mem::take(&mut ctx.read.write.lock().unwrap().queue);
-                let (patterns, rx): (Vec<_>, Vec<_>) = queue.into_iter().unzip();
-                let setup = patterns.iter().collect::<Vec<_>>();
+                let (queue, xref_queue) = {
+                    let mut lock = ctx.read.write.lock().unwrap();
+                    (
+                        std::mem::take(&mut lock.queue),
+                        std::mem::take(&mut lock.xref_queue),
+                    )
+                };
+                let (xrefs, xref_rx): (Vec<_>, Vec<_>) = xref_queue
+                    .into_iter()
+                    .map(|(kind, xref, rx)| ((kind, xref), rx))
+                    .unzip();
+                let xrefs = xrefs
+                    .iter()
+                    .map(|(kind, xref)| (*kind, xref))
+                    .collect::<Vec<_>>();
+                let (scans, rx): (Vec<_>, Vec<_>) = queue
+                    .into_iter()
+                    .map(|(scope, pattern, rx)| ((scope, pattern), rx))
+                    .unzip();
+
+                let span =
+                    tracing::debug_span!("patterns", patterns = scans.len(), xrefs = xrefs.len())
+                        .entered();
+                for (scope, p) in &scans {
+                    tracing::debug!("pattern = {p:?} scope = {scope:?}");
+                }
+                for (kind, xref) in &xrefs {
+                    tracing::debug!("xref = {xref:x?} kind = {kind:?}");
+                }
 
-                let span = tracing::debug_span!("patterns", patterns = setup.len()).entered();
-                for p in &setup {
-                    tracing::debug!("pattern = {p:?}");
+                let (all_results, xref_results) = scan_queued(
+                    image,
+                    &scans,
+                    &xrefs,
+                    &function_starts,
+                    precompiled.as_mut(),
+                );
+                drop(span);
+
+                for (((_, pattern), rx), matches) in scans.into_iter().zip(rx).zip(all_results) {
+                    rx.send(PatternMatches { pattern, matches }).unwrap();
                 }
+                for (rx, matches) in xref_rx.into_iter().zip(xref_results) {
+                    rx.send(matches).unwrap();
+                }
+            }
+        }
+    }
+}
 
-                let mut all_results = rx.into_iter().map(|rx| (rx, vec![])).collect::<Vec<_>>();
+/// Precompiled [`PatternSet`] given to [`eval_with_pattern_set`] along with its matches once
+/// scanned
+struct Precompiled<'set, 'p, 'data> {
+    set: Cow<'set, PatternSet<'p>>,
+    matches: Option<Vec<ScanMatches<Match<'data>>>>,
+}
+impl<'set, 'p> Precompiled<'set, 'p, '_> {
+    fn new(image: &Image<'_>, set: &'set PatternSet<'p>) -> Self {
+        let set = image.with_function_starts(set).unwrap_or_else(|err| {
+            tracing::warn!("failed to read function starts: {err}");
+            // without function starts nothing can be verified so nothing matches
+            Cow::Owned(set.clone().function_starts([]))
+        });
+        Self { set, matches: None }
+    }
+}
 
-                for section in image.memory.sections() {
-                    let span = tracing::debug_span!(
-                        "section",
-                        section = section.name(),
-                        kind = format!("{:?}", section.kind()),
-                        results = tracing::field::Empty
-                    )
-                    .entered();
+/// Limits apply to each section so apply them again to the combined matches
+fn apply_limit(pattern: &Pattern, matches: &mut ScanMatches<Match<'_>>) {
+    if let Some(limit) = pattern.limit {
+        if matches.matches.len() > limit {
+            matches.matches.sort_by_key(|m| m.address);
+            matches.matches.truncate(limit);
+            matches.limit_hit = true;
+        }
+    }
+}
 
-                    let base_address = section.address();
-                    let data = section.data();
+/// Scan every queued pattern inside its scope and every queued xref in a single pass over the
+/// sections of `image`. Scans over the whole image of patterns in `precompiled` are served from
+/// its matches, scanning it in this pass if it hasn't been scanned yet.
+#[allow(clippy::type_complexity)]
+fn scan_queued<'data>(
+    image: &'data Image<'data>,
+    scans: &[(ScanScope, Pattern)],
+    xrefs: &[(XrefKind, &Xref)],
+    function_starts: &std::cell::OnceCell<Vec<usize>>,
+    precompiled: Option<&mut Precompiled<'_, '_, 'data>>,
+) -> (Vec<ScanMatches<Match<'data>>>, Vec<Vec<usize>>) {
+    let mut xref_results = xrefs.iter().map(|_| vec![]).collect::<Vec<_>>();
+    let mut all_results = scans
+        .iter()
+        .map(|_| ScanMatches::default())
+        .collect::<Vec<_>>();
+
+    // index into the precompiled set of each scan it serves
+    let from_set = scans
+        .iter()
+        .map(|(scope, pattern)| {
+            precompiled
+                .as_ref()
+                .filter(|_| *scope == ScanScope::All)
+                .and_then(|p| p.set.patterns().iter().position(|p| *p == pattern))
+        })
+        .collect::<Vec<_>>();
+    let scan_set = precompiled.as_ref().is_some_and(|p| p.matches.is_none())
+        && from_set.iter().any(Option::is_some);
+    let mut set_results = precompiled
+        .as_ref()
+        .filter(|_| scan_set)
+        .map(|p| {
+            p.set
+                .patterns()
+                .iter()
+                .map(|_| ScanMatches::default())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let compile = |indexes: Vec<usize>| {
+        let patterns = indexes.iter().map(|i| &scans[*i].1).collect::<Vec<_>>();
+        let mut set = PatternSet::new(&patterns);
+        if patterns.iter().any(|p| p.function_start) {
+            // without function starts nothing can be verified so nothing matches
+            let starts = function_starts.get_or_init(|| {
+                image.function_starts().unwrap_or_else(|err| {
+                    tracing::warn!("failed to read function starts: {err}");
+                    vec![]
+                })
+            });
+            set = set.function_starts(starts.iter().copied());
+        }
+        (set, indexes)
+    };
 
-                    let scan_results =
-                        patternsleuth_scanner::scan_pattern(&setup, base_address, data);
+    // compile once for each kind of section
+    let mut section_sets = HashMap::new();
+
+    // compile once for each distinct range and only scan the part of each section
+    // inside the range
+    let mut ranges: Vec<(Range<usize>, Vec<usize>)> = vec![];
+    for (i, (scope, _)) in scans.iter().enumerate() {
+        if let ScanScope::Range(range) = scope {
+            match ranges.iter_mut().find(|(r, _)| r == range) {
+                Some((_, indexes)) => indexes.push(i),
+                None => ranges.push((range.clone(), vec![i])),
+            }
+        }
+    }
+    let range_sets = ranges
+        .into_iter()
+        .map(|(range, indexes)| (range, compile(indexes)))
+        .collect::<Vec<_>>();
+
+    for section in image.memory.sections() {
+        let span = tracing::debug_span!(
+            "section",
+            section = section.name(),
+            kind = format!("{:?}", section.kind()),
+            results = tracing::field::Empty
+        )
+        .entered();
+
+        let base_address = section.address();
+        let data = section.data();
+
+        let (pattern_set, indexes) = section_sets.entry(section.kind()).or_insert_with(|| {
+            compile(
+                scans
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, (scope, _))| {
+                        scope.includes_section(section.kind()) && from_set[*i].is_none()
+                    })
+                    .map(|(i, _)| i)
+                    .collect(),
+            )
+        });
 
-                    let mut total = 0;
+        let mut scan_results = indexes
+            .iter()
+            .zip(pattern_set.scan_captures_limited(base_address, data))
+            .collect::<Vec<_>>();
+
+        for (range, (pattern_set, indexes)) in &range_sets {
+            let start = range.start.max(base_address);
+            let end = range.end.min(base_address + data.len());
+            if start < end {
+                scan_results.extend(indexes.iter().zip(pattern_set.scan_captures_limited(
+                    start,
+                    &data[start - base_address..end - base_address],
+                )));
+            }
+        }
 
-                    for (i, res) in scan_results.iter().enumerate() {
-                        total += res.len();
-                        all_results[i].1.extend(res)
-                    }
+        let mut total = 0;
 
-                    span.record("results", total);
-                }
+        for (i, res) in scan_results {
+            total += res.matches.len();
+            let all = &mut all_results[*i];
+            all.matches.extend(res.matches);
+            all.limit_hit |= res.limit_hit;
+        }
 
-                drop(span);
+        if let Some(p) = precompiled.as_ref().filter(|_| scan_set) {
+            let scan = p.set.scan_captures_limited(base_address, data);
+            for (all, res) in set_results.iter_mut().zip(scan) {
+                total += res.matches.len();
+                all.matches.extend(res.matches);
+                all.limit_hit |= res.limit_hit;
+            }
+        }
 
-                for ((rx, matches), pattern) in all_results.into_iter().zip(patterns) {
-                    rx.send(PatternMatches { pattern, matches }).unwrap();
+        // single pass for every xref
+        if !xrefs.is_empty() {
+            for (i, res) in scan_xref(xrefs, base_address, data).into_iter().enumerate() {
+                total += res.len();
+                xref_results[i].extend(res)
+            }
+        }
+
+        span.record("results", total);
+    }
+
+    if let Some(p) = precompiled {
+        if scan_set {
+            for (pattern, matches) in p.set.patterns().iter().zip(&mut set_results) {
+                apply_limit(pattern, matches);
+            }
+            p.matches = Some(set_results);
+        }
+        if let Some(matches) = &p.matches {
+            for (all, j) in all_results.iter_mut().zip(&from_set) {
+                if let Some(j) = j {
+                    *all = matches[*j].clone();
                 }
             }
         }
     }
+
+    for ((_, pattern), matches) in scans.iter().zip(&mut all_results) {
+        apply_limit(pattern, matches);
+    }
+
+    (all_results, xref_results)
 }
 
 pub fn resolve<T: Send + Sync>(
@@ -665,3 +1033,121 @@ pub fn resolve_many(
         Box::pin(async { join_all(fns.into_iter().map(|f| f(ctx))).await })
     })
 }
+
+#[cfg(all(test, feature = "image-pe"))]
+mod test {
+    use super::*;
+    use crate::image::pe::PEImage;
+    use object::SectionKind;
+
+    #[test]
+    fn test_scan_queued_scopes() {
+        let mut text = vec![0; 0x200];
+        text[0x10..0x12].copy_from_slice(&[0xaa, 0xbb]);
+        text[0x100..0x102].copy_from_slice(&[0xaa, 0xbb]);
+        let mut data = vec![0; 0x100];
+        data[0x10..0x12].copy_from_slice(&[0xaa, 0xbb]);
+        let image = PEImage::synthetic(
+            vec![
+                (".text", SectionKind::Text, 0x1000, text),
+                (".data", SectionKind::Data, 0x3000, data),
+            ],
+            &[],
+        );
+
+        let pattern = Pattern::new("aa bb").unwrap();
+        let scopes = [
+            (ScanScope::All, vec![0x1010, 0x1100, 0x3010]),
+            (ScanScope::Section(SectionKind::Text), vec![0x1010, 0x1100]),
+            (ScanScope::Section(SectionKind::Data), vec![0x3010]),
+            // clipped to the part of each section inside the range
+            (ScanScope::Range(0x1050..0x4000), vec![0x1100, 0x3010]),
+            // matches must lie entirely inside the range
+            (ScanScope::Range(0x1011..0x1101), vec![]),
+            (ScanScope::Range(0x2000..0x3000), vec![]),
+        ];
+        let scans = scopes
+            .iter()
+            .map(|(scope, _)| (scope.clone(), pattern.clone()))
+            .collect::<Vec<_>>();
+
+        let (results, _) = scan_queued(&image, &scans, &[], &Default::default(), None);
+        for ((scope, expected), res) in scopes.iter().zip(results) {
+            let mut found = res.matches.iter().map(|m| m.address).collect::<Vec<_>>();
+            found.sort();
+            assert_eq!(expected, &found, "{scope:x?}");
+        }
+    }
+
+    #[test]
+    fn test_scan_queued_pattern_set() {
+        let mut text = vec![0; 0x200];
+        text[0x10..0x12].copy_from_slice(&[0xaa, 0xbb]);
+        text[0x100..0x102].copy_from_slice(&[0xaa, 0xbb]);
+        text[0x180..0x182].copy_from_slice(&[0xcc, 0xdd]);
+        let mut data = vec![0; 0x100];
+        data[0x10..0x12].copy_from_slice(&[0xaa, 0xbb]);
+        let image = PEImage::synthetic(
+            vec![
+                (".text", SectionKind::Text, 0x1000, text),
+                (".data", SectionKind::Data, 0x3000, data),
+            ],
+            &[],
+        );
+
+        let a = Pattern::new("aa bb").unwrap();
+        let first = a.clone().first_match();
+        let c = Pattern::new("cc dd").unwrap();
+        let set_patterns = [&a, &first];
+        let set = PatternSet::new(&set_patterns);
+        let mut precompiled = Precompiled::new(&image, &set);
+
+        let scans = [
+            (ScanScope::All, a.clone()),
+            // one match across all sections, not one per section
+            (ScanScope::All, first.clone()),
+            // not served by the set as it is scoped
+            (ScanScope::Section(SectionKind::Data), a.clone()),
+            (ScanScope::All, c.clone()),
+        ];
+        let addresses = |results: Vec<ScanMatches<Match<'_>>>| {
+            results
+                .into_iter()
+                .map(|res| {
+                    let mut found = res.matches.iter().map(|m| m.address).collect::<Vec<_>>();
+                    found.sort();
+                    (found, res.limit_hit)
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            (vec![0x1010, 0x1100, 0x3010], false),
+            (vec![0x1010], true),
+            (vec![0x3010], false),
+            (vec![0x1180], false),
+        ];
+        let (results, _) = scan_queued(
+            &image,
+            &scans,
+            &[],
+            &Default::default(),
+            Some(&mut precompiled),
+        );
+        assert_eq!(expected, addresses(results));
+        assert!(precompiled.matches.is_some());
+
+        // later stages reuse the matches without scanning the set again
+        let other = PEImage::synthetic(
+            vec![(".text", SectionKind::Text, 0x1000, vec![0; 0x200])],
+            &[],
+        );
+        let (results, _) = scan_queued(
+            &other,
+            &scans[..1],
+            &[],
+            &Default::default(),
+            Some(&mut precompiled),
+        );
+        assert_eq!(expected[..1], addresses(results));
+    }
+}
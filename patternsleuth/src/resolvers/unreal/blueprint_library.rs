@@ -80,28 +80,28 @@ impl_resolver!(all, BlueprintLibraryInit, |ctx| async {
         join_all(
             class_str
                 .iter()
-                .map(|s| ctx.scan_tagged((), pattern_dyn_init_class(*s)))
+                .map(|s| ctx.scan_captures(pattern_dyn_init_class(*s)))
         ),
         join_all(
             class_str
                 .iter()
-                .map(|s| ctx.scan_tagged((), pattern_dyn_init_object(*s)))
+                .map(|s| ctx.scan_captures(pattern_dyn_init_object(*s)))
         )
     );
 
-    let uclass_compiled_in_defer =
-        ensure_one(init_class_refs.into_iter().flat_map(|(_, p, a)| {
-            a.into_iter()
-                .flat_map(move |a| mem.captures(&p, a).ok().flatten().map(|c| c[1].rip()))
-        }))?;
+    let uclass_compiled_in_defer = ensure_one(
+        init_class_refs
+            .iter()
+            .flatten()
+            .map(|m| m.captures[1].rip()),
+    )?;
     let (get_private_static_class_wrapper, construct_uclass_wrapper, uobject_compiled_in_defer) =
-        ensure_one(init_object_refs.into_iter().flat_map(|(_, p, a)| {
-            a.into_iter().flat_map(move |a| {
-                mem.captures(&p, a)
-                    .ok()
-                    .flatten()
-                    .map(|c| (c[0].rip(), c[1].rip(), c[2].rip()))
-            })
+        ensure_one(init_object_refs.iter().flatten().map(|m| {
+            (
+                m.captures[0].rip(),
+                m.captures[1].rip(),
+                m.captures[2].rip(),
+            )
         }))?;
 
     let construct_uclass = mem
@@ -0,0 +1,461 @@
+//! Readers for Unreal's core containers
+
+use crate::{
+    read::{MemoryRead, Ptr},
+    resolvers::{bail_out, Context, Result},
+    MemoryTrait,
+};
+
+use super::{engine_version::EngineVersion, fname::FNamePool};
+
+#[derive(MemoryRead)]
+struct TArray {
+    data: usize,
+    num: i32,
+    max: i32,
+}
+
+/// Leading fields of `TSparseArray` whose `TBitArray` allocation flags are read as laid out by
+/// [`Layout`]
+struct TSparseArray {
+    data: TArray,
+    allocation_flags_inline: Vec<u32>,
+    allocation_flags_secondary: usize,
+    num_bits: i32,
+    max_bits: i32,
+}
+
+#[derive(MemoryRead)]
+struct FName {
+    comparison_index: u32,
+    number: u32,
+}
+
+/// `FNamePool` replaced `TNameEntryArray` in 4.23
+const NAME_POOL_VERSION: EngineVersion = EngineVersion {
+    major: 4,
+    minor: 23,
+};
+
+/// Offsets of `FNameEntryAllocator::Blocks` in `FNamePool` depending on the size of `FRWLock`
+/// (`SRWLOCK` on Windows, `pthread_rwlock_t` elsewhere)
+const NAME_POOL_BLOCKS: [usize; 2] = [0x10, 0x40];
+
+/// Container layouts which depend on the engine version
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    /// Candidate offsets of `FNameEntryAllocator::Blocks`, `None` for versions without `FNamePool`
+    name_pool_blocks: Option<&'static [usize]>,
+    /// Number of `u32` words of the `TInlineAllocator` of the `TBitArray` holding the allocation
+    /// flags of `TSparseArray`
+    sparse_array_inline_words: usize,
+    /// Size of `FSetElementId HashNextId` and `int32 HashIndex` following each `TSet` value
+    set_element_hash: usize,
+}
+
+impl Layout {
+    fn new(version: EngineVersion) -> Self {
+        Self {
+            name_pool_blocks: (version >= NAME_POOL_VERSION).then_some(&NAME_POOL_BLOCKS[..]),
+            sparse_array_inline_words: 4,
+            set_element_hash: 8,
+        }
+    }
+}
+
+/// Reads Unreal containers from `memory` using the layouts of engine `version`
+pub struct UnrealReader<'m, M: ?Sized> {
+    pub memory: &'m M,
+    pub version: EngineVersion,
+}
+
+/// Entry point for reading Unreal containers, implemented for all [`MemoryTrait`]s so it works on a
+/// static image as well as on live or dumped process memory
+pub trait UnrealMemoryTrait<'data>: MemoryTrait<'data> {
+    /// Read Unreal containers from this memory as laid out by engine `version`
+    fn unreal(&self, version: EngineVersion) -> UnrealReader<'_, Self> {
+        UnrealReader {
+            memory: self,
+            version,
+        }
+    }
+}
+impl<'data, T: MemoryTrait<'data> + ?Sized> UnrealMemoryTrait<'data> for T {}
+
+impl<M: ?Sized> UnrealReader<'_, M> {
+    fn layout(&self) -> Layout {
+        Layout::new(self.version)
+    }
+
+    /// Read `TArray<T>` at `address`
+    pub fn tarray<'data, T: MemoryRead>(&self, address: usize) -> Result<Vec<T>>
+    where
+        M: MemoryTrait<'data>,
+    {
+        let array = TArray::read_from(self.memory, address)?;
+        if array.num < 0 || array.num > array.max {
+            bail_out!("invalid TArray");
+        }
+        Ok(Ptr::<T>::new(array.data).read_slice(self.memory, array.num as usize)?)
+    }
+
+    /// Read `FString` at `address`
+    pub fn fstring<'data>(&self, address: usize) -> Result<String>
+    where
+        M: MemoryTrait<'data>,
+    {
+        let chars = self.tarray::<u16>(address)?;
+        let len = chars.iter().position(|c| *c == 0).unwrap_or(chars.len());
+        String::from_utf16(&chars[..len])
+            .ok()
+            .context("invalid FString")
+    }
+
+    /// Read `TCHAR*` string at `address` of at most `max_len` characters
+    pub fn tchar<'data>(&self, address: usize, max_len: usize) -> Result<String>
+    where
+        M: MemoryTrait<'data>,
+    {
+        let chars = self
+            .memory
+            .range_from(address..)?
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take(max_len)
+            .take_while(|c| *c != 0)
+            .collect::<Vec<_>>();
+        String::from_utf16(&chars)
+            .ok()
+            .context("invalid TCHAR string")
+    }
+
+    /// Read the leading fields of `TSparseArray` at `address`
+    fn sparse_array<'data>(&self, address: usize) -> Result<TSparseArray>
+    where
+        M: MemoryTrait<'data>,
+    {
+        let words = self.layout().sparse_array_inline_words;
+        let inline = address + TArray::SIZE;
+        let secondary = (inline + words * 4).next_multiple_of(8);
+        Ok(TSparseArray {
+            data: TArray::read_from(self.memory, address)?,
+            allocation_flags_inline: Ptr::<u32>::new(inline).read_slice(self.memory, words)?,
+            allocation_flags_secondary: self.memory.ptr(secondary)?,
+            num_bits: self.memory.i32_le(secondary + 8)?,
+            max_bits: self.memory.i32_le(secondary + 12)?,
+        })
+    }
+
+    /// Read allocated elements of `TSet<T>` at `address`
+    pub fn tset<'data, T: MemoryRead>(&self, address: usize) -> Result<Vec<T>>
+    where
+        M: MemoryTrait<'data>,
+    {
+        let layout = self.layout();
+        let elements = self.sparse_array(address)?;
+        let data = &elements.data;
+        if data.num < 0
+            || data.num > data.max
+            || elements.num_bits < data.num
+            || elements.num_bits > elements.max_bits
+        {
+            bail_out!("invalid TSet");
+        }
+
+        // TSetElement { T Value; FSetElementId HashNextId; int32 HashIndex; } in a union with the
+        // sparse array free list link
+        let value = T::SIZE.next_multiple_of(4);
+        let stride = (value + layout.set_element_hash).next_multiple_of(T::ALIGN.max(4));
+
+        let flags = if elements.allocation_flags_secondary != 0 {
+            Ptr::<u32>::new(elements.allocation_flags_secondary)
+                .read_slice(self.memory, (data.num as usize).div_ceil(32))?
+        } else if data.num as usize <= elements.allocation_flags_inline.len() * 32 {
+            elements.allocation_flags_inline
+        } else {
+            bail_out!("invalid TSet");
+        };
+
+        let mut res = vec![];
+        for i in 0..data.num as usize {
+            if flags[i / 32] & (1 << (i % 32)) != 0 {
+                res.push(T::read_from(
+                    self.memory,
+                    data.data.wrapping_add(i * stride),
+                )?);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Read allocated pairs of `TMap<K, V>` at `address`
+    pub fn tmap<'data, K: MemoryRead, V: MemoryRead>(&self, address: usize) -> Result<Vec<(K, V)>>
+    where
+        M: MemoryTrait<'data>,
+    {
+        self.tset::<(K, V)>(address)
+    }
+
+    /// Read `FName` at `address` given the address of `FNamePool`. Only supported for 4.23+
+    pub fn fname<'data>(&self, name_pool: &FNamePool, address: usize) -> Result<String>
+    where
+        M: MemoryTrait<'data>,
+    {
+        let Some(blocks) = self.layout().name_pool_blocks else {
+            bail_out!("FNamePool requires 4.23+");
+        };
+        let name = FName::read_from(self.memory, address)?;
+        let base = self.fname_entry(name_pool, blocks, name.comparison_index)?;
+        Ok(match name.number {
+            0 => base,
+            n => format!("{base}_{}", n - 1),
+        })
+    }
+
+    /// Read name of `FNameEntryId` `id` from `FNamePool` with blocks at one of `offsets`
+    fn fname_entry<'data>(
+        &self,
+        name_pool: &FNamePool,
+        offsets: &[usize],
+        id: u32,
+    ) -> Result<String>
+    where
+        M: MemoryTrait<'data>,
+    {
+        let blocks = self.fname_blocks(name_pool, offsets)?;
+        let block = self.memory.ptr(blocks + (id as usize >> 16) * 8)?;
+        // entries are aligned to 2 bytes
+        let entry = block.wrapping_add((id as usize & 0xffff) * 2);
+
+        // FNameEntryHeader { uint16 bIsWide : 1; uint16 LowercaseProbeHash : 5; uint16 Len : 10; }
+        let header = self.memory.u16_le(entry)?;
+        let len = (header >> 6) as usize;
+        let start = entry.wrapping_add(2);
+        if header & 1 != 0 {
+            let data = self.memory.read(start..start.wrapping_add(len * 2))?;
+            let chars = data
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>();
+            String::from_utf16(&chars).ok().context("invalid FName")
+        } else {
+            // ANSICHAR names are Latin-1
+            let data = self.memory.read(start..start.wrapping_add(len))?;
+            Ok(data.iter().map(|&c| c as char).collect())
+        }
+    }
+
+    /// Find `FNameEntryAllocator::Blocks` by checking which of the candidate `offsets` holds
+    /// "None" as the first entry
+    fn fname_blocks<'data>(&self, name_pool: &FNamePool, offsets: &[usize]) -> Result<usize>
+    where
+        M: MemoryTrait<'data>,
+    {
+        offsets
+            .iter()
+            .map(|offset| name_pool.0 + offset)
+            .find(|&blocks| {
+                self.memory
+                    .ptr(blocks)
+                    .and_then(|block| self.memory.read(block..block.wrapping_add(6)))
+                    .is_ok_and(|d| {
+                        let header = u16::from_le_bytes([d[0], d[1]]);
+                        header & 1 == 0 && header >> 6 == 4 && &d[2..] == b"None"
+                    })
+            })
+            .context("FNamePool blocks not found")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::MemorySection;
+
+    const BASE: usize = 0x1000;
+    const VERSION: EngineVersion = EngineVersion { major: 5, minor: 1 };
+
+    /// Memory at [`BASE`] built by writing values at absolute addresses
+    #[derive(Default)]
+    struct Builder(Vec<u8>);
+    impl Builder {
+        fn put(&mut self, address: usize, bytes: impl AsRef<[u8]>) -> &mut Self {
+            let bytes = bytes.as_ref();
+            let offset = address - BASE;
+            if self.0.len() < offset + bytes.len() {
+                self.0.resize(offset + bytes.len(), 0);
+            }
+            self.0[offset..offset + bytes.len()].copy_from_slice(bytes);
+            self
+        }
+        fn tarray(&mut self, address: usize, data: usize, num: i32, max: i32) -> &mut Self {
+            self.put(address, data.to_le_bytes())
+                .put(address + 8, num.to_le_bytes())
+                .put(address + 12, max.to_le_bytes())
+        }
+        /// `TSparseArray` of `num` elements with bits of `flags` set, inline if `secondary` is 0
+        fn tset(
+            &mut self,
+            address: usize,
+            data: usize,
+            num: i32,
+            flags: &[u32],
+            secondary: usize,
+        ) -> &mut Self {
+            self.tarray(address, data, num, num);
+            let flags = flags
+                .iter()
+                .flat_map(|f| f.to_le_bytes())
+                .collect::<Vec<_>>();
+            if secondary == 0 {
+                self.put(address + 0x10, flags);
+            } else {
+                self.put(secondary, flags);
+            }
+            self.put(address + 0x20, secondary.to_le_bytes())
+                .put(address + 0x28, num.to_le_bytes())
+                .put(address + 0x2c, 128i32.max(num).to_le_bytes())
+        }
+        fn build(&self) -> MemorySection<'static> {
+            MemorySection {
+                address: BASE,
+                data: Cow::Owned(self.0.clone()),
+            }
+        }
+    }
+
+    fn utf16(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn test_tarray() {
+        let section = Builder::default()
+            .tarray(0x1000, 0x1100, 3, 4)
+            .tarray(0x1010, 0x1100, 5, 4)
+            .tarray(0x1020, 0x1100, -1, 4)
+            .tarray(0x1030, 0, 0, 0)
+            .put(0x1100, [1u32, 2, 3].map(u32::to_le_bytes).concat())
+            .build();
+        let mem = section.unreal(VERSION);
+        assert_eq!(vec![1, 2, 3], mem.tarray::<u32>(0x1000).unwrap());
+        assert!(mem.tarray::<u32>(0x1010).is_err());
+        assert!(mem.tarray::<u32>(0x1020).is_err());
+        // empty arrays have no allocation
+        assert!(mem.tarray::<u32>(0x1030).unwrap().is_empty());
+        assert!(mem.tarray::<u32>(0x2000).is_err());
+    }
+
+    #[test]
+    fn test_fstring() {
+        let section = Builder::default()
+            .tarray(0x1000, 0x1100, 6, 8)
+            .tarray(0x1010, 0x1100, 3, 8)
+            .put(0x1100, utf16("hello\0"))
+            .tarray(0x1020, 0x1200, 2, 2)
+            .put(0x1200, [0x00, 0xd8, 0x41, 0x00])
+            .build();
+        let mem = section.unreal(VERSION);
+        assert_eq!("hello", mem.fstring(0x1000).unwrap());
+        // without a terminator the whole array is the string
+        assert_eq!("hel", mem.fstring(0x1010).unwrap());
+        // unpaired surrogate
+        assert!(mem.fstring(0x1020).is_err());
+
+        assert_eq!("hel", mem.tchar(0x1100, 3).unwrap());
+        assert_eq!("hello", mem.tchar(0x1100, 10).unwrap());
+        assert_eq!("", mem.tchar(0x1100, 0).unwrap());
+    }
+
+    #[test]
+    fn test_tset() {
+        let mut builder = Builder::default();
+        // u32 elements are 12 bytes apart, element 1 is a hole on the free list
+        builder.tset(0x1000, 0x1100, 4, &[0b1101, 0, 0, 0], 0);
+        for (i, value) in [10u32, 0xdead, 12, 13].into_iter().enumerate() {
+            builder.put(0x1100 + i * 12, value.to_le_bytes());
+        }
+        // more than 128 elements need the secondary bit array
+        builder.tset(0x1040, 0x1400, 200, &[1, 2, 0, 0, 0, 0, 0x80], 0x1200);
+        for i in 0..200 {
+            builder.put(0x1400 + i * 12, (i as u32 + 100).to_le_bytes());
+        }
+        // too large for the inline bits without a secondary array
+        builder.tset(0x1080, 0x1400, 200, &[], 0);
+        let section = builder.build();
+        let mem = section.unreal(VERSION);
+
+        assert_eq!(vec![10, 12, 13], mem.tset::<u32>(0x1000).unwrap());
+        assert_eq!(vec![100, 133, 299], mem.tset::<u32>(0x1040).unwrap());
+        assert!(mem.tset::<u32>(0x1080).is_err());
+    }
+
+    #[test]
+    fn test_tmap() {
+        let mut builder = Builder::default();
+        builder.tset(0x1000, 0x1100, 3, &[0b101, 0, 0, 0], 0);
+        // (u32, u64) pairs are 16 bytes padded to 24 with the hash fields
+        for i in 0..3 {
+            builder
+                .put(0x1100 + i * 24, (i as u32 + 1).to_le_bytes())
+                .put(0x1100 + i * 24 + 8, (i as u64 * 10 + 10).to_le_bytes());
+        }
+        let section = builder.build();
+        let mem = section.unreal(VERSION);
+        assert_eq!(
+            vec![(1, 10), (3, 30)],
+            mem.tmap::<u32, u64>(0x1000).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_fname() {
+        // header is bIsWide in bit 0 and Len in the top 10 bits
+        let ansi = |s: &str| [((s.len() as u16) << 6).to_le_bytes().to_vec(), s.into()].concat();
+        let wide = |s: &str| {
+            let header = ((s.encode_utf16().count() as u16) << 6) | 1;
+            [header.to_le_bytes().to_vec(), utf16(s)].concat()
+        };
+        let section = Builder::default()
+            // FNamePool with a Windows sized lock
+            .put(0x1010, 0x1800usize.to_le_bytes())
+            // FNamePool with a Linux sized lock
+            .put(0x1140, 0x1800usize.to_le_bytes())
+            // FNamePool whose blocks don't start with "None"
+            .put(0x1210, 0x1808usize.to_le_bytes())
+            .put(0x1800, ansi("None"))
+            .put(0x1806, ansi("Actor"))
+            .put(0x180e, wide("Größe"))
+            .put(0x1900, [0u32, 0].map(u32::to_le_bytes).concat())
+            .put(0x1908, [3u32, 0].map(u32::to_le_bytes).concat())
+            .put(0x1910, [3u32, 2].map(u32::to_le_bytes).concat())
+            .put(0x1918, [7u32, 0].map(u32::to_le_bytes).concat())
+            .build();
+        let mem = section.unreal(VERSION);
+
+        for pool in [FNamePool(0x1000), FNamePool(0x1100)] {
+            assert_eq!("None", mem.fname(&pool, 0x1900).unwrap());
+            assert_eq!("Actor", mem.fname(&pool, 0x1908).unwrap());
+            assert_eq!("Actor_1", mem.fname(&pool, 0x1910).unwrap());
+            assert_eq!("Größe", mem.fname(&pool, 0x1918).unwrap());
+        }
+        assert!(mem.fname(&FNamePool(0x1200), 0x1900).is_err());
+
+        // fails up front rather than probing for blocks
+        let old = EngineVersion {
+            major: 4,
+            minor: 22,
+        };
+        let err = section.unreal(old).fname(&FNamePool(0x1000), 0x1900);
+        assert!(err.unwrap_err().to_string().contains("4.23+"));
+        assert_eq!(
+            "Actor",
+            section
+                .unreal(NAME_POOL_VERSION)
+                .fname(&FNamePool(0x1000), 0x1908)
+                .unwrap()
+        );
+    }
+}
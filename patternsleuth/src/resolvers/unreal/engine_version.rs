@@ -3,14 +3,14 @@ use std::fmt::{Debug, Display};
 use futures::future::join_all;
 
 use itertools::Itertools;
-use patternsleuth_scanner::Pattern;
+use patternsleuth_scanner::{Constraint, Pattern};
 
 use crate::{
     resolvers::{bail_out, impl_resolver, try_ensure_one},
     MemoryTrait,
 };
 
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[cfg_attr(
     feature = "serde-resolvers",
     derive(serde::Serialize, serde::Deserialize)
@@ -30,59 +30,89 @@ impl Debug for EngineVersion {
     }
 }
 
-impl_resolver!(all, EngineVersion, |ctx| async {
-    let patterns = [
-        "C7 47 20 | 04 00 ?? 00 66 89 6F 24",
-        "C7 4? 20 | 04 00 ?? ?? 66 4? 89 ?? 24",
-        "C7 ?? 24 20 | 04 00 ?? ?? 48 8D 45 F0",
-        "C7 05 ?? ?? ?? ?? | 04 00 ?? 00 66 89 ?? ?? ?? ?? ?? C7 05",
-        "C7 05 ?? ?? ?? ?? | 04 00 ?? 00 66 89 ?? ?? ?? ?? ?? 89",
-        "41 C7 ?? | 04 00 ?? 00 ?? ?? 00 00 00 66 41 89",
-        "41 C7 ?? | 04 00 18 00 66 41 89 ?? 04",
-        "41 C7 04 24 | 04 00 ?? 00 66 ?? 89 ?? 24",
-        "41 C7 04 24 | 04 00 ?? 00 B9 ?? 00 00 00",
-        "41 C7 44 24 20 | 04 00 ?? 00 66 ?? 89 ?? 24",
-        "C7 05 ?? ?? ?? ?? | 04 00 ?? 00 89 3D ?? ?? ?? ?? 85 FF",
-        "C7 05 ?? ?? ?? ?? | 04 00 ?? 00 89 05 ?? ?? ?? ?? E8",
-        "C7 05 ?? ?? ?? ?? | 04 00 ?? 00 66 89 ?? ?? ?? ?? ??",
-        "C7 46 20 | 04 00 ?? 00 66 44 89 76 24 44 89 76 28 48 39 C7",
-        "C7 03 | 04 00 ?? 00 66 44 89 63 04 C7 43 08 C1 5C 08 80 E8",
-        "C7 47 20 | 04 00 ?? 00 66 89 6F 24 C7 47 28 ?? ?? ?? ?? 49",
-        "C7 03 | 04 00 ?? 00 66 89 6B 04 89 7B 08 48 83 C3 10",
-        "41 C7 06 | 05 00 ?? ?? 48 8B 5C 24 ?? 49 8D 76 ?? 33 ED 41 89 46",
-        "C7 06 | 05 00 ?? ?? 48 8B 5C 24 20 4C 8D 76 10 33 ED",
-        "11 76 30 c7 46 20 | 04 00 ?? 00",
+/// Patterns matching the initialization of the engine version, constrained to versions which
+/// exist. Each captures the major and minor version at its custom offset.
+fn version_patterns() -> Vec<Pattern> {
+    let ue4 = [
+        "C7 47 20 | [ 04 00 ] [ ?? 00 ] 66 89 6F 24",
+        "C7 4? 20 | [ 04 00 ] [ ?? ?? ] 66 4? 89 ?? 24",
+        "C7 ?? 24 20 | [ 04 00 ] [ ?? ?? ] 48 8D 45 F0",
+        "C7 05 ?? ?? ?? ?? | [ 04 00 ] [ ?? 00 ] 66 89 ?? ?? ?? ?? ?? C7 05",
+        "C7 05 ?? ?? ?? ?? | [ 04 00 ] [ ?? 00 ] 66 89 ?? ?? ?? ?? ?? 89",
+        "41 C7 ?? | [ 04 00 ] [ ?? 00 ] ?? ?? 00 00 00 66 41 89",
+        "41 C7 ?? | [ 04 00 ] [ 18 00 ] 66 41 89 ?? 04",
+        "41 C7 04 24 | [ 04 00 ] [ ?? 00 ] 66 ?? 89 ?? 24",
+        "41 C7 04 24 | [ 04 00 ] [ ?? 00 ] B9 ?? 00 00 00",
+        "41 C7 44 24 20 | [ 04 00 ] [ ?? 00 ] 66 ?? 89 ?? 24",
+        "C7 05 ?? ?? ?? ?? | [ 04 00 ] [ ?? 00 ] 89 3D ?? ?? ?? ?? 85 FF",
+        "C7 05 ?? ?? ?? ?? | [ 04 00 ] [ ?? 00 ] 89 05 ?? ?? ?? ?? E8",
+        "C7 05 ?? ?? ?? ?? | [ 04 00 ] [ ?? 00 ] 66 89 ?? ?? ?? ?? ??",
+        "C7 46 20 | [ 04 00 ] [ ?? 00 ] 66 44 89 76 24 44 89 76 28 48 39 C7",
+        "C7 03 | [ 04 00 ] [ ?? 00 ] 66 44 89 63 04 C7 43 08 C1 5C 08 80 E8",
+        "C7 47 20 | [ 04 00 ] [ ?? 00 ] 66 89 6F 24 C7 47 28 ?? ?? ?? ?? 49",
+        "C7 03 | [ 04 00 ] [ ?? 00 ] 66 89 6B 04 89 7B 08 48 83 C3 10",
+        "11 76 30 c7 46 20 | [ 04 00 ] [ ?? 00 ]",
+    ];
+    let ue5 = [
+        "41 C7 06 | [ 05 00 ] [ ?? ?? ] 48 8B 5C 24 ?? 49 8D 76 ?? 33 ED 41 89 46",
+        "C7 06 | [ 05 00 ] [ ?? ?? ] 48 8B 5C 24 20 4C 8D 76 10 33 ED",
         // maybe better go from BuildSettings::GetBranchName -> FGlobalEngineVersions::FGlobalEngineVersions
-        "0F 57 C0 0F 11 43 10 C7 03 | 05 ?? ?? ?? 66 C7 43 04 ?? ??", // <- last one is patch
-        "48 89 2? 48 89 6? 08 C7 0? | 05 00 ?? ?? 66",
-        "49 89 2? 49 89 6? 08 C7 0? | 05 00 ?? ?? 66",
-        "C7 46 20 | 05 00 ?? ?? 66 89 ?? 24",
-        "C7 43 20 | 05 00 ?? ?? 48 3B F0",
-        "C7 46 20 | 05 00 ?? ?? 48 8D 44 24 20",
-        "C7 4? 20 | 05 00 ?? ?? 66 44 89 ?? 24",
-        "C7 ?? 24 20 | 05 00 ?? ?? 48 8D 45 F0",
+        "0F 57 C0 0F 11 43 10 C7 03 | [ 05 ?? ] [ ?? ?? ] 66 C7 43 04 ?? ??", // <- last one is patch
+        "48 89 2? 48 89 6? 08 C7 0? | [ 05 00 ] [ ?? ?? ] 66",
+        "49 89 2? 49 89 6? 08 C7 0? | [ 05 00 ] [ ?? ?? ] 66",
+        "C7 46 20 | [ 05 00 ] [ ?? ?? ] 66 89 ?? 24",
+        "C7 43 20 | [ 05 00 ] [ ?? ?? ] 48 3B F0",
+        "C7 46 20 | [ 05 00 ] [ ?? ?? ] 48 8D 44 24 20",
+        "C7 4? 20 | [ 05 00 ] [ ?? ?? ] 66 44 89 ?? 24",
+        "C7 ?? 24 20 | [ 05 00 ] [ ?? ?? ] 48 8D 45 F0",
     ];
 
-    let res = join_all(patterns.iter().map(|p| ctx.scan(Pattern::new(p).unwrap()))).await;
-
-    try_ensure_one(
-        res.iter()
-            .flatten()
-            .map(|a| {
-                Ok(EngineVersion {
-                    major: ctx.image().memory.u16_le(*a)?,
-                    minor: ctx.image().memory.u16_le(a + 2)?,
-                })
-            })
-            .filter_ok(|ver| match ver.major {
-                // TODO 4.0 can false positive so ignore it. need to harden if this is to work on 4.0 games
-                4 if (1..=27).contains(&ver.minor) => true,
-                5 if (0..).contains(&ver.minor) => true,
-                _ => false,
-            }),
-    )
+    let version = |p: &str, major: u64| {
+        Pattern::new(p)
+            .unwrap()
+            .constrain(0, Constraint::Value(major..=major))
+            .unwrap()
+    };
+    ue4.iter()
+        .map(|p| {
+            // TODO 4.0 can false positive so ignore it. need to harden if this is to work on 4.0 games
+            version(p, 4)
+                .constrain(1, Constraint::Value(1..=27))
+                .unwrap()
+        })
+        .chain(ue5.iter().map(|p| version(p, 5)))
+        .collect()
+}
+
+impl_resolver!(all, EngineVersion, |ctx| async {
+    let res = join_all(version_patterns().into_iter().map(|p| ctx.scan(p))).await;
+
+    try_ensure_one(res.iter().flatten().map(|a| {
+        Ok(EngineVersion {
+            major: ctx.image().memory.u16_le(*a)?,
+            minor: ctx.image().memory.u16_le(a + 2)?,
+        })
+    }))
 });
 
+/// Patterns matching the UTF-16 build date for each month. `__DATE__` is "Mmm dd yyyy" with the
+/// day padded by a space.
+fn build_date_patterns() -> Vec<Pattern> {
+    [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .into_iter()
+    .map(|month| {
+        let month = month
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .map(|b| format!("{b:02x}"))
+            .join(" ");
+        Pattern::new(format!("{month} 20 00 ?? 00 ?? 00 20 00 32 00 30 00")).unwrap()
+    })
+    .collect()
+}
+
 /// currently seems to be 4.22+
 #[derive(Debug, PartialEq)]
 #[cfg_attr(
@@ -99,10 +129,14 @@ impl_resolver!(collect, EngineVersionStrings);
 impl_resolver!(ElfImage, EngineVersionStrings, |ctx| async {
     use crate::resolvers::{ensure_one, unreal::util};
 
-    let pattern_name = util::utf16_pattern("++UE5+Release-");
-    let name_scan = ctx.scan(pattern_name).await;
+    let pattern_name = util::utf16_pattern("++UE5+Release-").limit(2);
+    let name_scan = ctx.scan_limited(pattern_name).await;
+    if name_scan.limit_hit {
+        bail_out!("ambiguous branch name");
+    }
 
     let mut name_scan: Vec<_> = name_scan
+        .matches
         .iter()
         .flat_map(|&addr| ctx.image().memory.read_wstring(addr))
         .collect();
@@ -114,29 +148,32 @@ impl_resolver!(ElfImage, EngineVersionStrings, |ctx| async {
     name_scan.sort();
     let (branch_name, build_version) = (name_scan[0].clone(), name_scan[1].clone());
 
-    let build_date = join_all(
-        [
-            "Jan ", "Feb ", "Mar ", "Apr ", "May ", "Jun ", "Jul ", "Aug ", "Sep ", "Oct ", "Nov ",
-            "Dec ",
-        ]
-        .map(|p| ctx.scan(util::utf16_pattern(p))),
+    let date_scans = join_all(
+        build_date_patterns()
+            .into_iter()
+            .map(|p| ctx.scan_limited(p.limit(2))),
     )
-    .await
-    .into_iter()
-    .flatten()
-    .flat_map(|addr| ctx.image().memory.read_wstring(addr))
-    .filter(|p| {
-        let sp = p.split_whitespace().collect_vec();
-        if sp.len() == 3 {
-            let (dd, yyyy) = (
-                sp[1].parse::<u32>().unwrap_or(0),
-                sp[2].parse::<u32>().unwrap_or(0),
-            );
-            !(dd >= 32 || yyyy >= 2100 || yyyy <= 2000)
-        } else {
-            false
-        }
-    });
+    .await;
+    if date_scans.iter().any(|s| s.limit_hit) {
+        bail_out!("ambiguous build date");
+    }
+
+    let build_date = date_scans
+        .into_iter()
+        .flat_map(|s| s.matches)
+        .flat_map(|addr| ctx.image().memory.read_wstring(addr))
+        .filter(|p| {
+            let sp = p.split_whitespace().collect_vec();
+            if sp.len() == 3 {
+                let (dd, yyyy) = (
+                    sp[1].parse::<u32>().unwrap_or(0),
+                    sp[2].parse::<u32>().unwrap_or(0),
+                );
+                !(dd >= 32 || yyyy >= 2100 || yyyy <= 2000)
+            } else {
+                false
+            }
+        });
 
     let build_date = ensure_one(build_date)?;
 
@@ -148,7 +185,7 @@ impl_resolver!(ElfImage, EngineVersionStrings, |ctx| async {
 });
 
 impl_resolver!(PEImage, EngineVersionStrings, |ctx| async {
-    use crate::{Addressable, Matchable, MemoryTrait};
+    use crate::{Addressable, MemoryTrait};
     use std::collections::HashSet;
 
     let patterns = [
@@ -158,7 +195,7 @@ impl_resolver!(PEImage, EngineVersionStrings, |ctx| async {
     let res = join_all(
         patterns
             .iter()
-            .map(|p| ctx.scan_tagged((), Pattern::new(p).unwrap())),
+            .map(|p| ctx.scan_captures(Pattern::new(p).unwrap())),
     )
     .await;
 
@@ -171,24 +208,83 @@ impl_resolver!(PEImage, EngineVersionStrings, |ctx| async {
     .map(|month| month.encode_utf16().flat_map(u16::to_le_bytes).collect())
     .collect::<HashSet<Vec<u8>>>();
 
-    for (_, pattern, addresses) in res {
-        for a in addresses {
-            let caps = mem.captures(&pattern, a)?.unwrap();
-            let date = caps[1].rip();
-            if mem
-                .range(date..date + 6)
-                .ok()
-                .filter(|r| months.contains(&r[..]))
-                .is_some()
-            {
-                return Ok(EngineVersionStrings {
-                    branch_name: mem.read_wstring(caps[0].rip())?,
-                    build_date: mem.read_wstring(caps[1].rip())?,
-                    build_version: mem.read_wstring(caps[2].rip())?,
-                });
-            }
+    for caps in res.into_iter().flatten().map(|m| m.captures) {
+        let date = caps[1].rip();
+        if mem
+            .range(date..date + 6)
+            .ok()
+            .filter(|r| months.contains(&r[..]))
+            .is_some()
+        {
+            return Ok(EngineVersionStrings {
+                branch_name: mem.read_wstring(caps[0].rip())?,
+                build_date: mem.read_wstring(caps[1].rip())?,
+                build_version: mem.read_wstring(caps[2].rip())?,
+            });
         }
     }
 
     bail_out!("not found");
 });
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_version_constraints() {
+        let patterns = version_patterns();
+        let set = patternsleuth_scanner::PatternSet::new(&patterns.iter().collect::<Vec<_>>());
+
+        let mut data = vec![];
+        let mut expected = vec![];
+        for (prefix, version, found) in [
+            ([0xc7, 0x47, 0x20], [4, 0, 26, 0], true),
+            // 4.0 and versions past 4.27 are rejected
+            ([0xc7, 0x47, 0x20], [4, 0, 0, 0], false),
+            ([0xc7, 0x47, 0x20], [4, 0, 28, 0], false),
+            ([0xc7, 0x46, 0x20], [5, 0, 3, 0], true),
+        ] {
+            data.extend([0xcc; 4]);
+            data.extend(prefix);
+            if found {
+                expected.push(data.len());
+            }
+            data.extend(version);
+            data.extend([0x66, 0x89, 0x6f, 0x24]);
+        }
+        // major version 0x105 only matches the unconstrained byte pattern
+        data.extend([0x0f, 0x57, 0xc0, 0x0f, 0x11, 0x43, 0x10, 0xc7, 0x03]);
+        data.extend([0x05, 0x01, 0x02, 0x00, 0x66, 0xc7, 0x43, 0x04, 0x00, 0x00]);
+
+        let mut found = set.scan(0, &data).into_iter().flatten().collect::<Vec<_>>();
+        found.sort();
+        found.dedup();
+        assert_eq!(expected, found);
+    }
+
+    #[test]
+    fn test_build_date_patterns() {
+        let patterns = build_date_patterns();
+        let set = patternsleuth_scanner::PatternSet::new(&patterns.iter().collect::<Vec<_>>());
+
+        let mut data = vec![];
+        let mut expected = vec![];
+        for (string, found) in [
+            ("Jan  1 2024", true),
+            ("Dec 31 2023", true),
+            ("May not be empty", false),
+            ("Mar 10", false),
+        ] {
+            data.extend([0; 2]);
+            if found {
+                expected.push(data.len());
+            }
+            data.extend(string.encode_utf16().flat_map(u16::to_le_bytes));
+        }
+
+        let mut found = set.scan(0, &data).into_iter().flatten().collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(expected, found);
+    }
+}
@@ -6,6 +6,7 @@ use itertools::Itertools;
 use patternsleuth_scanner::Pattern;
 
 use crate::{
+    cfg::ControlFlowGraph,
     disassemble::{disassemble, Control},
     resolvers::{impl_resolver_singleton, try_ensure_one, unreal::util, Result},
     MemoryTrait,
@@ -67,7 +68,6 @@ impl_resolver_singleton!(collect, GMallocString);
 
 impl_resolver_singleton!(PEImage, GMallocString, |ctx| async {
     use crate::Image;
-    use iced_x86::FlowControl;
     use std::collections::HashSet;
 
     let strings = ctx.scan(util::utf16_pattern("DeleteFile %s\0")).await;
@@ -87,17 +87,9 @@ impl_resolver_singleton!(PEImage, GMallocString, |ctx| async {
 
         let mut mov_rcx = None;
         let mut possible_gmalloc = vec![];
-        let mut calls = vec![];
-
-        disassemble(img, f, |inst| {
-            let cur = inst.ip() as usize;
-            if !(f..f + 1000).contains(&cur)
-                && Some(f) != img.get_root_function(cur)?.map(|f| f.range.start)
-            {
-                //println!("bailing at {:x}", inst.ip());
-                return Ok(Control::Break);
-            }
 
+        let cfg = ControlFlowGraph::build_with_slack(img, f, util::FUNCTION_SLACK)?;
+        for inst in cfg.blocks.values().flat_map(|b| &b.instructions) {
             if inst.code() == Code::Cmp_rm64_imm8
                 && inst.memory_base() == Register::RIP
                 && inst.op0_kind() == OpKind::Memory
@@ -120,34 +112,15 @@ impl_resolver_singleton!(PEImage, GMallocString, |ctx| async {
                 && inst.memory_base() == Register::RIP
                 && inst.op0_register() == Register::RCX
             {
-                /*
-                println!(
-                    "{depth} {:x} {:x} {:x?}",
-                    inst.ip(),
-                    inst.ip_rel_memory_address(),
-                    inst
-                );
-                */
                 mov_rcx = Some(inst.ip_rel_memory_address() as usize);
             } else {
                 mov_rcx = None;
             }
-
-            match inst.flow_control() {
-                FlowControl::Call
-                | FlowControl::ConditionalBranch
-                | FlowControl::UnconditionalBranch => {
-                    let call = inst.near_branch_target() as usize;
-                    //println!("{:x} {:x}", inst.ip(), call);
-                    if Some(f) != img.get_root_function(call)?.map(|f| f.range.start) {
-                        calls.push(call);
-                    }
-                }
-                _ => {}
-            }
-
-            Ok(Control::Continue)
-        })?;
+        }
+        let calls = util::calls_in(img, &cfg)?
+            .into_iter()
+            .map(|c| c.callee)
+            .collect::<Vec<_>>();
 
         if let [gmalloc] = possible_gmalloc.as_slice() {
             Ok(Some(*gmalloc))
@@ -5,10 +5,9 @@ use iced_x86::{Decoder, DecoderOptions, Instruction};
 use patternsleuth_scanner::Pattern;
 
 use crate::{
-    resolvers::{
-        bail_out, ensure_one, impl_resolver, impl_resolver_singleton, try_ensure_one, Result,
-    },
-    Addressable, Matchable, MemoryTrait,
+    asm,
+    resolvers::{bail_out, ensure_one, impl_resolver, impl_resolver_singleton},
+    Addressable, MemoryTrait,
 };
 
 /// public: void __cdecl UObject::SkipFunction(struct FFrame &, void *const, class UFunction *)
@@ -95,13 +94,19 @@ impl_resolver_singleton!(ElfImage, GNatives, |ctx| async {
 )]
 pub struct FFrameStep(pub usize);
 impl_resolver_singleton!(all, FFrameStep, |ctx| async {
-    let patterns = [
-        "48 8B 41 20 4C 8B D2 48 8B D1 44 0F B6 08 48 FF C0 48 89 41 20 41 8B C1 4C 8D 0D ?? ?? ?? ?? 49 8B CA 49 FF 24 C1",
+    let patterns = asm::compile(
+        "mov rax, qword ptr [rcx + 0x20]; mov r10, rdx; mov rdx, rcx; movzx r9d, byte ptr [rax]; \
+         inc rax; mov qword ptr [rcx + 0x20], rax; mov eax, r9d; lea r9, [rip + ?]; mov rcx, r10; \
+         jmp qword ptr [r9 + rax*8]",
+    )
+    .unwrap()
+    .into_iter()
+    .chain([
         // linux
-        "01001??? 89 f8 01001??? 8b 4f 20 01001??? 8d 79 01 01001??? 89 78 20 0f b6 09 01001??? 8b 0c ?????101 ?? ?? ?? ?? 01001??? 89 f7 01001??? 89 c6 ff e1",
-    ];
+        Pattern::new("01001??? 89 f8 01001??? 8b 4f 20 01001??? 8d 79 01 01001??? 89 78 20 0f b6 09 01001??? 8b 0c ?????101 ?? ?? ?? ?? 01001??? 89 f7 01001??? 89 c6 ff e1").unwrap(),
+    ]);
 
-    let res = join_all(patterns.iter().map(|p| ctx.scan(Pattern::new(p).unwrap()))).await;
+    let res = join_all(patterns.map(|p| ctx.scan(p))).await;
 
     Ok(FFrameStep(ensure_one(res.into_iter().flatten())?))
 });
@@ -151,20 +156,14 @@ impl_resolver!(all, FFrameStepViaExec, |ctx| async {
     let res = join_all(
         patterns
             .iter()
-            .map(|p| ctx.scan_tagged((), Pattern::new(p).unwrap())),
+            .map(|p| ctx.scan_captures(Pattern::new(p).unwrap())),
     )
     .await;
 
-    ensure_one(
-        res.into_iter()
-            .flat_map(|(_, pattern, addresses)| -> Result<_> {
-                try_ensure_one(addresses.iter().map(|a| -> Result<_> {
-                    let caps = ctx.image().memory.captures(&pattern, *a)?.unwrap();
-                    Ok(FFrameStepViaExec {
-                        step: caps[0].rip(),
-                        step_explicit_property: caps[1].rip(),
-                    })
-                }))
-            }),
-    )
+    ensure_one(res.into_iter().flat_map(|matches| {
+        ensure_one(matches.into_iter().map(|m| FFrameStepViaExec {
+            step: m.captures[0].rip(),
+            step_explicit_property: m.captures[1].rip(),
+        }))
+    }))
 });
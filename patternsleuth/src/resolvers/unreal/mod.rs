@@ -1,5 +1,6 @@
 pub mod aes;
 pub mod blueprint_library;
+pub mod containers;
 pub mod engine_version;
 pub mod fname;
 pub mod ftext;
@@ -20,15 +21,13 @@ use std::{
 };
 
 use futures::future::join_all;
-use iced_x86::FlowControl;
-use itertools::Itertools;
-use patternsleuth_scanner::Pattern;
+use iced_x86::{Decoder, DecoderOptions, FlowControl, Instruction, OpKind};
+use patternsleuth_scanner::{Pattern, XrefKind};
 
 use crate::{
-    disassemble::{disassemble, Control},
-    resolvers::{
-        bail_out, ensure_one, impl_resolver, impl_resolver_singleton, try_ensure_one, Result,
-    },
+    cfg::{ControlFlowGraph, EdgeKind},
+    read::{MemoryRead, Ptr},
+    resolvers::{bail_out, ensure_one, impl_resolver, impl_resolver_singleton, Result},
     Addressable, Image, Matchable, MemoryTrait,
 };
 
@@ -54,74 +53,113 @@ pub mod util {
     pub fn utf16_pattern(string: &str) -> Pattern {
         Pattern::from_bytes(utf16(string)).unwrap()
     }
+    /// Find instructions referencing any of `addresses` either directly (rip-relative or 32-bit
+    /// immediate) or through a pointer, returning the address of each instruction
     pub async fn scan_xrefs(
         ctx: &AsyncContext<'_>,
         addresses: impl IntoIterator<Item = &usize> + Copy,
     ) -> Vec<usize> {
-        let refs_indirect = join_all(
-            addresses
-                .into_iter()
-                .map(|s| ctx.scan(Pattern::from_bytes(usize::to_le_bytes(*s).into()).unwrap())),
-        )
-        .await;
+        scan_references(ctx, addresses, true, is_xref).await
+    }
 
-        let refs = join_all(
-            addresses
-                .into_iter()
-                .copied()
-                .chain(refs_indirect.into_iter().flatten())
-                .flat_map(|s| {
-                    let mut scans =
-                        vec![format!("48 8d ?? X0x{s:X}"), format!("4c 8d ?? X0x{s:X}")];
-                    if TryInto::<u32>::try_into(s).is_ok() {
-                        // mov reg, imm32 if address is 32 bit
-                        scans.extend([
-                            format!("b8 0x{s:X}"),
-                            format!("b9 0x{s:X}"),
-                            format!("ba 0x{s:X}"),
-                            format!("bb 0x{s:X}"),
-                            format!("bc 0x{s:X}"),
-                            format!("bd 0x{s:X}"),
-                            format!("be 0x{s:X}"),
-                            format!("bf 0x{s:X}"),
-                        ]);
-                    }
-                    scans
-                })
-                .map(|p| ctx.scan(Pattern::new(p).unwrap())),
-        )
-        .await;
+    /// Find near calls and jumps to any of `addresses` either directly or through a pointer,
+    /// returning the address of each instruction
+    pub async fn scan_xcalls(
+        ctx: &AsyncContext<'_>,
+        addresses: impl IntoIterator<Item = &usize> + Copy,
+    ) -> Vec<usize> {
+        scan_references(ctx, addresses, false, is_xcall).await
+    }
 
-        refs.into_iter().flatten().collect()
+    fn is_xref(inst: &Instruction, target: usize) -> bool {
+        (inst.is_ip_rel_memory_operand() && inst.ip_rel_memory_address() as usize == target)
+            || (0..inst.op_count()).any(|i| {
+                matches!(
+                    inst.op_kind(i),
+                    OpKind::Immediate32 | OpKind::Immediate32to64 | OpKind::Immediate64
+                ) && inst.immediate(i) as usize == target
+            })
     }
 
-    pub async fn scan_xcalls(
+    fn is_xcall(inst: &Instruction, target: usize) -> bool {
+        matches!(
+            inst.flow_control(),
+            FlowControl::Call
+                | FlowControl::UnconditionalBranch
+                | FlowControl::IndirectCall
+                | FlowControl::IndirectBranch
+        ) && (inst.near_branch_target() as usize == target
+            // call [rip + pointer]
+            || (inst.is_ip_rel_memory_operand() && inst.ip_rel_memory_address() as usize == target))
+    }
+
+    async fn scan_references(
         ctx: &AsyncContext<'_>,
         addresses: impl IntoIterator<Item = &usize> + Copy,
+        immediate: bool,
+        is_reference: impl Fn(&Instruction, usize) -> bool,
     ) -> Vec<usize> {
         let refs_indirect = join_all(
             addresses
                 .into_iter()
-                .map(|s| ctx.scan(Pattern::from_bytes(usize::to_le_bytes(*s).into()).unwrap())),
+                .map(|s| ctx.scan_xref_kind(XrefKind::Abs64, *s)),
         )
         .await;
 
-        let refs = join_all(
-            addresses
+        let targets = addresses
+            .into_iter()
+            .copied()
+            .chain(refs_indirect.into_iter().flatten())
+            .collect::<Vec<_>>();
+
+        let refs = join_all(targets.iter().flat_map(|&s| {
+            let mut kinds = vec![XrefKind::Rel32];
+            if immediate && u32::try_from(s).is_ok() {
+                // mov reg, imm32 if address is 32 bit
+                kinds.push(XrefKind::Abs32);
+            }
+            kinds
                 .into_iter()
-                .copied()
-                .chain(refs_indirect.into_iter().flatten())
-                .flat_map(|s| {
-                    [
-                        //ctx.scan(Pattern::new(format!("10111??? 0x{s:X}")).unwrap()), // mov reg, imm32
-                        ctx.scan(Pattern::new(format!("e8 X0x{s:X}")).unwrap()),
-                        ctx.scan(Pattern::new(format!("e9 X0x{s:X}")).unwrap()),
-                    ]
-                }),
-        )
+                .map(move |kind| async move { (s, ctx.scan_xref_kind(kind, s).await) })
+        }))
         .await;
 
-        refs.into_iter().flatten().collect()
+        refs.into_iter()
+            .flat_map(|(target, refs)| refs.into_iter().map(move |r| (target, r)))
+            .filter_map(|(target, r)| {
+                referencing_instruction(ctx.image(), r, target, &is_reference)
+            })
+            .collect()
+    }
+
+    /// Find the instruction whose 4 byte displacement or immediate at `address` references
+    /// `target` by decoding backwards from `address`, returning the address of the instruction.
+    /// Hits which don't decode to a reference are dropped.
+    fn referencing_instruction(
+        img: &Image<'_>,
+        address: usize,
+        target: usize,
+        is_reference: impl Fn(&Instruction, usize) -> bool,
+    ) -> Option<usize> {
+        let section = img.memory.get_section_containing(address).ok()?;
+        let start = address.saturating_sub(15).max(section.address());
+        let end = (address + 8).min(section.address() + section.len());
+        let data = &section.data()[start - section.address()..end - section.address()];
+
+        // nearest candidate first, the instruction must end with the 4 bytes at `address`
+        // optionally followed by an immediate of up to 4 bytes
+        for ip in (start..address).rev() {
+            let mut decoder =
+                Decoder::with_ip(64, &data[ip - start..], ip as u64, DecoderOptions::NONE);
+            let inst = decoder.decode();
+            if !inst.is_invalid()
+                && (address + 4..=address + 8).contains(&(inst.next_ip() as usize))
+                && is_reference(&inst, target)
+            {
+                return Some(ip);
+            }
+        }
+        None
     }
 
     pub fn root_functions<'a, I>(ctx: &AsyncContext<'_>, addresses: I) -> Result<Vec<usize>>
@@ -137,33 +175,37 @@ pub mod util {
             .collect())
     }
 
+    /// Bytes past the start of a function which [`find_path`] and `GMallocString` search
+    /// regardless of the function table
+    pub const FUNCTION_SLACK: usize = 1000;
+
     pub fn find_calls(img: &Image<'_>, f: usize) -> Result<Vec<Call>> {
-        let mut calls = vec![];
+        calls_in(img, &*img.control_flow_graph(f)?)
+    }
 
-        disassemble(img, f, |inst| {
-            let cur = inst.ip() as usize;
-            if Some(f) != img.get_root_function(cur)?.map(|f| f.range.start) {
-                return Ok(Control::Break);
-            }
+    /// Calls and jumps in `cfg` to other root functions than its start
+    pub fn calls_in(img: &Image<'_>, cfg: &ControlFlowGraph) -> Result<Vec<Call>> {
+        let f = cfg.start;
+        let root =
+            |address| -> Result<_> { Ok(img.get_root_function(address)?.map(|f| f.range.start)) };
 
-            match inst.flow_control() {
-                FlowControl::Call
-                | FlowControl::ConditionalBranch
-                | FlowControl::UnconditionalBranch => {
-                    let call = inst.near_branch_target() as usize;
-                    if Some(f) != img.get_root_function(call)?.map(|f| f.range.start) {
-                        calls.push(Call {
-                            index: 0,
-                            ip: inst.ip() as usize,
-                            callee: call,
-                        });
-                    }
-                }
-                _ => {}
+        let mut calls = vec![];
+        for edge in cfg.edges.iter().chain(&cfg.tail_calls) {
+            let leaves = match edge.kind {
+                EdgeKind::Call => true,
+                // jumps only leave the start's root function within the slack of
+                // `ControlFlowGraph::build_with_slack`
+                EdgeKind::Conditional | EdgeKind::Unconditional => root(edge.to)? != root(f)?,
+                EdgeKind::Fallthrough => false,
+            };
+            if leaves && Some(f) != root(edge.to)? {
+                calls.push(Call {
+                    index: 0,
+                    ip: edge.ip,
+                    callee: edge.to,
+                });
             }
-
-            Ok(Control::Continue)
-        })?;
+        }
 
         calls.sort_by_key(|c| c.ip);
         for (i, call) in calls.iter_mut().enumerate() {
@@ -183,41 +225,10 @@ pub mod util {
         searched.insert(f);
 
         let mut result = vec![];
-        let mut calls = vec![];
-
-        disassemble(img, f, |inst| {
-            let cur = inst.ip() as usize;
-            if !(f..f + 1000).contains(&cur)
-                && Some(f) != img.get_root_function(cur)?.map(|f| f.range.start)
-            {
-                println!("bailing at {:x}", inst.ip());
-                return Ok(Control::Break);
-            }
-
-            match inst.flow_control() {
-                FlowControl::Call
-                | FlowControl::ConditionalBranch
-                | FlowControl::UnconditionalBranch => {
-                    let call = inst.near_branch_target() as usize;
-                    println!("{:x} {:x}", inst.ip(), call);
-                    if Some(f) != img.get_root_function(call)?.map(|f| f.range.start) {
-                        calls.push(Call {
-                            index: 0, // unknown for now
-                            ip: cur,
-                            callee: call,
-                        });
-                    }
-                }
-                _ => {}
-            }
-
-            Ok(Control::Continue)
-        })?;
-
-        for (i, call) in calls.iter_mut().sorted_by_key(|c| c.ip).enumerate() {
-            call.index = i;
+        let cfg = ControlFlowGraph::build_with_slack(img, f, FUNCTION_SLACK)?;
+        for call in calls_in(img, &cfg)? {
             if !searched.contains(&call.callee) {
-                path.push(*call);
+                path.push(call);
                 if call.callee == needle {
                     println!("{path:x?}");
                     result.push(format!("{path:x?}"));
@@ -237,6 +248,86 @@ pub mod util {
         }
         Ok(result)
     }
+
+    #[cfg(all(test, feature = "image-pe"))]
+    mod test {
+        use super::*;
+        use crate::image::pe::PEImage;
+
+        #[test]
+        fn test_referencing_instruction() {
+            let mut text = vec![0; 0x300];
+            let mut put = |address: usize, bytes: &[u8]| {
+                text[address - 0x1000..address - 0x1000 + bytes.len()].copy_from_slice(bytes)
+            };
+            // call 0x1050
+            put(0x1000, &[0xe8, 0x4b, 0x00, 0x00, 0x00]);
+            // call [rip + 0x2000]
+            put(0x1005, &[0xff, 0x15, 0xf5, 0x0f, 0x00, 0x00]);
+            // mov dword [rip + 0x2000], 1
+            put(
+                0x1010,
+                &[0xc7, 0x05, 0xe6, 0x0f, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+            );
+            // not an instruction, inside and outside a function
+            put(0x1080, &[0xcc, 0xcc, 0x4b, 0x00, 0x00, 0x00]);
+            put(0x1200, &[0xcc, 0xcc, 0xcc, 0x4b, 0x00, 0x00, 0x00]);
+            // call 0x1050 outside a function
+            put(0x1210, &[0xe8, 0x3b, 0xfe, 0xff, 0xff]);
+
+            let image = PEImage::synthetic(
+                vec![(".text", object::SectionKind::Text, 0x1000, text)],
+                &[0x1000..0x1100, 0x1100..0x1180],
+            );
+            let find = |address, target, is_reference: fn(&Instruction, usize) -> bool| {
+                referencing_instruction(&image, address, target, is_reference)
+            };
+
+            assert_eq!(Some(0x1000), find(0x1001, 0x1050, is_xcall));
+            assert_eq!(Some(0x1005), find(0x1007, 0x2000, is_xcall));
+            assert_eq!(Some(0x1010), find(0x1012, 0x2000, is_xref));
+            assert_eq!(None, find(0x1012, 0x2000, is_xcall));
+            assert_eq!(None, find(0x1082, 0x1050, is_xcall));
+            assert_eq!(None, find(0x1203, 0x1050, is_xcall));
+            assert_eq!(Some(0x1210), find(0x1211, 0x1050, is_xcall));
+        }
+
+        #[test]
+        fn test_calls_in() {
+            let mut text = vec![0xcc; 0x200];
+            text[..0xd].copy_from_slice(&[
+                0xe8, 0xfb, 0x00, 0x00, 0x00, // call 0x1100
+                0xeb, 0x01, // jmp 0x1008
+                0xcc, //
+                0xe9, 0xf3, 0x00, 0x00, 0x00, // jmp 0x1100
+            ]);
+            // the function table splits the function at 0x1008
+            let image = PEImage::synthetic(
+                vec![(".text", object::SectionKind::Text, 0x1000, text)],
+                &[0x1000..0x1008, 0x1008..0x1100, 0x1100..0x1200],
+            );
+            let calls = |cfg: &ControlFlowGraph| {
+                calls_in(&image, cfg)
+                    .unwrap()
+                    .into_iter()
+                    .map(|c| (c.index, c.ip, c.callee))
+                    .collect::<Vec<_>>()
+            };
+
+            let cfg = image.control_flow_graph(0x1000).unwrap();
+            assert_eq!(vec![(0, 0x1000, 0x1100), (1, 0x1005, 0x1008)], calls(&cfg));
+            // the jump into the other root function is still a call but is also followed
+            let cfg = ControlFlowGraph::build_with_slack(&image, 0x1000, FUNCTION_SLACK).unwrap();
+            assert_eq!(
+                vec![
+                    (0, 0x1000, 0x1100),
+                    (1, 0x1005, 0x1008),
+                    (2, 0x1008, 0x1100)
+                ],
+                calls(&cfg)
+            );
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -259,7 +350,7 @@ impl_resolver!(all, KismetSystemLibrary, |ctx| async {
     let strings = ctx.scan(s).await;
 
     let refs = join_all(strings.iter().map(|s| {
-        ctx.scan(
+        ctx.scan_captures(
             Pattern::new(format!(
         // fragile (only 4.25-4.27 most likely)
         "4c 8d 0d [ ?? ?? ?? ?? ] 88 4c 24 70 4c 8d 05 ?? ?? ?? ?? 49 89 43 e0 48 8d 15 X0x{:x}",
@@ -270,12 +361,7 @@ impl_resolver!(all, KismetSystemLibrary, |ctx| async {
     }))
     .await;
 
-    let cap = Pattern::new("4c 8d 0d [ ?? ?? ?? ?? ]").unwrap();
-
-    let register_natives_addr =
-        try_ensure_one(refs.iter().flatten().map(|a| -> Result<_> {
-            Ok(ctx.image().memory.captures(&cap, *a)?.unwrap()[0].rip())
-        }))?;
+    let register_natives_addr = ensure_one(refs.iter().flatten().map(|m| m.captures[0].rip()))?;
 
     let register_natives = Pattern::new("48 83 ec 28 e8 ?? ?? ?? ?? 41 b8 [ ?? ?? ?? ?? ] 48 8d 15 [ ?? ?? ?? ?? ] 48 8b c8 48 83 c4 28 e9 ?? ?? ?? ??").unwrap();
 
@@ -285,14 +371,18 @@ impl_resolver!(all, KismetSystemLibrary, |ctx| async {
         .captures(&register_natives, register_natives_addr);
 
     if let Some([num, data]) = captures?.as_deref() {
-        let mut res = HashMap::new();
-
-        let ptr = data.rip();
-        for i in 0..(num.u32() as usize) {
-            let a = ptr + i * 0x10;
-            res.insert(mem.read_string(mem.ptr(a)?)?, mem.ptr(a + 8)?);
+        #[derive(MemoryRead)]
+        struct FNameNativePtrPair {
+            #[memory(utf8)]
+            name: String,
+            pointer: usize,
         }
-        Ok(KismetSystemLibrary(res))
+
+        let pairs =
+            Ptr::<FNameNativePtrPair>::new(data.rip()).read_slice(mem, num.u32() as usize)?;
+        Ok(KismetSystemLibrary(
+            pairs.into_iter().map(|p| (p.name, p.pointer)).collect(),
+        ))
     } else {
         bail_out!("did not match");
     }
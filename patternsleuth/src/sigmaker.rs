@@ -0,0 +1,335 @@
+//! Generate the shortest pattern that uniquely matches an address
+
+use anyhow::{bail, Context, Result};
+use iced_x86::{Decoder, DecoderOptions, OpKind};
+use object::SectionKind;
+
+use crate::{
+    image::Image,
+    scanner::{Pattern, PatternSet},
+    MemoryTrait,
+};
+
+/// Max length of a single x86 instruction
+const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// Find the shortest pattern that matches only the paired address in each image, searching up to
+/// `max_len` bytes. Growing forward from the address is preferred, otherwise the pattern is
+/// started at earlier instructions of the containing function and `|` is placed at the address.
+///
+/// Operands which are likely to change between builds (rip-relative displacements, branch
+/// targets and immediates pointing into the image) are wildcarded as are any bytes which differ
+/// between images.
+pub fn make_signature(targets: &[(&Image<'_>, usize)], max_len: usize) -> Result<Pattern> {
+    make(targets, max_len, true)
+}
+
+/// Same as [`make_signature`] but the pattern always starts at the address, for formats which
+/// cannot express [`Pattern::custom_offset`]
+pub fn make_signature_at(targets: &[(&Image<'_>, usize)], max_len: usize) -> Result<Pattern> {
+    make(targets, max_len, false)
+}
+
+fn make(targets: &[(&Image<'_>, usize)], max_len: usize, earlier: bool) -> Result<Pattern> {
+    let Some((img, address)) = targets.first() else {
+        bail!("no targets to make a signature for");
+    };
+
+    let mut starts = vec![*address];
+    let function = if earlier {
+        img.get_function(*address)?
+    } else {
+        None
+    };
+    if let Some(function) = function {
+        let min = function.range.start.max(address.saturating_sub(max_len));
+        let boundaries = decode(img, function.range.start, *address - function.range.start)?
+            .into_iter()
+            .map(|i| i.address)
+            .filter(|a| (min..*address).contains(a))
+            .collect::<Vec<_>>();
+        starts.extend(boundaries.into_iter().rev());
+    }
+
+    for start in starts {
+        if let Some(pattern) = shortest_unique(targets, *address - start, max_len)? {
+            return Ok(pattern);
+        }
+    }
+    bail!("no unique pattern found within {max_len} bytes of {address:#x}")
+}
+
+/// Instruction with relocatable operands wildcarded
+struct InstructionBytes {
+    address: usize,
+    sig: Vec<u8>,
+    mask: Vec<u8>,
+}
+
+/// Decode instructions starting at `address` until at least `len` bytes are covered
+fn decode(img: &Image<'_>, address: usize, len: usize) -> Result<Vec<InstructionBytes>> {
+    let section = img.memory.get_section_containing(address)?;
+    let end = (address + len + MAX_INSTRUCTION_LEN).min(section.address() + section.len());
+    let data = img.memory.range(address..end)?;
+
+    let is_image_address = |value: u64| {
+        usize::try_from(value)
+            .map(|v| img.memory.get_section_containing(v).is_ok())
+            .unwrap_or(false)
+    };
+
+    let mut decoder = Decoder::with_ip(64, data, address as u64, DecoderOptions::NONE);
+    let mut instructions = vec![];
+    let mut covered = 0;
+    while covered < len && decoder.can_decode() {
+        let inst = decoder.decode();
+        if inst.is_invalid() {
+            break;
+        }
+        let offsets = decoder.get_constant_offsets(&inst);
+        let start = inst.ip() as usize - address;
+
+        let mut mask = vec![0xff; inst.len()];
+        if offsets.has_displacement()
+            && (inst.is_ip_rel_memory_operand()
+                || offsets.displacement_size() >= 4
+                    && is_image_address(inst.memory_displacement64()))
+        {
+            let offset = offsets.displacement_offset();
+            mask[offset..offset + offsets.displacement_size()].fill(0);
+        }
+        if offsets.has_immediate() && offsets.immediate_size() >= 4 {
+            let relocatable = (0..inst.op_count()).any(|i| match inst.op_kind(i) {
+                OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64 => true,
+                OpKind::Immediate32 | OpKind::Immediate32to64 | OpKind::Immediate64 => {
+                    is_image_address(inst.immediate(i))
+                }
+                _ => false,
+            });
+            if relocatable {
+                let offset = offsets.immediate_offset();
+                mask[offset..offset + offsets.immediate_size()].fill(0);
+            }
+        }
+
+        let sig = data[start..start + inst.len()]
+            .iter()
+            .zip(&mask)
+            .map(|(b, m)| b & m)
+            .collect();
+        instructions.push(InstructionBytes {
+            address: inst.ip() as usize,
+            sig,
+            mask,
+        });
+        covered += inst.len();
+    }
+    Ok(instructions)
+}
+
+/// Find the shortest unique pattern starting `offset` bytes before each target
+fn shortest_unique(
+    targets: &[(&Image<'_>, usize)],
+    offset: usize,
+    max_len: usize,
+) -> Result<Option<Pattern>> {
+    let mut sig: Vec<u8> = vec![];
+    let mut mask: Vec<u8> = vec![];
+    // candidate pattern lengths at instruction boundaries of the first target
+    let mut boundaries = vec![];
+
+    for (i, (img, address)) in targets.iter().enumerate() {
+        let start = address
+            .checked_sub(offset)
+            .context("target address underflow")?;
+        let instructions = decode(img, start, max_len)?;
+        let target_sig = instructions
+            .iter()
+            .flat_map(|i| i.sig.iter().copied())
+            .take(max_len)
+            .collect::<Vec<_>>();
+        let target_mask = instructions
+            .iter()
+            .flat_map(|i| i.mask.iter().copied())
+            .take(max_len)
+            .collect::<Vec<_>>();
+
+        if i == 0 {
+            sig = target_sig;
+            mask = target_mask;
+            boundaries = instructions
+                .iter()
+                .map(|i| i.address + i.sig.len() - start)
+                .collect();
+        } else {
+            // wildcard any bytes which differ between targets
+            let len = sig.len().min(target_sig.len());
+            sig.truncate(len);
+            mask.truncate(len);
+            for ((s, m), (target_s, target_m)) in sig
+                .iter_mut()
+                .zip(mask.iter_mut())
+                .zip(target_sig.into_iter().zip(target_mask))
+            {
+                if *m != target_m || *s != target_s {
+                    *s = 0;
+                    *m = 0;
+                }
+            }
+        }
+    }
+
+    let build = |len: usize| {
+        // trailing wildcards do not make a pattern more unique
+        let len = mask[..len].iter().rposition(|m| *m != 0)? + 1;
+        if len <= offset {
+            return None;
+        }
+        let mut pattern = Pattern::from_bytes(sig[..len].to_vec()).ok()?;
+        pattern.simple.mask = mask[..len].to_vec();
+        pattern.custom_offset = offset;
+        Some(pattern)
+    };
+
+    let lengths = boundaries
+        .into_iter()
+        .filter(|len| *len > offset && *len <= sig.len())
+        .collect::<Vec<_>>();
+    let Some(len) = first_unique(targets, &lengths, build)? else {
+        return Ok(None);
+    };
+
+    // try to shorten further by cutting into the last instruction
+    let prev = lengths
+        .iter()
+        .rev()
+        .find(|l| **l < len)
+        .copied()
+        .unwrap_or(offset)
+        .max(offset);
+    let lengths = (prev + 1..len).collect::<Vec<_>>();
+    let len = first_unique(targets, &lengths, build)?.unwrap_or(len);
+
+    Ok(build(len))
+}
+
+/// Scan the executable sections of every target for the pattern of each length, returning the
+/// first length whose pattern only matches the target address in every image
+fn first_unique(
+    targets: &[(&Image<'_>, usize)],
+    lengths: &[usize],
+    build: impl Fn(usize) -> Option<Pattern>,
+) -> Result<Option<usize>> {
+    let candidates = lengths
+        .iter()
+        .filter_map(|len| build(*len).map(|p| (*len, p.first_match())))
+        .collect::<Vec<_>>();
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+    let patterns = candidates.iter().map(|(_, p)| p).collect::<Vec<_>>();
+    let pattern_set = PatternSet::new(&patterns);
+
+    let mut unique = vec![true; candidates.len()];
+    for (img, address) in targets {
+        let mut matches = vec![vec![]; candidates.len()];
+        for section in img
+            .memory
+            .sections()
+            .iter()
+            .filter(|s| s.kind() == SectionKind::Text)
+        {
+            let results = pattern_set.scan_limited(section.address(), section.data());
+            for (i, res) in results.into_iter().enumerate() {
+                // limit hit means there is more than one match
+                if res.limit_hit {
+                    matches[i].push(usize::MAX);
+                }
+                matches[i].extend(res.matches);
+            }
+        }
+        for (i, m) in matches.into_iter().enumerate() {
+            unique[i] &= m == [*address];
+        }
+    }
+    Ok(candidates
+        .iter()
+        .zip(unique)
+        .find(|(_, unique)| *unique)
+        .map(|((len, _), _)| *len))
+}
+
+#[cfg(all(test, feature = "image-pe"))]
+mod test {
+    use super::*;
+    use crate::image::pe::PEImage;
+
+    /// mov [rsp + 8], rbx; ret
+    const TARGET: [u8; 6] = [0x48, 0x89, 0x5c, 0x24, 0x08, 0xc3];
+
+    fn image(len: usize, code: &[(usize, &[u8])]) -> Image<'static> {
+        let mut text = vec![0xcc; len];
+        // differs from the target only in the displacement
+        let decoy = [0x48, 0x89, 0x5c, 0x24, 0x10, 0xc3];
+        for (address, bytes) in code.iter().chain([(0x1080, &decoy[..])].iter()) {
+            if address - 0x1000 + bytes.len() > len {
+                continue;
+            }
+            text[address - 0x1000..address - 0x1000 + bytes.len()].copy_from_slice(bytes);
+        }
+        PEImage::synthetic(
+            vec![(".text", SectionKind::Text, 0x1000, text)],
+            &[0x1000..0x1010, 0x1010..0x1020],
+        )
+    }
+
+    #[test]
+    fn test_make_signature() {
+        let a = image(0x100, &[(0x1010, &TARGET)]);
+        // shares the first instruction so it alone is not unique in b
+        let b = image(
+            0x100,
+            &[
+                (0x1020, &TARGET),
+                (0x1040, &[0x48, 0x89, 0x5c, 0x24, 0x08, 0x90]),
+            ],
+        );
+
+        let first = Pattern::new("48 89 5c 24 08").unwrap();
+        assert_eq!(first, make_signature(&[(&a, 0x1010)], 32).unwrap());
+        assert_eq!(
+            Pattern::new("48 89 5c 24 08 c3").unwrap(),
+            make_signature(&[(&a, 0x1010), (&b, 0x1020)], 32).unwrap()
+        );
+
+        // decodes fewer bytes than the later target as the section ends after it
+        let short = image(0x16, &[(0x1010, &TARGET)]);
+        assert_eq!(
+            Pattern::new("48 89 5c 24 08 c3").unwrap(),
+            make_signature(&[(&short, 0x1010), (&b, 0x1020)], 32).unwrap()
+        );
+        assert_eq!(
+            Pattern::new("48 89 5c 24 08 c3").unwrap(),
+            make_signature(&[(&b, 0x1020), (&short, 0x1010)], 32).unwrap()
+        );
+
+        // identical surroundings outside of any function
+        let twice = image(0x100, &[(0x1040, &TARGET), (0x1050, &TARGET)]);
+        assert!(make_signature(&[(&twice, 0x1050)], 8).is_err());
+
+        // only unique when started at the nop before the target
+        let nop = image(
+            0x100,
+            &[(0x1010, &[0x90]), (0x1011, &TARGET), (0x1040, &TARGET)],
+        );
+        assert_eq!(
+            Pattern::new("90 | 48").unwrap(),
+            make_signature(&[(&nop, 0x1011)], 8).unwrap()
+        );
+        assert!(make_signature_at(&[(&nop, 0x1011)], 8).is_err());
+        assert_eq!(
+            Pattern::new("48 89 5c 24 08").unwrap(),
+            make_signature_at(&[(&a, 0x1010)], 32).unwrap()
+        );
+    }
+}
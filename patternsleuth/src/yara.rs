@@ -0,0 +1,900 @@
+//! Subset of YARA rules compiled into patterns and evaluated against an [`Image`]
+//!
+//! Supported:
+//! - hex strings with wildcards, nibbles, bounded jumps (`[2-4]`) and alternations
+//! - text strings with the `ascii`, `wide`, `nocase` and `private` modifiers
+//! - conditions made of `and`, `or`, `not`, parentheses, `true`, `false`, `$a`, `#a == N` (and
+//!   the other comparisons), `$a at N`, `$a in (N..M)` and `all/any/none/N of them` or of a set
+//!   such as `($a, $b*)`
+//!
+//! Offsets in conditions are relative to the image base address rather than the file.
+
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+
+use crate::{
+    image::Image,
+    scanner::{Pattern, PatternSet},
+};
+
+/// Max number of patterns a single string can expand to because of alternations
+const MAX_PATTERNS: usize = 64;
+
+#[derive(Debug)]
+pub struct Rules {
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    pub name: String,
+    pub tags: Vec<String>,
+    pub strings: Vec<YaraString>,
+    pub condition: Condition,
+}
+
+/// String of a rule, matched if any of its patterns match
+#[derive(Debug)]
+pub struct YaraString {
+    pub id: String,
+    pub patterns: Vec<Pattern>,
+}
+
+/// Rule condition referring to strings by index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Bool(bool),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    /// `$a`
+    Matched(usize),
+    /// `#a == N`
+    Count(usize, Comparison, usize),
+    /// `$a at N`
+    At(usize, usize),
+    /// `$a in (N..M)`, inclusive
+    In(usize, usize, usize),
+    /// `all of ($a, $b)`
+    Of(Quantifier, Vec<usize>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantifier {
+    All,
+    Any,
+    None,
+    AtLeast(usize),
+}
+
+/// Result of a rule for an image
+#[derive(Debug)]
+pub struct RuleScan<'r> {
+    pub rule: &'r Rule,
+    pub matched: bool,
+    /// Sorted match addresses of each string
+    pub matches: Vec<Vec<usize>>,
+}
+
+impl Rules {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut cursor = Cursor { s, pos: 0 };
+        let mut rules = vec![];
+        while cursor.peek()?.is_some() {
+            if cursor.eat("import")? || cursor.eat("include")? {
+                bail!(
+                    "imports and includes are not supported {}",
+                    cursor.location()
+                );
+            }
+            let rule = parse_rule(&mut cursor)?;
+            if rules.iter().any(|r: &Rule| r.name == rule.name) {
+                bail!("duplicate rule {}", rule.name);
+            }
+            rules.push(rule);
+        }
+        Ok(Self { rules })
+    }
+
+    /// Scan every section of `image` and evaluate each rule
+    pub fn scan(&self, image: &Image<'_>) -> Vec<RuleScan<'_>> {
+        let patterns = self
+            .rules
+            .iter()
+            .flat_map(|r| &r.strings)
+            .flat_map(|s| &s.patterns)
+            .collect_vec();
+        let pattern_set = PatternSet::new(&patterns);
+
+        let mut pattern_matches = patterns.iter().map(|_| vec![]).collect_vec();
+        for section in image.memory.sections() {
+            let results = pattern_set.scan(section.address(), section.data());
+            for (matches, result) in pattern_matches.iter_mut().zip(results) {
+                matches.extend(result);
+            }
+        }
+
+        let mut pattern_matches = pattern_matches.into_iter();
+        self.rules
+            .iter()
+            .map(|rule| {
+                let matches = rule
+                    .strings
+                    .iter()
+                    .map(|s| {
+                        pattern_matches
+                            .by_ref()
+                            .take(s.patterns.len())
+                            .flatten()
+                            .sorted()
+                            .dedup()
+                            .collect_vec()
+                    })
+                    .collect_vec();
+                RuleScan {
+                    rule,
+                    matched: rule.condition.eval(&matches, image.base_address),
+                    matches,
+                }
+            })
+            .collect()
+    }
+}
+
+impl std::str::FromStr for Rules {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl Comparison {
+    fn apply(self, a: usize, b: usize) -> bool {
+        match self {
+            Self::Eq => a == b,
+            Self::Ne => a != b,
+            Self::Lt => a < b,
+            Self::Le => a <= b,
+            Self::Gt => a > b,
+            Self::Ge => a >= b,
+        }
+    }
+}
+
+impl Condition {
+    /// Evaluate given the match addresses of each string
+    pub fn eval(&self, matches: &[Vec<usize>], base_address: usize) -> bool {
+        let offsets = |s: usize| {
+            matches[s]
+                .iter()
+                .filter_map(move |a| a.checked_sub(base_address))
+        };
+        match self {
+            Self::Bool(b) => *b,
+            Self::Not(c) => !c.eval(matches, base_address),
+            Self::And(a, b) => a.eval(matches, base_address) && b.eval(matches, base_address),
+            Self::Or(a, b) => a.eval(matches, base_address) || b.eval(matches, base_address),
+            Self::Matched(s) => !matches[*s].is_empty(),
+            Self::Count(s, cmp, n) => cmp.apply(matches[*s].len(), *n),
+            Self::At(s, offset) => offsets(*s).any(|o| o == *offset),
+            Self::In(s, start, end) => offsets(*s).any(|o| (*start..=*end).contains(&o)),
+            Self::Of(quantifier, strings) => {
+                let count = strings.iter().filter(|s| !matches[**s].is_empty()).count();
+                match quantifier {
+                    Quantifier::All => count == strings.len(),
+                    Quantifier::Any => count > 0,
+                    Quantifier::None => count == 0,
+                    Quantifier::AtLeast(n) => count >= *n,
+                }
+            }
+        }
+    }
+}
+
+/// On-demand tokenizer over the rule source
+struct Cursor<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+impl<'a> Cursor<'a> {
+    fn rest(&self) -> &'a str {
+        &self.s[self.pos..]
+    }
+    fn location(&self) -> String {
+        format!("at line {}", self.s[..self.pos].matches('\n').count() + 1)
+    }
+    /// Skip whitespace and comments
+    fn skip(&mut self) -> Result<()> {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if trimmed.starts_with("//") {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else if trimmed.starts_with("/*") {
+                let end = trimmed
+                    .find("*/")
+                    .with_context(|| format!("unterminated comment {}", self.location()))?;
+                self.pos += end + 2;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+    fn peek(&mut self) -> Result<Option<char>> {
+        self.skip()?;
+        Ok(self.rest().chars().next())
+    }
+    /// Consume `token` if next, keywords must not be followed by an identifier character
+    fn eat(&mut self, token: &str) -> Result<bool> {
+        self.skip()?;
+        let Some(after) = self.rest().strip_prefix(token) else {
+            return Ok(false);
+        };
+        if token.ends_with(is_ident_char) && after.starts_with(is_ident_char) {
+            return Ok(false);
+        }
+        self.pos += token.len();
+        Ok(true)
+    }
+    fn expect(&mut self, token: &str) -> Result<()> {
+        if !self.eat(token)? {
+            bail!("expected \"{token}\" {}", self.location());
+        }
+        Ok(())
+    }
+    fn ident(&mut self) -> Result<Option<&'a str>> {
+        self.skip()?;
+        let rest = self.rest();
+        let len = rest.find(|c| !is_ident_char(c)).unwrap_or(rest.len());
+        if len == 0 || rest.starts_with(|c: char| c.is_ascii_digit()) {
+            return Ok(None);
+        }
+        self.pos += len;
+        Ok(Some(&rest[..len]))
+    }
+    /// Whether the next tokens are a section label such as `condition:`
+    fn at_section(&mut self) -> Result<bool> {
+        let pos = self.pos;
+        let section = self.ident()?.is_some() && self.eat(":")?;
+        self.pos = pos;
+        Ok(section)
+    }
+    fn number(&mut self) -> Result<Option<usize>> {
+        self.skip()?;
+        let rest = self.rest();
+        if !rest.starts_with(|c: char| c.is_ascii_digit()) {
+            return Ok(None);
+        }
+        let len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(rest.len());
+        let s = &rest[..len];
+        let n = match s.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => s.parse(),
+        }
+        .with_context(|| format!("bad number \"{s}\" {}", self.location()))?;
+        self.pos += len;
+        Ok(Some(n))
+    }
+    fn expect_number(&mut self) -> Result<usize> {
+        self.number()?
+            .with_context(|| format!("expected number {}", self.location()))
+    }
+    /// `$name`, `#name` etc, returning the name and whether it ends with a `*` wildcard
+    fn string_ref(&mut self, sigil: char) -> Result<Option<(&'a str, bool)>> {
+        if self.peek()? != Some(sigil) {
+            return Ok(None);
+        }
+        self.pos += 1;
+        let rest = self.rest();
+        let len = rest.find(|c| !is_ident_char(c)).unwrap_or(rest.len());
+        self.pos += len;
+        let wildcard = self.rest().starts_with('*');
+        if wildcard {
+            self.pos += 1;
+        }
+        Ok(Some((&rest[..len], wildcard)))
+    }
+    /// Quoted text string with escapes
+    fn text(&mut self) -> Result<Vec<u8>> {
+        self.expect("\"")?;
+        let mut bytes = vec![];
+        let mut chars = self.rest().char_indices();
+        loop {
+            let Some((i, c)) = chars.next() else {
+                bail!("unterminated string {}", self.location());
+            };
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(bytes);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => bytes.push(b'"'),
+                    Some('\\') => bytes.push(b'\\'),
+                    Some('n') => bytes.push(b'\n'),
+                    Some('r') => bytes.push(b'\r'),
+                    Some('t') => bytes.push(b'\t'),
+                    Some('x') => {
+                        let hex = chars.by_ref().take(2).map(|(_, c)| c).collect::<String>();
+                        bytes.push(
+                            u8::from_str_radix(&hex, 16)
+                                .with_context(|| format!("bad escape \\x{hex}"))?,
+                        );
+                    }
+                    c => bail!("unsupported escape {c:?} {}", self.location()),
+                },
+                c => bytes.extend(c.to_string().as_bytes()),
+            }
+        }
+    }
+}
+
+fn parse_rule(c: &mut Cursor<'_>) -> Result<Rule> {
+    while c.eat("private")? || c.eat("global")? {}
+    c.expect("rule")?;
+    let name = c
+        .ident()?
+        .with_context(|| format!("expected rule name {}", c.location()))?
+        .to_string();
+    let mut tags = vec![];
+    if c.eat(":")? {
+        while let Some(tag) = c.ident()? {
+            tags.push(tag.to_string());
+        }
+    }
+    c.expect("{")?;
+
+    if c.eat("meta")? {
+        c.expect(":")?;
+        while !c.at_section()? {
+            c.ident()?
+                .with_context(|| format!("expected meta name {}", c.location()))?;
+            c.expect("=")?;
+            if c.peek()? == Some('"') {
+                c.text()?;
+            } else if !(c.eat("true")? || c.eat("false")? || c.number()?.is_some()) {
+                bail!("bad meta value {}", c.location());
+            }
+        }
+    }
+
+    let mut strings: Vec<YaraString> = vec![];
+    if c.eat("strings")? {
+        c.expect(":")?;
+        while let Some((id, wildcard)) = c.string_ref('$')? {
+            if wildcard {
+                bail!("bad string identifier ${id}* {}", c.location());
+            }
+            // anonymous strings can only be referred to by `them` or `$*`
+            let id = format!("${id}");
+            if id != "$" && strings.iter().any(|s| s.id == id) {
+                bail!("duplicate string {id}");
+            }
+            let patterns = parse_string(c).with_context(|| format!("failed to parse {id}"))?;
+            strings.push(YaraString { id, patterns });
+        }
+    }
+
+    c.expect("condition")?;
+    c.expect(":")?;
+    let condition = parse_or(c, &strings)?;
+    c.expect("}")?;
+
+    Ok(Rule {
+        name,
+        tags,
+        strings,
+        condition,
+    })
+}
+
+/// Parse the value and modifiers of a string after its identifier
+fn parse_string(c: &mut Cursor<'_>) -> Result<Vec<Pattern>> {
+    c.expect("=")?;
+    let (text, hex) = match c.peek()? {
+        Some('"') => (Some(c.text()?), None),
+        Some('{') => {
+            c.pos += 1;
+            let rest = c.rest();
+            let end = rest
+                .find('}')
+                .with_context(|| format!("unterminated hex string {}", c.location()))?;
+            c.pos += end + 1;
+            (None, Some(&rest[..end]))
+        }
+        Some('/') => bail!("regular expressions are not supported"),
+        _ => bail!("expected string {}", c.location()),
+    };
+
+    let (mut ascii, mut wide, mut nocase) = (false, false, false);
+    loop {
+        let pos = c.pos;
+        let modifier = c.ident()?;
+        match modifier {
+            Some("ascii") => ascii = true,
+            Some("wide") => wide = true,
+            Some("nocase") => nocase = true,
+            Some("private") => {}
+            Some(m @ ("fullword" | "xor" | "base64" | "base64wide")) => {
+                bail!("modifier {m} is not supported")
+            }
+            _ => {
+                c.pos = pos;
+                break;
+            }
+        }
+    }
+
+    if let Some(hex) = hex {
+        if ascii || wide || nocase {
+            bail!("text modifiers cannot be used with hex strings");
+        }
+        return parse_hex(hex);
+    }
+    let text = text.unwrap();
+    if text.is_empty() {
+        bail!("empty string");
+    }
+    let mut encodings = vec![];
+    if ascii || !wide {
+        encodings.push(text.clone());
+    }
+    if wide {
+        encodings.push(text.iter().flat_map(|b| [*b, 0]).collect());
+    }
+    encodings
+        .into_iter()
+        .map(|bytes| {
+            let mut pattern = Pattern::from_bytes(bytes)?;
+            if nocase {
+                for (sig, mask) in pattern.simple.sig.iter_mut().zip(&mut pattern.simple.mask) {
+                    if sig.is_ascii_alphabetic() {
+                        // upper and lower case letters only differ by bit 5
+                        *sig &= !0x20;
+                        *mask = !0x20;
+                    }
+                }
+            }
+            Ok(pattern)
+        })
+        .collect()
+}
+
+/// Hex string token
+#[derive(Debug, Clone)]
+enum HexToken {
+    /// Byte in pattern syntax, `4D`, `?D` or `??`
+    Byte(String),
+    /// Jump in pattern syntax, `[2-4]`
+    Jump(String),
+    Alternation(Vec<Vec<HexToken>>),
+}
+
+fn parse_hex(s: &str) -> Result<Vec<Pattern>> {
+    let tokens = parse_hex_tokens(&mut s.chars().peekable(), false)?;
+    expand_hex(&tokens)?
+        .into_iter()
+        .map(|words| Pattern::new(words.join(" ")))
+        .collect()
+}
+
+fn parse_hex_tokens(
+    chars: &mut std::iter::Peekable<impl Iterator<Item = char>>,
+    nested: bool,
+) -> Result<Vec<HexToken>> {
+    let mut tokens = vec![];
+    while let Some(&c) = chars.peek() {
+        match c {
+            ')' | '|' if nested => break,
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '[' => {
+                chars.next();
+                let jump = chars
+                    .by_ref()
+                    .take_while(|c| *c != ']')
+                    .filter(|c| !c.is_whitespace())
+                    .collect::<String>();
+                let bounded = match jump.split_once('-') {
+                    Some((min, max)) => !min.is_empty() && !max.is_empty(),
+                    None => !jump.is_empty(),
+                };
+                if !bounded {
+                    bail!("unbounded jump [{jump}] is not supported");
+                }
+                tokens.push(HexToken::Jump(format!("[{jump}]")));
+            }
+            '(' => {
+                chars.next();
+                let mut alternatives = vec![parse_hex_tokens(chars, true)?];
+                loop {
+                    match chars.next() {
+                        Some('|') => alternatives.push(parse_hex_tokens(chars, true)?),
+                        Some(')') => break,
+                        _ => bail!("unterminated alternation"),
+                    }
+                }
+                tokens.push(HexToken::Alternation(alternatives));
+            }
+            '~' => bail!("negated bytes are not supported"),
+            _ => {
+                // only consume the digits so a following [ or ( is not lost
+                let mut byte = String::new();
+                while byte.len() < 2 {
+                    match chars.next_if(|c| c.is_ascii_hexdigit() || *c == '?') {
+                        Some(c) => byte.push(c),
+                        None => break,
+                    }
+                }
+                match byte.len() {
+                    2 => tokens.push(HexToken::Byte(byte)),
+                    0 => bail!("unexpected {c:?} in hex string"),
+                    _ => bail!("bad hex byte \"{byte}\""),
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Expand alternations of byte sequences into the words of one pattern each. Alternations of
+/// single fixed bytes are kept as pattern byte alternatives.
+fn expand_hex(tokens: &[HexToken]) -> Result<Vec<Vec<String>>> {
+    let mut expanded = vec![vec![]];
+    for token in tokens {
+        let words = match token {
+            HexToken::Byte(word) | HexToken::Jump(word) => vec![vec![word.clone()]],
+            HexToken::Alternation(alternatives) => {
+                let single_bytes = alternatives
+                    .iter()
+                    .map(|a| match &a[..] {
+                        [HexToken::Byte(b)] if !b.contains('?') => Some(b.as_str()),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>();
+                match single_bytes {
+                    Some(bytes) => vec![vec![format!("({})", bytes.join("|"))]],
+                    None => alternatives
+                        .iter()
+                        .map(|a| expand_hex(a))
+                        .flatten_ok()
+                        .collect::<Result<_>>()?,
+                }
+            }
+        };
+        if expanded.len() * words.len() > MAX_PATTERNS {
+            bail!("hex string expands to more than {MAX_PATTERNS} patterns");
+        }
+        expanded = expanded
+            .iter()
+            .cartesian_product(&words)
+            .map(|(a, b)| a.iter().chain(b).cloned().collect())
+            .collect();
+    }
+    Ok(expanded)
+}
+
+fn parse_or(c: &mut Cursor<'_>, strings: &[YaraString]) -> Result<Condition> {
+    let mut condition = parse_and(c, strings)?;
+    while c.eat("or")? {
+        condition = Condition::Or(condition.into(), parse_and(c, strings)?.into());
+    }
+    Ok(condition)
+}
+
+fn parse_and(c: &mut Cursor<'_>, strings: &[YaraString]) -> Result<Condition> {
+    let mut condition = parse_not(c, strings)?;
+    while c.eat("and")? {
+        condition = Condition::And(condition.into(), parse_not(c, strings)?.into());
+    }
+    Ok(condition)
+}
+
+fn parse_not(c: &mut Cursor<'_>, strings: &[YaraString]) -> Result<Condition> {
+    if c.eat("not")? {
+        return Ok(Condition::Not(parse_not(c, strings)?.into()));
+    }
+    parse_primary(c, strings)
+}
+
+/// Indexes of the strings matching `name`, a prefix if `wildcard`
+fn find_strings(strings: &[YaraString], name: &str, wildcard: bool) -> Result<Vec<usize>> {
+    let id = format!("${name}");
+    let indexes = strings
+        .iter()
+        .positions(|s| match wildcard {
+            true => s.id.starts_with(&id),
+            false => s.id == id,
+        })
+        .collect_vec();
+    if indexes.is_empty() {
+        bail!("undefined string {id}{}", if wildcard { "*" } else { "" });
+    }
+    Ok(indexes)
+}
+
+fn find_string(
+    c: &Cursor<'_>,
+    strings: &[YaraString],
+    name: &str,
+    wildcard: bool,
+) -> Result<usize> {
+    if wildcard || name.is_empty() {
+        bail!("expected a single string {}", c.location());
+    }
+    Ok(find_strings(strings, name, false)?[0])
+}
+
+fn parse_primary(c: &mut Cursor<'_>, strings: &[YaraString]) -> Result<Condition> {
+    if c.eat("(")? {
+        let condition = parse_or(c, strings)?;
+        c.expect(")")?;
+        return Ok(condition);
+    }
+    if c.eat("true")? {
+        return Ok(Condition::Bool(true));
+    }
+    if c.eat("false")? {
+        return Ok(Condition::Bool(false));
+    }
+    if let Some((name, wildcard)) = c.string_ref('$')? {
+        let s = find_string(c, strings, name, wildcard)?;
+        if c.eat("at")? {
+            return Ok(Condition::At(s, c.expect_number()?));
+        }
+        if c.eat("in")? {
+            c.expect("(")?;
+            let start = c.expect_number()?;
+            c.expect("..")?;
+            let end = c.expect_number()?;
+            c.expect(")")?;
+            return Ok(Condition::In(s, start, end));
+        }
+        return Ok(Condition::Matched(s));
+    }
+    if let Some((name, wildcard)) = c.string_ref('#')? {
+        let s = find_string(c, strings, name, wildcard)?;
+        let comparisons = [
+            ("==", Comparison::Eq),
+            ("!=", Comparison::Ne),
+            ("<=", Comparison::Le),
+            (">=", Comparison::Ge),
+            ("<", Comparison::Lt),
+            (">", Comparison::Gt),
+        ];
+        for (token, comparison) in comparisons {
+            if c.eat(token)? {
+                return Ok(Condition::Count(s, comparison, c.expect_number()?));
+            }
+        }
+        bail!("expected comparison {}", c.location());
+    }
+
+    let quantifier = if c.eat("all")? {
+        Quantifier::All
+    } else if c.eat("any")? {
+        Quantifier::Any
+    } else if c.eat("none")? {
+        Quantifier::None
+    } else if let Some(n) = c.number()? {
+        Quantifier::AtLeast(n)
+    } else {
+        bail!("unsupported condition {}", c.location());
+    };
+    c.expect("of")?;
+    let set = if c.eat("them")? {
+        if strings.is_empty() {
+            bail!("rule has no strings for them");
+        }
+        (0..strings.len()).collect()
+    } else {
+        c.expect("(")?;
+        let mut set = vec![];
+        loop {
+            let (name, wildcard) = c
+                .string_ref('$')?
+                .with_context(|| format!("expected string {}", c.location()))?;
+            set.extend(find_strings(strings, name, wildcard)?);
+            if !c.eat(",")? {
+                break;
+            }
+        }
+        c.expect(")")?;
+        set.into_iter().unique().collect()
+    };
+    Ok(Condition::Of(quantifier, set))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(s: &str) -> Rule {
+        Rules::parse(s).unwrap().rules.remove(0)
+    }
+
+    fn scan(string: &YaraString, data: &[u8]) -> Vec<usize> {
+        let set = PatternSet::new(&string.patterns.iter().collect_vec());
+        set.scan(0, data)
+            .concat()
+            .into_iter()
+            .sorted()
+            .dedup()
+            .collect()
+    }
+
+    #[test]
+    fn test_text_strings() {
+        let r = rule(
+            r#"rule r {
+                strings:
+                    $a = "Ab" nocase
+                    $b = "hi" wide
+                    $c = "hi" ascii wide
+                    $d = "q\x41\"\\" private
+                condition:
+                    any of them
+            }"#,
+        );
+        let [a, b, c, d] = &r.strings[..] else {
+            panic!("expected 4 strings");
+        };
+        assert_eq!(vec![1, 4, 7], scan(a, b"xAB ab aB @b"));
+        assert_eq!(vec![0], scan(b, b"h\0i\0hi"));
+        assert_eq!(vec![0, 4], scan(c, b"h\0i\0hi"));
+        assert_eq!(vec![1], scan(d, b"xqA\"\\"));
+    }
+
+    #[test]
+    fn test_hex_strings() {
+        let r = rule(
+            "rule r {
+                strings:
+                    $a = { 4D 5A [2-4] ( 90 | 91 ) ?0 }
+                    $b = { 01 ( 02 03 | 04 ) 05 }
+                    $c = {4D[2]5A(90|91)}
+                    $d = { 4D [ 1 - 2 ] 5A }
+                condition:
+                    all of them
+            }",
+        );
+        let [a, b, c, d] = &r.strings[..] else {
+            panic!("expected 4 strings");
+        };
+        assert_eq!(vec![0], scan(a, &[0x4d, 0x5a, 0, 0, 0x91, 0x30]));
+        assert!(scan(a, &[0x4d, 0x5a, 0, 0, 0, 0, 0, 0x91, 0x30]).is_empty());
+        assert_eq!(2, b.patterns.len());
+        assert_eq!(vec![0, 4], scan(b, &[1, 2, 3, 5, 1, 4, 5, 1, 2, 5]));
+        assert_eq!(vec![0], scan(c, &[0x4d, 0, 0, 0x5a, 0x90]));
+        assert_eq!(vec![0], scan(d, &[0x4d, 0, 0, 0x5a]));
+
+        for hex in [
+            "{ 4 D }",
+            "{ 4D 5 [2] 5A }",
+            "{ 4D 5(90|91) }",
+            "{ 4D [-] 5A }",
+            "{ 4D [2-] 5A }",
+            "{ ~00 }",
+            "{ 4D ( 90 }",
+            "{ 4D ) }",
+            "{ 4D | 90 }",
+            "{ 4G }",
+        ] {
+            let rule = format!("rule r {{ strings: $a = {hex} condition: $a }}");
+            assert!(Rules::parse(&rule).is_err(), "{hex}");
+        }
+    }
+
+    #[test]
+    fn test_conditions() {
+        let eval = |condition: &str| {
+            let r = rule(&format!(
+                r#"rule r {{
+                    strings: $a = "a" $b1 = "b" $b2 = "c" $ = "d"
+                    condition: {condition}
+                }}"#
+            ));
+            let matches = [vec![0x1010, 0x1020], vec![0x1030], vec![], vec![0x1040]];
+            r.condition.eval(&matches, 0x1000)
+        };
+
+        assert!(eval("#a == 2"));
+        assert!(!eval("#a > 2"));
+        assert!(eval("#b2 == 0 and #b1 <= 1"));
+        assert!(eval("$a at 0x10"));
+        assert!(!eval("$a at 0x11"));
+        assert!(eval("$a in (0x11..0x20)"));
+        assert!(!eval("$a in (0x21..0x30)"));
+
+        assert!(eval("any of them"));
+        assert!(!eval("all of them"));
+        assert!(eval("3 of them"));
+        assert!(!eval("4 of them"));
+        assert!(eval("all of ($a, $b1)"));
+        assert!(!eval("all of ($b*)"));
+        assert!(eval("any of ($b*)"));
+        assert!(eval("2 of ($a, $b*)"));
+        assert!(!eval("3 of ($a, $b*)"));
+        assert!(eval("none of ($b2)"));
+        // includes the anonymous string
+        assert!(eval("3 of ($*)"));
+
+        // and binds tighter than or
+        assert!(eval("$b2 or $a and $b1"));
+        assert!(!eval("($b2 or $a) and $b2"));
+        assert!(eval("not $a or $b1"));
+        assert!(eval("$a and not $b2"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let parse = |strings: &str, condition: &str| {
+            Rules::parse(&format!(
+                "rule r {{ strings: {strings} condition: {condition} }}"
+            ))
+        };
+
+        assert!(parse(r#"$a = "a" $a = "b""#, "$a").is_err());
+        assert!(parse(r#"$a* = "a""#, "$a").is_err());
+        assert!(parse(r#"$a = "a""#, "$c").is_err());
+        assert!(parse(r#"$a = "a""#, "#c == 1").is_err());
+        assert!(parse(r#"$a = "a""#, "any of ($c*)").is_err());
+        assert!(parse(r#"$a = "a""#, "$a*").is_err());
+        assert!(parse(r#"$a = "a""#, "#a").is_err());
+        assert!(parse(r#"$a = """#, "$a").is_err());
+        assert!(parse(r#"$a = "a" fullword"#, "$a").is_err());
+        assert!(parse(r#"$a = { 4D } wide"#, "$a").is_err());
+        assert!(parse(r#"$a = "a""#, "$a at").is_err());
+        assert!(parse("", "any of them").is_err());
+        assert!(Rules::parse("rule r { condition: true } rule r { condition: false }").is_err());
+
+        // anonymous strings can't be referred to on their own or clash with named ones
+        assert!(parse(r#"$ = "a""#, "$").is_err());
+        assert!(parse(r#"$ = "a""#, "#").is_err());
+        let r = parse(r#"$ = "a" $ = "b" $0 = "c""#, "$0").unwrap();
+        assert_eq!(Condition::Matched(2), r.rules[0].condition);
+    }
+
+    #[cfg(feature = "image-pe")]
+    #[test]
+    fn test_scan() {
+        use crate::image::pe::PEImage;
+
+        let mut text = vec![0; 0x100];
+        text[0x10..0x12].copy_from_slice(b"MZ");
+        let image = PEImage::synthetic(
+            vec![(".text", object::SectionKind::Text, 0x1000, text)],
+            &[],
+        );
+        let rules = Rules::parse(
+            r#"
+            rule at { strings: $a = "MZ" condition: $a at 0x1010 }
+            rule not_at { strings: $a = { 4D 5A } condition: $a at 0x1000 }
+            "#,
+        )
+        .unwrap();
+        let scans = rules.scan(&image);
+        assert_eq!(vec![vec![0x1010]], scans[0].matches);
+        assert!(scans[0].matched);
+        assert!(!scans[1].matched);
+    }
+}
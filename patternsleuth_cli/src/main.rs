@@ -18,9 +18,13 @@ use patricia_tree::StringPatriciaMap;
 use patternsleuth::image::Image;
 use patternsleuth::resolvers::{resolvers, NamedResolver};
 
-use patternsleuth::scanner::Xref;
+use patternsleuth::scanner::{PatternFormat, Xref, XrefKind};
 use patternsleuth::symbols::Symbol;
-use patternsleuth::{scanner::Pattern, PatternConfig, Resolution};
+use patternsleuth::yara::Rules;
+use patternsleuth::{
+    scanner::{Pattern, PatternSet},
+    PatternConfig, Resolution, ScanSet,
+};
 
 #[derive(Parser)]
 enum Commands {
@@ -31,6 +35,7 @@ enum Commands {
     BuildIndex(CommandBuildIndex),
     ViewSymbol(CommandViewSymbol),
     AutoGen(CommandAutoGen),
+    SigMaker(CommandSigMaker),
 }
 
 fn parse_maybe_hex(s: &str) -> Result<usize> {
@@ -39,6 +44,17 @@ fn parse_maybe_hex(s: &str) -> Result<usize> {
         .unwrap_or_else(|| s.parse())?)
 }
 
+/// Parse an xref address optionally prefixed with its kind as in patterns (e.g. `A0x1234`)
+fn parse_xref(s: &str) -> Result<(XrefKind, Xref)> {
+    let (kind, s) = XrefKind::split_prefix(s).unwrap_or((XrefKind::Rel32, s));
+    Ok((kind, Xref(parse_maybe_hex(s)?)))
+}
+
+fn format_parser() -> impl IntoResettable<ValueParser> {
+    PossibleValuesParser::new(PatternFormat::ALL.map(|f| PossibleValue::new(f.name())))
+        .map(|v| PatternFormat::from_str(&v).unwrap())
+}
+
 fn resolver_parser() -> impl IntoResettable<ValueParser> {
     fn parse_resolver(s: &str) -> Result<&'static NamedResolver> {
         resolvers()
@@ -76,16 +92,30 @@ struct CommandScan {
     disassemble_merged: bool,
 
     /// A pattern to scan for (can be specified multiple times)
-    #[arg(short, long, value_parser(|s: &_| Pattern::new(s)))]
-    patterns: Vec<Pattern>,
+    #[arg(short, long)]
+    patterns: Vec<String>,
 
     /// A path to a JSON pattern config file
     #[arg(long)]
     pattern_config: Option<PathBuf>,
 
-    /// An xref to scan for (can be specified multiple times)
-    #[arg(short, long, value_parser(|s: &str| parse_maybe_hex(s).map(Xref)))]
-    xref: Vec<Xref>,
+    /// A path to a file of YARA rules to evaluate (supports a subset of YARA)
+    #[arg(long)]
+    yara: Option<PathBuf>,
+
+    /// Format of patterns passed with --patterns and --pattern-config
+    #[arg(long, default_value = "patternsleuth", value_parser(format_parser()))]
+    format: PatternFormat,
+
+    /// Also report sites where patterns match with up to this many mismatching bytes, for finding
+    /// where a pattern that no longer matches has moved to
+    #[arg(long)]
+    fuzzy: Option<usize>,
+
+    /// An xref to scan for (can be specified multiple times). Prefix with the kind as in patterns
+    /// (X, S, I or A) to scan for other than rel32 references
+    #[arg(short, long, value_parser(parse_xref))]
+    xref: Vec<(XrefKind, Xref)>,
 
     /// Load and display symbols from PDBs when available (can be slow)
     #[arg(long)]
@@ -197,6 +227,42 @@ struct CommandViewSymbol {
 #[derive(Parser)]
 struct CommandAutoGen {}
 
+#[derive(Debug, Clone)]
+struct TargetSpec {
+    path: PathBuf,
+    address: usize,
+}
+impl FromStr for TargetSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some((path, address)) = s.rsplit_once(':') {
+            Ok(TargetSpec {
+                path: path.into(),
+                address: parse_maybe_hex(address)?,
+            })
+        } else {
+            bail!("failed to parse target: expected format <path.exe>:<address>")
+        }
+    }
+}
+
+#[derive(Parser)]
+struct CommandSigMaker {
+    /// An executable and address to make a pattern for (can be specified multiple times to find a
+    /// pattern unique in every executable)
+    #[arg(short, long, required = true)]
+    target: Vec<TargetSpec>,
+
+    /// Maximum length of the pattern in bytes
+    #[arg(long, default_value = "64")]
+    max_len: usize,
+
+    /// Format to output the pattern in
+    #[arg(long, default_value = "patternsleuth", value_parser(format_parser()))]
+    format: PatternFormat,
+}
+
 fn find_ext<P: AsRef<Path>, E: AsRef<str>>(dir: P, ext: &[E]) -> Result<Option<PathBuf>> {
     for f in fs::read_dir(dir)? {
         let f = f?.path();
@@ -231,9 +297,43 @@ fn main() -> Result<()> {
         Commands::BuildIndex(command) => db::build(command),
         Commands::ViewSymbol(command) => db::view(command),
         Commands::AutoGen(command) => db::auto_gen(command),
+        Commands::SigMaker(command) => sig_maker(command),
     }
 }
 
+fn sig_maker(command: CommandSigMaker) -> Result<()> {
+    let data = command
+        .target
+        .iter()
+        .map(|t| fs::read(&t.path).with_context(|| format!("failed to read {}", t.path.display())))
+        .collect::<Result<Vec<_>>>()?;
+    let images = data
+        .iter()
+        .map(|d| Image::builder().build(d))
+        .collect::<Result<Vec<_>>>()?;
+    let targets = images
+        .iter()
+        .zip(&command.target)
+        .map(|(img, t)| (img, t.address))
+        .collect::<Vec<_>>();
+
+    let pattern = if command.format == PatternFormat::Patternsleuth {
+        patternsleuth::sigmaker::make_signature(&targets, command.max_len)?
+    } else {
+        // other formats cannot express an offset so the pattern has to start at the target
+        patternsleuth::sigmaker::make_signature_at(&targets, command.max_len).with_context(
+            || {
+                format!(
+                    "{} format requires a pattern starting at the target",
+                    command.format
+                )
+            },
+        )?
+    };
+    println!("{}", pattern.to_format(command.format)?);
+    Ok(())
+}
+
 // TODO remove, only used for patterns/xrefs from CLI
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 struct Sig(String);
@@ -244,29 +344,61 @@ impl std::fmt::Display for Sig {
 }
 
 fn scan(command: CommandScan) -> Result<()> {
-    let include_default = command.patterns.is_empty() && command.xref.is_empty();
+    let include_default =
+        command.patterns.is_empty() && command.xref.is_empty() && command.yara.is_none();
     // TODO warn if empty?
-    let patterns = command
+    let format = command.format;
+    let parse_pattern = |p: &str| {
+        Pattern::parse_format(p, format)
+            .with_context(|| format!("failed to parse {format} pattern {p}"))
+    };
+    let arg_patterns = command
         .patterns
+        .iter()
+        .map(|p| parse_pattern(p))
+        .collect::<Result<Vec<_>>>()?;
+    let config_patterns = command
+        .pattern_config
+        .map(|path| -> Result<_> {
+            let file = std::fs::read_to_string(path)?;
+            let config: HashMap<String, Vec<String>> = serde_json::from_str(&file)?;
+            config
+                .into_iter()
+                .map(|(symbol, patterns)| {
+                    let patterns = patterns
+                        .iter()
+                        .map(|p| parse_pattern(p))
+                        .collect::<Result<Vec<_>>>()?;
+                    Ok((symbol, patterns))
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+    let yara_rules = command
+        .yara
+        .map(|path| -> Result<_> {
+            let file = std::fs::read_to_string(&path)?;
+            Rules::parse(&file)
+                .with_context(|| format!("failed to parse YARA rules {}", path.display()))
+        })
+        .transpose()?;
+
+    let patterns = arg_patterns
         .into_iter()
         .enumerate()
         .map(|(i, p)| PatternConfig::new(Sig("arg".to_string()), format!("pattern {i}"), None, p))
         .chain(command.xref.into_iter().enumerate().map(|(i, p)| {
             PatternConfig::xref(Sig("arg".to_string()), format!("xref {i}"), None, p)
         }))
-        .chain(command.pattern_config.into_iter().flat_map(|path| {
-            let file = std::fs::read_to_string(path).unwrap();
-            let config: HashMap<String, Vec<String>> = serde_json::from_str(&file).unwrap();
-
-            config.into_iter().flat_map(|(symbol, patterns)| {
-                patterns.into_iter().enumerate().map(move |(i, p)| {
-                    PatternConfig::new(
-                        Sig(format!("file {symbol}")),
-                        format!("#{i} {symbol}"),
-                        None,
-                        Pattern::new(p).unwrap(),
-                    )
-                })
+        .chain(config_patterns.into_iter().flat_map(|(symbol, patterns)| {
+            patterns.into_iter().enumerate().map(move |(i, p)| {
+                PatternConfig::new(
+                    Sig(format!("file {symbol}")),
+                    format!("#{i} {symbol}"),
+                    None,
+                    p,
+                )
             })
         }))
         .collect_vec();
@@ -307,6 +439,24 @@ fn scan(command: CommandScan) -> Result<()> {
         }
     }
 
+    // compile patterns once for all games
+    let scan_set = ScanSet::new(&patterns);
+    let fuzzy_patterns = patterns
+        .iter()
+        .filter_map(|config| {
+            config
+                .scan
+                .scan_type
+                .get_pattern()
+                .map(|pattern| (config, pattern))
+        })
+        .collect_vec();
+    // only compiled when fuzzy matching is requested
+    let fuzzy = command.fuzzy.map(|max_mismatches| {
+        let patterns = fuzzy_patterns.iter().map(|(_, p)| *p).collect_vec();
+        (max_mismatches, PatternSet::new(&patterns))
+    });
+
     let mut games_vec = vec![];
 
     if let Some(pid) = command.pid {
@@ -364,7 +514,7 @@ fn scan(command: CommandScan) -> Result<()> {
 
         games.insert(name.to_string());
 
-        let scan = exe.scan(&patterns)?;
+        let scan = exe.scan_set(&scan_set)?;
 
         // group results by Sig
         let folded_scans = scan
@@ -495,6 +645,62 @@ fn scan(command: CommandScan) -> Result<()> {
             table.add_row(Row::new(cells));
         }
 
+        if let Some((max_mismatches, fuzzy_set)) = &fuzzy {
+            let mut fuzzy_matches = fuzzy_patterns.iter().map(|_| vec![]).collect_vec();
+            for section in exe.memory.sections() {
+                let results =
+                    fuzzy_set.scan_fuzzy(section.address(), section.data(), *max_mismatches);
+                for ((matches, (config, _)), results) in
+                    fuzzy_matches.iter_mut().zip(&fuzzy_patterns).zip(results)
+                {
+                    if config
+                        .scan
+                        .section
+                        .map(|s| s == section.kind())
+                        .unwrap_or(true)
+                    {
+                        matches.extend(results);
+                    }
+                }
+            }
+            for ((config, _), matches) in fuzzy_patterns.iter().zip(fuzzy_matches) {
+                let cell = if matches.is_empty() {
+                    "not found".red().to_string()
+                } else {
+                    matches
+                        .iter()
+                        .sorted_by_key(|m| (m.mismatches, m.address))
+                        .map(|m| format!("{:016x} mismatches={}", m.address, m.mismatches))
+                        .join("\n")
+                };
+                table.add_row(Row::new(vec![
+                    Cell::new(&format!("fuzzy {:?} {}", config.sig, config.name)),
+                    Cell::new(&cell),
+                ]));
+            }
+        }
+
+        if let Some(rules) = &yara_rules {
+            for scan in rules.scan(&exe) {
+                let cell = if scan.matched {
+                    scan.rule
+                        .strings
+                        .iter()
+                        .zip(&scan.matches)
+                        .flat_map(|(s, matches)| {
+                            matches.iter().map(move |a| format!("{a:016x} {}", s.id))
+                        })
+                        .join("\n")
+                } else {
+                    "not matched".red().to_string()
+                };
+                table.add_row(Row::new(vec![
+                    Cell::new(&format!("yara {}", scan.rule.name)),
+                    Cell::new(&cell),
+                ]));
+            }
+        }
+
         let game_name = match game {
             GameEntry::File(GameFileEntry { name, .. }) => name.clone(),
             GameEntry::Process(GameProcessEntry { pid }) => format!("pid={pid}"),
@@ -0,0 +1,197 @@
+//! Derive macros for patternsleuth
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, LitInt};
+
+/// Derive `patternsleuth::read::MemoryRead` for a struct by laying out its fields in declaration
+/// order with C rules.
+///
+/// Field attributes:
+/// - `#[memory(offset = 0x10)]` places the field at a fixed offset instead of the next aligned one
+/// - `#[memory(utf8)]` / `#[memory(utf16)]` read a `String` field through a pointer to a null
+///   terminated string
+#[proc_macro_derive(MemoryRead, attributes(memory))]
+pub fn derive_memory_read(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+enum Kind {
+    Value,
+    Utf8,
+    Utf16,
+}
+
+struct Field {
+    member: syn::Member,
+    ty: syn::Type,
+    offset: Option<LitInt>,
+    kind: Kind,
+}
+
+fn parse_field(index: usize, field: &syn::Field) -> syn::Result<Field> {
+    let mut offset = None;
+    let mut kind = Kind::Value;
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("memory")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("offset") {
+                offset = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("utf8") {
+                kind = Kind::Utf8;
+            } else if meta.path.is_ident("utf16") {
+                kind = Kind::Utf16;
+            } else {
+                return Err(meta.error("expected `offset`, `utf8` or `utf16`"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(Field {
+        member: field
+            .ident
+            .clone()
+            .map(syn::Member::Named)
+            .unwrap_or_else(|| syn::Member::Unnamed(index.into())),
+        ty: field.ty.clone(),
+        offset,
+        kind,
+    })
+}
+
+fn expand(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    let krate = quote!(::patternsleuth);
+    let read = quote!(#krate::read);
+
+    let data = match &input.data {
+        Data::Struct(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "MemoryRead can only be derived for structs",
+            ))
+        }
+    };
+    let fields = data
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(i, f)| parse_field(i, f))
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    // (size, align) of each field
+    let layouts = fields
+        .iter()
+        .map(|f| match f.kind {
+            Kind::Value => {
+                let ty = &f.ty;
+                (
+                    quote!(<#ty as #read::MemoryRead>::SIZE),
+                    quote!(<#ty as #read::MemoryRead>::ALIGN),
+                )
+            }
+            Kind::Utf8 | Kind::Utf16 => (quote!(8usize), quote!(8usize)),
+        })
+        .collect::<Vec<_>>();
+
+    // offset of each field given the end of the previous field in `offset`
+    let offsets = fields
+        .iter()
+        .zip(&layouts)
+        .map(|(f, (_, align))| match &f.offset {
+            Some(fixed) => quote!(#fixed),
+            None => quote!(#read::align_up(offset, #align)),
+        })
+        .collect::<Vec<_>>();
+
+    let checks = fields.iter().map(|f| match &f.offset {
+        Some(fixed) => {
+            let message = format!("field offset {fixed} overlaps the previous field");
+            quote!(::core::assert!(offset <= #fixed, #message);)
+        }
+        None => quote!(),
+    });
+    let sizes = layouts.iter().map(|(size, _)| size);
+    let aligns = layouts.iter().map(|(_, align)| align);
+
+    let reads = fields
+        .iter()
+        .zip(&layouts)
+        .zip(&offsets)
+        .enumerate()
+        .map(|(i, ((f, (size, _)), offset))| {
+            let var = quote::format_ident!("field_{}", i);
+            let value = match f.kind {
+                Kind::Value => {
+                    let ty = &f.ty;
+                    quote!(<#ty as #read::MemoryRead>::read_from(memory, field)?)
+                }
+                Kind::Utf8 => quote!(#krate::MemoryTrait::read_string(
+                    memory,
+                    #krate::MemoryTrait::ptr(memory, field)?
+                )?),
+                Kind::Utf16 => quote!(#krate::MemoryTrait::read_wstring(
+                    memory,
+                    #krate::MemoryTrait::ptr(memory, field)?
+                )?),
+            };
+            quote! {
+                let offset = #offset;
+                let field = address
+                    .checked_add(offset)
+                    .ok_or(#krate::MemoryAccessError::MemoryOutOfBoundsError(address))?;
+                let #var = #value;
+                let offset = offset + #size;
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let vars = (0..fields.len()).map(|i| quote::format_ident!("field_{}", i));
+    let construct = match &data.fields {
+        Fields::Named(_) => {
+            let members = fields.iter().map(|f| &f.member);
+            quote!(Self { #(#members: #vars),* })
+        }
+        Fields::Unnamed(_) => quote!(Self(#(#vars),*)),
+        Fields::Unit => quote!(Self),
+    };
+
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#read::MemoryRead));
+    }
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        #[automatically_derived]
+        impl #impl_generics #read::MemoryRead for #name #ty_generics #where_clause {
+            const SIZE: usize = {
+                let offset = 0usize;
+                #(
+                    #checks
+                    let offset = #offsets + #sizes;
+                )*
+                #read::align_up(offset, <Self as #read::MemoryRead>::ALIGN)
+            };
+            const ALIGN: usize = {
+                let align = 1usize;
+                #(
+                    let align = #read::max(align, #aligns);
+                )*
+                align
+            };
+            #[allow(unused_variables)]
+            fn read_from<'data, M: #krate::MemoryTrait<'data> + ?Sized>(
+                memory: &M,
+                address: usize,
+            ) -> ::core::result::Result<Self, #krate::MemoryAccessError> {
+                let offset = 0usize;
+                #(#reads)*
+                ::core::result::Result::Ok(#construct)
+            }
+        }
+    })
+}
@@ -217,7 +217,10 @@ fn xref(c: &mut Criterion) {
         Xref(0x144F4D6D8),
     ];
 
-    let id_patterns = raw_patterns.iter().collect::<Vec<_>>();
+    let id_patterns = raw_patterns
+        .iter()
+        .map(|x| (XrefKind::Rel32, x))
+        .collect::<Vec<_>>();
 
     let mut group = c.benchmark_group("xref");
 
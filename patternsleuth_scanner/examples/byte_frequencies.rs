@@ -0,0 +1,76 @@
+//! Generate the x86-64 frequency tables in `src/frequency.rs` from the `.text` sections of the
+//! given binaries. Each binary is weighted equally regardless of size.
+//!
+//! The shipped tables were generated from `libLLVM.so.22.1-rust-1.95.0-stable` (C++) and
+//! `librustc_driver-6108105cd7e839cf.so` (Rust) of the Rust 1.95.0 x86_64-unknown-linux-gnu
+//! toolchain:
+//!
+//! ```bash
+//! LIB=~/.rustup/toolchains/1.95.0-x86_64-unknown-linux-gnu/lib
+//! cargo run --release --example byte_frequencies -- \
+//!     $LIB/libLLVM.so.22.1-rust-1.95.0-stable $LIB/librustc_driver-*.so > table.rs
+//! rustfmt --edition 2021 table.rs
+//! ```
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use object::{Object, ObjectSection};
+
+const SCALE: f64 = (1u64 << 24) as f64;
+
+fn main() -> Result<()> {
+    let paths = std::env::args().skip(1).collect::<Vec<_>>();
+    if paths.is_empty() {
+        anyhow::bail!("usage: byte_frequencies <binary>...");
+    }
+
+    let mut bytes = [0f64; 256];
+    let mut pairs: HashMap<u16, f64> = HashMap::new();
+    for path in &paths {
+        let file = std::fs::read(path).with_context(|| format!("reading {path}"))?;
+        let object = object::File::parse(&*file)?;
+        let data = object
+            .section_by_name(".text")
+            .with_context(|| format!("{path} has no .text section"))?
+            .data()?;
+
+        let mut counts = [0u64; 256];
+        let mut pair_counts = vec![0u64; 0x10000];
+        for b in data {
+            counts[*b as usize] += 1;
+        }
+        for w in data.windows(2) {
+            pair_counts[(w[0] as usize) << 8 | w[1] as usize] += 1;
+        }
+        let weight = paths.len() as f64;
+        for (f, n) in bytes.iter_mut().zip(counts) {
+            *f += n as f64 / data.len() as f64 / weight;
+        }
+        for (pair, n) in pair_counts.into_iter().enumerate().filter(|(_, n)| *n != 0) {
+            *pairs.entry(pair as u16).or_default() += n as f64 / (data.len() - 1) as f64 / weight;
+        }
+    }
+
+    println!("/// Occurrences of each byte per 2^24 bytes");
+    println!("const X86_64_BYTES: [u32; 256] = [");
+    for f in bytes {
+        println!("    {},", ((f * SCALE).round() as u32).max(1));
+    }
+    println!("];");
+
+    let mut pairs = pairs.into_iter().collect::<Vec<_>>();
+    pairs.sort_by(|a, b| b.1.total_cmp(&a.1));
+    println!("/// Occurrences per 2^24 pairs of the most common byte pairs");
+    println!("const X86_64_PAIRS: [(u8, u8, u32); 256] = [");
+    for (pair, f) in &pairs[..256] {
+        println!(
+            "    ({:#04x}, {:#04x}, {}),",
+            pair >> 8,
+            pair & 0xff,
+            (f * SCALE).round() as u32
+        );
+    }
+    println!("];");
+    Ok(())
+}
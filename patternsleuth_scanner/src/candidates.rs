@@ -0,0 +1,396 @@
+//! Single pass search for positions that may start any of a set of pattern anchors
+//!
+//! The widest of SSE2 and AVX2 supported by the CPU is used. Setting the `PATTERNSLEUTH_NO_SIMD`
+//! environment variable forces the scalar fallback, e.g. to compare results or performance. It is
+//! read once when the first [`crate::PatternSet`] is compiled.
+
+/// Filters a block of data for positions whose first bytes could start one of a set of pattern
+/// anchors. Candidates are always reported in ascending order.
+pub(crate) struct AnchorFilter {
+    first: ByteClass,
+    /// Checks of the bytes following the first byte, derived from the fixed 2 and 4 byte wide
+    /// anchor prefixes
+    prefixes: Vec<Prefix>,
+    level: Level,
+}
+
+/// The byte at `offset` into a candidate must be in `class` unless the first byte is in `skip`,
+/// i.e. the candidate may start an anchor which doesn't fix the byte at `offset`
+struct Prefix {
+    offset: usize,
+    class: ByteClass,
+    skip: ByteClass,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    Sse2,
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+}
+
+/// Max number of distinct bytes checked with a compare per byte in the SSE2 path
+#[cfg(target_arch = "x86_64")]
+const SSE2_MAX_BYTES: usize = 16;
+
+struct ByteClass {
+    bytes: Vec<u8>,
+    table: [bool; 256],
+    /// Nibble lookup tables (bucketed by high nibble) for pshufb classification. A byte `b` is
+    /// possibly in the class if `lo[b & 0xf] & hi[b >> 4] != 0`, which can report false positives
+    /// if more than 8 distinct high nibbles share buckets so candidates must be rechecked with
+    /// `table`.
+    #[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+    lo: [u8; 16],
+    #[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+    hi: [u8; 16],
+}
+
+impl ByteClass {
+    fn new(bytes: impl IntoIterator<Item = u8>) -> Self {
+        let mut table = [false; 256];
+        for b in bytes {
+            table[b as usize] = true;
+        }
+        let bytes = (0..=255u8)
+            .filter(|b| table[*b as usize])
+            .collect::<Vec<_>>();
+
+        let mut lo = [0; 16];
+        let mut hi = [0; 16];
+        let mut buckets = 0;
+        for h in 0..16 {
+            if !bytes.iter().any(|b| b >> 4 == h) {
+                continue;
+            }
+            let bit = 1 << (buckets % 8);
+            buckets += 1;
+            hi[h as usize] = bit;
+            for b in bytes.iter().filter(|b| *b >> 4 == h) {
+                lo[(b & 0xf) as usize] |= bit;
+            }
+        }
+
+        Self {
+            bytes,
+            table,
+            lo,
+            hi,
+        }
+    }
+    #[inline(always)]
+    fn contains(&self, b: u8) -> bool {
+        self.table[b as usize]
+    }
+}
+
+impl AnchorFilter {
+    /// Filter for anchors with only a first byte in `short` and anchors with a fixed `wide`
+    /// prefix of up to 4 bytes
+    pub(crate) fn new<'a>(
+        short: impl IntoIterator<Item = u8>,
+        wide: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Self {
+        let short = short.into_iter().collect::<Vec<_>>();
+        let wide = wide.into_iter().collect::<Vec<_>>();
+
+        let first = ByteClass::new(short.iter().copied().chain(wide.iter().map(|w| w[0])));
+        let prefixes = (1..4)
+            .map(|offset| Prefix {
+                offset,
+                class: ByteClass::new(wide.iter().filter_map(|w| w.get(offset).copied())),
+                skip: ByteClass::new(
+                    short
+                        .iter()
+                        .copied()
+                        .chain(wide.iter().filter(|w| w.len() <= offset).map(|w| w[0])),
+                ),
+            })
+            // drop checks which can't reject any candidate
+            .filter(|p| {
+                p.class.bytes.len() < 256 && first.bytes.iter().any(|b| !p.skip.contains(*b))
+            })
+            .collect::<Vec<_>>();
+
+        let level = Level::detect(
+            std::iter::once(&first).chain(prefixes.iter().flat_map(|p| [&p.class, &p.skip])),
+        );
+        Self {
+            first,
+            prefixes,
+            level,
+        }
+    }
+
+    /// Call `f` for every candidate position in `range` until it returns `false`. `data` must
+    /// extend at least as many bytes past `range.end` as the widest anchor prefix minus one.
+    #[inline(always)]
+    pub(crate) fn for_each_candidate<F: FnMut(usize) -> bool>(
+        &self,
+        data: &[u8],
+        range: std::ops::Range<usize>,
+        f: F,
+    ) {
+        match self.level {
+            Level::Scalar => self.scalar(data, range, f),
+            #[cfg(target_arch = "x86_64")]
+            Level::Sse2 => unsafe { self.sse2(data, range, f) },
+            #[cfg(target_arch = "x86_64")]
+            Level::Avx2 => unsafe { self.avx2(data, range, f) },
+        };
+    }
+
+    #[inline(always)]
+    fn is_candidate(&self, data: &[u8], i: usize) -> bool {
+        self.first.contains(data[i])
+            && self
+                .prefixes
+                .iter()
+                .all(|p| p.skip.contains(data[i]) || p.class.contains(data[i + p.offset]))
+    }
+
+    fn scalar<F: FnMut(usize) -> bool>(
+        &self,
+        data: &[u8],
+        range: std::ops::Range<usize>,
+        mut f: F,
+    ) -> bool {
+        let haystack = &data[range.clone()];
+        let mut report = |i: usize| {
+            let i = range.start + i;
+            !self.is_candidate(data, i) || f(i)
+        };
+        // memchr is still the fastest way to search for a small number of bytes
+        match self.first.bytes[..] {
+            [a] => memchr::memchr_iter(a, haystack).all(&mut report),
+            [a, b] => memchr::memchr2_iter(a, b, haystack).all(&mut report),
+            [a, b, c] => memchr::memchr3_iter(a, b, c, haystack).all(&mut report),
+            _ => (0..haystack.len()).all(report),
+        }
+    }
+
+    /// Number of bytes past the end of a block required to be readable
+    fn overlap(&self) -> usize {
+        self.prefixes.iter().map(|p| p.offset).max().unwrap_or(0)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "sse2")]
+    unsafe fn sse2<F: FnMut(usize) -> bool>(
+        &self,
+        data: &[u8],
+        range: std::ops::Range<usize>,
+        mut f: F,
+    ) -> bool {
+        use std::arch::x86_64::*;
+
+        const WIDTH: usize = 16;
+
+        unsafe fn classify(class: &ByteClass, v: __m128i) -> u32 {
+            let mut acc = _mm_setzero_si128();
+            for b in &class.bytes {
+                acc = _mm_or_si128(acc, _mm_cmpeq_epi8(v, _mm_set1_epi8(*b as i8)));
+            }
+            _mm_movemask_epi8(acc) as u32
+        }
+
+        let overlap = self.overlap();
+        let mut i = range.start;
+        while i + WIDTH <= range.end && i + WIDTH + overlap <= data.len() {
+            let ptr = data.as_ptr().add(i);
+            let v = _mm_loadu_si128(ptr as *const __m128i);
+            let mut bits = classify(&self.first, v);
+            for p in &self.prefixes {
+                if bits == 0 {
+                    break;
+                }
+                bits &= classify(&p.skip, v)
+                    | classify(
+                        &p.class,
+                        _mm_loadu_si128(ptr.add(p.offset) as *const __m128i),
+                    );
+            }
+            while bits != 0 {
+                if !f(i + bits.trailing_zeros() as usize) {
+                    return false;
+                }
+                bits &= bits - 1;
+            }
+            i += WIDTH;
+        }
+        self.scalar(data, i..range.end, f)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn avx2<F: FnMut(usize) -> bool>(
+        &self,
+        data: &[u8],
+        range: std::ops::Range<usize>,
+        mut f: F,
+    ) -> bool {
+        use std::arch::x86_64::*;
+
+        const WIDTH: usize = 32;
+
+        struct Tables {
+            lo: __m256i,
+            hi: __m256i,
+        }
+        #[target_feature(enable = "avx2")]
+        unsafe fn tables(class: &ByteClass) -> Tables {
+            Tables {
+                lo: _mm256_broadcastsi128_si256(_mm_loadu_si128(class.lo.as_ptr() as *const _)),
+                hi: _mm256_broadcastsi128_si256(_mm_loadu_si128(class.hi.as_ptr() as *const _)),
+            }
+        }
+        #[target_feature(enable = "avx2")]
+        unsafe fn classify(tables: &Tables, v: __m256i) -> u32 {
+            let nibble = _mm256_set1_epi8(0xf);
+            let lo = _mm256_and_si256(v, nibble);
+            let hi = _mm256_and_si256(_mm256_srli_epi16(v, 4), nibble);
+            let class = _mm256_and_si256(
+                _mm256_shuffle_epi8(tables.lo, lo),
+                _mm256_shuffle_epi8(tables.hi, hi),
+            );
+            !(_mm256_movemask_epi8(_mm256_cmpeq_epi8(class, _mm256_setzero_si256())) as u32)
+        }
+
+        let first = tables(&self.first);
+        let prefixes = self
+            .prefixes
+            .iter()
+            .map(|p| (p.offset, tables(&p.class), tables(&p.skip)))
+            .collect::<Vec<_>>();
+
+        let overlap = self.overlap();
+        let mut i = range.start;
+        while i + WIDTH <= range.end && i + WIDTH + overlap <= data.len() {
+            let ptr = data.as_ptr().add(i);
+            let v = _mm256_loadu_si256(ptr as *const __m256i);
+            let mut bits = classify(&first, v);
+            for (offset, class, skip) in &prefixes {
+                if bits == 0 {
+                    break;
+                }
+                bits &= classify(skip, v)
+                    | classify(
+                        class,
+                        _mm256_loadu_si256(ptr.add(*offset) as *const __m256i),
+                    );
+            }
+            while bits != 0 {
+                let j = i + bits.trailing_zeros() as usize;
+                // nibble buckets can produce false positives
+                if self.is_candidate(data, j) && !f(j) {
+                    return false;
+                }
+                bits &= bits - 1;
+            }
+            i += WIDTH;
+        }
+        self.scalar(data, i..range.end, f)
+    }
+}
+
+impl Level {
+    #[allow(unused_variables)]
+    fn detect<'a>(mut classes: impl Iterator<Item = &'a ByteClass>) -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            static NO_SIMD: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+            if *NO_SIMD.get_or_init(|| std::env::var_os("PATTERNSLEUTH_NO_SIMD").is_some()) {
+                return Level::Scalar;
+            }
+            if is_x86_feature_detected!("avx2") {
+                return Level::Avx2;
+            }
+            if classes.all(|c| c.bytes.len() <= SSE2_MAX_BYTES) {
+                return Level::Sse2;
+            }
+        }
+        Level::Scalar
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn candidates(filter: &AnchorFilter, data: &[u8], end: usize) -> Vec<usize> {
+        let mut res = vec![];
+        filter.for_each_candidate(data, 0..end, |i| {
+            res.push(i);
+            true
+        });
+        res
+    }
+
+    #[test]
+    fn test_anchor_filter() {
+        let data = (0..1000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect::<Vec<_>>();
+        let end = data.len() - 3;
+
+        let short_sets: [&[u8]; 5] = [
+            &[],
+            &[0x48],
+            &[0x00, 0x24, 0x48, 0xff],
+            &[
+                0x10, 0x21, 0x32, 0x43, 0x54, 0x65, 0x76, 0x87, 0x98, 0xa9, 0xba,
+            ],
+            &(0..=255).step_by(3).collect::<Vec<_>>(),
+        ];
+        // prefixes taken from the data so they are actually found
+        let wide_sets: [Vec<&[u8]>; 3] = [
+            vec![],
+            vec![&data[100..102], &data[200..202], &[0x48, 0x8b]],
+            vec![
+                &data[100..102],
+                &data[300..304],
+                &data[400..404],
+                &[0x48, 0x8b, 0, 0],
+            ],
+        ];
+        for short in short_sets {
+            for wide in &wide_sets {
+                if short.is_empty() && wide.is_empty() {
+                    continue;
+                }
+                let anchor_at = |i: usize| {
+                    short.contains(&data[i]) || wide.iter().any(|w| data[i..].starts_with(w))
+                };
+
+                let mut filter = AnchorFilter::new(short.iter().copied(), wide.iter().copied());
+                let expected = (0..end)
+                    .filter(|i| filter.is_candidate(&data, *i))
+                    .collect::<Vec<_>>();
+                assert!(
+                    (0..end)
+                        .filter(|i| anchor_at(*i))
+                        .all(|i| expected.contains(&i)),
+                    "anchor rejected"
+                );
+                if !wide.is_empty() {
+                    assert!(!filter.prefixes.is_empty());
+                }
+
+                let mut levels = vec![Level::Scalar];
+                #[cfg(target_arch = "x86_64")]
+                {
+                    levels.push(Level::Sse2);
+                    if is_x86_feature_detected!("avx2") {
+                        levels.push(Level::Avx2);
+                    }
+                }
+                for level in levels {
+                    filter.level = level;
+                    assert_eq!(expected, candidates(&filter, &data, end), "{level:?}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,328 @@
+//! Conversion of patterns to and from the signature formats used by other tools
+
+use std::fmt::Write;
+
+use anyhow::{bail, Context, Result};
+
+use crate::{Pattern, PatternSimple};
+
+/// Text format of a pattern
+#[derive(Debug, Default, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum PatternFormat {
+    /// Native syntax supporting captures, xrefs, jumps, etc. (`48 8B ?? | E8 [ ?? ?? ?? ?? ]`)
+    #[default]
+    Patternsleuth,
+    /// IDA, only whole byte wildcards (`48 8B ? ?`)
+    Ida,
+    /// x64dbg, nibble wildcards (`48 8B ?? 4?`)
+    X64dbg,
+    /// Cheat Engine AOB, nibble wildcards and `*` for a whole byte (`48 8B * 4?`)
+    CheatEngine,
+    /// Code-style signature and mask strings (`"\x48\x8B\x00", "xx?"`)
+    Code,
+}
+
+impl PatternFormat {
+    pub const ALL: [Self; 5] = [
+        Self::Patternsleuth,
+        Self::Ida,
+        Self::X64dbg,
+        Self::CheatEngine,
+        Self::Code,
+    ];
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Patternsleuth => "patternsleuth",
+            Self::Ida => "ida",
+            Self::X64dbg => "x64dbg",
+            Self::CheatEngine => "cheat-engine",
+            Self::Code => "code",
+        }
+    }
+    /// Whether the format can express wildcards for only one nibble of a byte
+    fn has_nibbles(self) -> bool {
+        matches!(self, Self::Patternsleuth | Self::X64dbg | Self::CheatEngine)
+    }
+}
+
+impl std::fmt::Display for PatternFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for PatternFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|f| f.name() == s)
+            .with_context(|| format!("unknown pattern format \"{s}\""))
+    }
+}
+
+impl Pattern {
+    /// Parse a pattern written in `format`
+    pub fn parse_format(s: &str, format: PatternFormat) -> Result<Self> {
+        let (sig, mask) = match format {
+            PatternFormat::Patternsleuth => return Self::new(s),
+            PatternFormat::Ida => parse_bytes(s, format, |w| match w {
+                "?" | "??" => Some((0, 0)),
+                _ => parse_nibbles(w, &['?']).filter(|(_, mask)| *mask == 0xff),
+            })?,
+            PatternFormat::X64dbg => parse_bytes(s, format, |w| parse_nibbles(w, &['?']))?,
+            PatternFormat::CheatEngine => parse_bytes(s, format, |w| match w {
+                "?" | "*" => Some((0, 0)),
+                _ => parse_nibbles(w, &['?', '*', 'x', 'X']),
+            })?,
+            PatternFormat::Code => parse_code(s)?,
+        };
+        if sig.is_empty() {
+            bail!("pattern must match at least one byte");
+        }
+        Ok(Self::from_simple(PatternSimple { sig, mask }))
+    }
+
+    /// Format the pattern for `format`, failing if the pattern uses features that cannot be
+    /// expressed in it
+    pub fn to_format(&self, format: PatternFormat) -> Result<String> {
+        // no text syntax in any format, same as the serde impls
+        let unsupported = [
+            (!self.constraints.is_empty(), "capture constraints"),
+            (self.limit.is_some(), "match limit"),
+            (self.align != 1, "alignment"),
+            (self.function_start, "function start"),
+        ];
+        if let Some((_, feature)) = unsupported.iter().find(|(used, _)| *used) {
+            bail!("{format} format cannot express {feature} in pattern {self}");
+        }
+        if format == PatternFormat::Patternsleuth {
+            return Ok(self.to_string());
+        }
+
+        let unsupported = [
+            (self.custom_offset != 0, "custom offset"),
+            (!self.captures.is_empty(), "captures"),
+            (!self.xrefs.is_empty(), "xrefs"),
+            (!self.jumps.is_empty(), "jumps"),
+            (!self.alternatives.is_empty(), "byte alternatives"),
+        ];
+        if let Some((_, feature)) = unsupported.iter().find(|(used, _)| *used) {
+            bail!("{format} format cannot express {feature} in pattern {self}");
+        }
+        for (i, (_, mask)) in self.simple.iter().enumerate() {
+            match mask {
+                0 | 0xff => {}
+                0x0f | 0xf0 if format.has_nibbles() => {}
+                0x0f | 0xf0 => {
+                    bail!(
+                        "{format} format cannot express nibble mask of byte {i} in pattern {self}"
+                    )
+                }
+                _ => bail!("{format} format cannot express bit mask of byte {i} in pattern {self}"),
+            }
+        }
+
+        let mut out = String::new();
+        if format == PatternFormat::Code {
+            out.push('"');
+            for (sig, _) in self.simple.iter() {
+                write!(out, "\\x{sig:02X}").unwrap();
+            }
+            out.push_str("\", \"");
+            for (_, mask) in self.simple.iter() {
+                out.push(if *mask == 0 { '?' } else { 'x' });
+            }
+            out.push('"');
+            return Ok(out);
+        }
+
+        let wildcard = match format {
+            PatternFormat::Ida => "?",
+            PatternFormat::CheatEngine => "*",
+            _ => "??",
+        };
+        for (i, (sig, mask)) in self.simple.iter().enumerate() {
+            if i != 0 {
+                out.push(' ');
+            }
+            match mask {
+                0 => out.push_str(wildcard),
+                0x0f => write!(out, "?{:X}", sig & 0xf).unwrap(),
+                0xf0 => write!(out, "{:X}?", sig >> 4).unwrap(),
+                _ => write!(out, "{sig:02X}").unwrap(),
+            }
+        }
+        Ok(out)
+    }
+
+    fn from_simple(simple: PatternSimple) -> Self {
+        Self {
+            simple,
+            custom_offset: 0,
+            captures: vec![],
+            xrefs: vec![],
+            jumps: vec![],
+            alternatives: vec![],
+            constraints: vec![],
+            limit: None,
+            align: 1,
+            function_start: false,
+        }
+    }
+}
+
+/// Parse whitespace separated bytes, or a single unseparated run of bytes
+fn parse_bytes(
+    s: &str,
+    format: PatternFormat,
+    parse: impl Fn(&str) -> Option<(u8, u8)>,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let words = s.split_whitespace().collect::<Vec<_>>();
+    let words = match words[..] {
+        [w] if w.len() > 2 && w.is_ascii() => (0..w.len())
+            .step_by(2)
+            .map(|i| w.get(i..i + 2).unwrap_or(&w[i..]))
+            .collect(),
+        _ => words,
+    };
+    words
+        .into_iter()
+        .map(|w| parse(w).with_context(|| format!("bad {format} pattern byte \"{w}\"")))
+        .collect()
+}
+
+/// Parse two character byte where each nibble is a hex digit or one of `wildcards`
+fn parse_nibbles(s: &str, wildcards: &[char]) -> Option<(u8, u8)> {
+    let mut chars = s.chars();
+    let (Some(hi), Some(lo), None) = (chars.next(), chars.next(), chars.next()) else {
+        return None;
+    };
+    let nibble = |c: char| match c.to_digit(16) {
+        Some(d) => Some((d as u8, 0xf)),
+        None => wildcards.contains(&c).then_some((0, 0)),
+    };
+    let (hi, lo) = (nibble(hi)?, nibble(lo)?);
+    Some((hi.0 << 4 | lo.0, hi.1 << 4 | lo.1))
+}
+
+/// Parse `"\x48\x8B\x00", "xx?"` style signature and mask strings
+fn parse_code(s: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+    let strings = s.split('"').skip(1).step_by(2).collect::<Vec<_>>();
+    let [sig, mask] = strings[..] else {
+        bail!("expected quoted signature and mask strings in code pattern {s}");
+    };
+
+    let mut bytes = vec![];
+    let mut chars = sig.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            if !c.is_ascii() {
+                bail!("non-ASCII character {c:?} in code pattern signature");
+            }
+            bytes.push(c as u8);
+            continue;
+        }
+        let byte = match chars.next() {
+            Some('x') => {
+                let mut digits = String::new();
+                while digits.len() < 2 && chars.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+                    digits.push(chars.next().unwrap());
+                }
+                u8::from_str_radix(&digits, 16)
+                    .with_context(|| format!("bad escape \\x{digits} in code pattern"))?
+            }
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            c => bail!("unsupported escape {c:?} in code pattern"),
+        };
+        bytes.push(byte);
+    }
+
+    if bytes.len() != mask.len() {
+        bail!(
+            "code pattern signature has {} bytes but mask has {} characters",
+            bytes.len(),
+            mask.len()
+        );
+    }
+    let mask = mask
+        .chars()
+        .map(|c| match c {
+            'x' | 'X' => Ok(0xff),
+            '?' | '.' => Ok(0),
+            _ => bail!("bad code pattern mask character {c:?}"),
+        })
+        .collect::<Result<Vec<u8>>>()?;
+    let sig = bytes.iter().zip(&mask).map(|(s, m)| s & m).collect();
+    Ok((sig, mask))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        let pattern = Pattern::new("48 8B ?? ?? E8").unwrap();
+        let formats = [
+            (PatternFormat::Patternsleuth, "48 8B ?? ?? E8"),
+            (PatternFormat::Ida, "48 8B ? ? E8"),
+            (PatternFormat::X64dbg, "48 8B ?? ?? E8"),
+            (PatternFormat::CheatEngine, "48 8B * * E8"),
+            (PatternFormat::Code, r#""\x48\x8B\x00\x00\xE8", "xx??x""#),
+        ];
+        for (format, s) in formats {
+            assert_eq!(s, pattern.to_format(format).unwrap(), "{format}");
+            assert_eq!(
+                pattern,
+                Pattern::parse_format(s, format).unwrap(),
+                "{format}"
+            );
+            assert_eq!(format, format.name().parse::<PatternFormat>().unwrap());
+        }
+
+        // alternative spellings
+        let alternatives = [
+            (PatternFormat::Ida, "48 8b ?? ? e8"),
+            (PatternFormat::X64dbg, "488B????E8"),
+            (PatternFormat::CheatEngine, "48 8B ?? xx E8"),
+            (PatternFormat::CheatEngine, "488B****E8"),
+            (
+                PatternFormat::Code,
+                r#"sig("\x48\x8b\0\xff\xe8") + mask("xx..x")"#,
+            ),
+        ];
+        for (format, s) in alternatives {
+            assert_eq!(pattern, Pattern::parse_format(s, format).unwrap(), "{s}");
+        }
+
+        let nibbles = Pattern::new("48 8? ?B").unwrap();
+        assert!(nibbles.to_format(PatternFormat::Ida).is_err());
+        assert!(nibbles.to_format(PatternFormat::Code).is_err());
+        assert!(Pattern::parse_format("48 8? ?B", PatternFormat::Ida).is_err());
+        for format in [PatternFormat::X64dbg, PatternFormat::CheatEngine] {
+            let s = nibbles.to_format(format).unwrap();
+            assert_eq!("48 8? ?B", s);
+            assert_eq!(nibbles, Pattern::parse_format(&s, format).unwrap());
+        }
+
+        let bits = Pattern::new("48 010?????").unwrap();
+        assert!(bits.to_format(PatternFormat::X64dbg).is_err());
+        let capture = Pattern::new("E8 [ ?? ?? ?? ?? ]").unwrap();
+        assert!(capture.to_format(PatternFormat::Ida).is_err());
+        let plain = Pattern::new("48 8B").unwrap();
+        for pattern in [
+            plain.clone().limit(1),
+            plain.clone().align(16),
+            plain.clone().function_start(),
+        ] {
+            for format in [PatternFormat::Patternsleuth, PatternFormat::Ida] {
+                assert!(pattern.to_format(format).is_err(), "{format}");
+            }
+        }
+
+        assert!(Pattern::parse_format(r#""\x48\x8B", "x""#, PatternFormat::Code).is_err());
+        assert!(Pattern::parse_format("48 8B ???", PatternFormat::X64dbg).is_err());
+    }
+}
@@ -0,0 +1,448 @@
+//! Byte frequency tables used to anchor patterns on their rarest bytes
+
+use std::sync::OnceLock;
+
+use crate::{PatternPair, PatternSimple, WIDE2};
+
+/// Fixed point scale of frequencies
+const SCALE: f64 = (1u64 << 24) as f64;
+
+/// Relative frequencies of bytes and byte pairs in the data to be scanned. Used by
+/// [`crate::PatternSet::with_frequencies`] to anchor each pattern on the run of fixed bytes least
+/// likely to occur so as few candidate positions as possible need to be verified.
+#[derive(Clone)]
+pub struct ByteFrequencies {
+    /// Occurrences of each byte per 2^24 bytes
+    bytes: [u32; 256],
+    /// Occurrences of each byte pair per 2^24 pairs indexed by `first << 8 | second`
+    pairs: Vec<u32>,
+}
+
+impl std::fmt::Debug for ByteFrequencies {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ByteFrequencies").finish_non_exhaustive()
+    }
+}
+
+impl ByteFrequencies {
+    /// Frequencies typical of x86-64 code, used by [`crate::PatternSet::new`]
+    pub fn x86_64() -> &'static Self {
+        static FREQUENCIES: OnceLock<ByteFrequencies> = OnceLock::new();
+        FREQUENCIES.get_or_init(|| {
+            // pairs not in the table are assumed independent but no more common than the least
+            // common pair in the table
+            let floor = X86_64_PAIRS.iter().map(|p| p.2).min().unwrap();
+            let mut pairs = (0..=0xffff)
+                .map(|i| {
+                    let estimate = X86_64_BYTES[i >> 8] as u64 * X86_64_BYTES[i & 0xff] as u64;
+                    ((estimate >> 24) as u32).min(floor)
+                })
+                .collect::<Vec<_>>();
+            for (a, b, n) in X86_64_PAIRS {
+                pairs[(a as usize) << 8 | b as usize] = n;
+            }
+            Self {
+                bytes: X86_64_BYTES,
+                pairs,
+            }
+        })
+    }
+    /// Count frequencies of `data`, e.g. the section about to be scanned
+    pub fn from_data(data: &[u8]) -> Self {
+        let mut bytes = [0u64; 256];
+        let mut pairs = vec![0u64; 0x10000];
+        for b in data {
+            bytes[*b as usize] += 1;
+        }
+        for w in data.windows(2) {
+            pairs[(w[0] as usize) << 8 | w[1] as usize] += 1;
+        }
+        let scale = |n: u64, total: usize| (n as f64 * SCALE / total.max(1) as f64) as u32;
+        Self {
+            bytes: bytes.map(|n| scale(n, data.len())),
+            pairs: pairs
+                .into_iter()
+                .map(|n| scale(n, data.len().saturating_sub(1)))
+                .collect(),
+        }
+    }
+    /// Probability of `b` occurring at any position
+    pub fn byte(&self, b: u8) -> f64 {
+        self.bytes[b as usize] as f64 / SCALE
+    }
+    /// Probability of `a` followed by `b` occurring at any position
+    pub fn pair(&self, a: u8, b: u8) -> f64 {
+        self.pairs[(a as usize) << 8 | b as usize] as f64 / SCALE
+    }
+}
+
+/// Anchor each pattern on the rarest run of fixed bytes before its first jump
+///
+/// Candidates are filtered by the first two bytes of the anchor and then looked up by up to four
+/// bytes so the cost of a run is mostly the frequency of its first pair with a smaller penalty
+/// for only being able to look up two bytes. Single fixed bytes also prevent filtering every
+/// candidate by its second byte so are only used if there is no run of two.
+pub(crate) fn group_patterns<'p>(
+    patterns: &[&'p crate::Pattern],
+    frequencies: &ByteFrequencies,
+) -> Vec<PatternPair<'p>> {
+    patterns
+        .iter()
+        .map(|p| {
+            let end = p.prefix_len();
+            let sig = &p.simple.sig;
+            let fixed = |i: usize| i < end && p.simple.mask[i] == 0xff;
+            let cost = |i: usize| {
+                if !fixed(i + 1) {
+                    4.0 * frequencies.byte(sig[i])
+                } else if (i + 2..i + WIDE2).all(fixed) {
+                    frequencies.pair(sig[i], sig[i + 1])
+                        * (1.0 + frequencies.pair(sig[i + 2], sig[i + 3]))
+                } else {
+                    2.0 * frequencies.pair(sig[i], sig[i + 1])
+                }
+            };
+            let pos = (0..end)
+                .filter(|i| fixed(*i))
+                .map(|i| (i, cost(i)))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(i, _)| i)
+                .unwrap_or_else(|| {
+                    // no fixed byte so anchor at the most specific masked byte
+                    p.simple
+                        .iter()
+                        .take(end)
+                        .enumerate()
+                        .rev()
+                        .max_by_key(|(_, (_, mask))| mask.count_ones())
+                        .map(|(i, _)| i)
+                        .unwrap()
+                });
+            PatternPair {
+                pattern: p,
+                partial: PatternSimple {
+                    sig: sig[pos..end].to_vec(),
+                    mask: p.simple.mask[pos..end].to_vec(),
+                },
+                offset: pos,
+            }
+        })
+        .collect()
+}
+
+// measured over the .text sections of libLLVM (C++) and librustc_driver (Rust) from the Rust 1.95.0
+// x86_64-unknown-linux-gnu toolchain, see examples/byte_frequencies.rs to regenerate
+
+/// Occurrences of each byte per 2^24 bytes
+const X86_64_BYTES: [u32; 256] = [
+    1986789, 373094, 110089, 81870, 119826, 73478, 38991, 38159, 192042, 39907, 23960, 18587,
+    41559, 35844, 17926, 533152, 154289, 40055, 12944, 11089, 29504, 94373, 12886, 11758, 83425,
+    10694, 12365, 9325, 19999, 14014, 12593, 23660, 92109, 34623, 9604, 10741, 610949, 12299, 9761,
+    9562, 65723, 33144, 9041, 11217, 18022, 12406, 20312, 11146, 63808, 97846, 10066, 12020, 22925,
+    20902, 9438, 20696, 53033, 102648, 10624, 33096, 35895, 33859, 13937, 17385, 100954, 273717,
+    32204, 34634, 197247, 90545, 39426, 37186, 1149203, 280531, 22979, 20611, 365066, 110682,
+    25920, 21846, 50234, 14080, 12610, 25321, 53946, 31842, 26907, 28632, 36999, 9045, 9128, 23541,
+    37637, 28241, 22901, 21867, 39551, 9070, 10643, 12742, 23621, 10485, 113924, 9882, 33148,
+    11233, 9048, 13158, 30839, 11918, 15142, 29634, 43770, 11268, 18004, 18429, 109075, 57798,
+    18861, 17903, 35477, 13124, 13370, 15641, 65188, 14405, 17975, 35328, 84601, 48348, 23142,
+    232324, 245785, 213472, 23788, 27882, 46052, 686147, 26036, 512409, 45160, 307737, 9828, 10882,
+    39116, 8705, 10394, 10203, 39419, 14254, 9550, 8876, 21272, 7529, 9830, 6974, 19161, 7714,
+    7872, 8108, 25187, 7117, 6997, 10269, 14314, 8971, 7112, 6938, 22711, 11342, 11063, 8024,
+    18756, 7373, 7159, 15407, 31922, 9029, 7667, 9621, 39083, 9682, 28327, 21718, 45444, 31076,
+    31664, 16344, 72836, 15547, 33705, 22680, 134754, 136168, 39673, 48281, 36342, 33701, 60422,
+    106256, 52774, 49151, 27446, 13789, 13402, 19842, 24802, 22381, 46289, 34328, 37900, 23765,
+    13691, 16980, 29928, 26090, 34237, 17703, 19445, 24488, 14110, 13443, 22948, 34919, 61593,
+    29014, 24632, 13818, 18149, 14533, 24631, 27582, 167108, 174869, 28539, 42772, 24040, 30052,
+    26893, 36697, 64086, 25725, 27961, 47434, 14635, 16676, 65869, 55274, 74401, 42420, 47073,
+    36134, 32301, 45503, 119973, 767302,
+];
+/// Occurrences per 2^24 pairs of the most common byte pairs
+const X86_64_PAIRS: [(u8, u8, u32); 256] = [
+    (0x00, 0x00, 1068595),
+    (0x00, 0x48, 290116),
+    (0x48, 0x89, 279389),
+    (0xff, 0xff, 252144),
+    (0x48, 0x8b, 249006),
+    (0x48, 0x8d, 207821),
+    (0x01, 0x00, 177410),
+    (0x4c, 0x89, 155211),
+    (0xff, 0x48, 117845),
+    (0x0f, 0x84, 116747),
+    (0x0f, 0x85, 99922),
+    (0x84, 0x24, 93855),
+    (0x44, 0x24, 93316),
+    (0x48, 0x83, 84057),
+    (0x00, 0x4c, 75532),
+    (0x4c, 0x8b, 73553),
+    (0x49, 0x89, 72498),
+    (0x02, 0x00, 67801),
+    (0xff, 0x15, 64108),
+    (0x00, 0x0f, 61800),
+    (0x49, 0x8b, 59682),
+    (0x00, 0x49, 59532),
+    (0xbc, 0x24, 58302),
+    (0x48, 0x85, 57620),
+    (0x08, 0x48, 51872),
+    (0x7c, 0x24, 51757),
+    (0x01, 0x48, 43553),
+    (0x24, 0x10, 43344),
+    (0xff, 0xe9, 42652),
+    (0x24, 0x08, 41352),
+    (0x03, 0x00, 40915),
+    (0x10, 0x48, 39749),
+    (0x4c, 0x24, 39705),
+    (0x4c, 0x8d, 39209),
+    (0x00, 0x41, 38583),
+    (0x66, 0x0f, 37972),
+    (0x8b, 0x44, 37352),
+    (0x8c, 0x24, 35863),
+    (0x74, 0x24, 35559),
+    (0x85, 0xc0, 34840),
+    (0x48, 0xc1, 33646),
+    (0x66, 0x66, 33511),
+    (0x24, 0x20, 33350),
+    (0xff, 0x4c, 33130),
+    (0xc0, 0x0f, 33114),
+    (0x04, 0x00, 32879),
+    (0xfe, 0xff, 32679),
+    (0x48, 0xc7, 31456),
+    (0x44, 0x89, 31130),
+    (0x48, 0x39, 31112),
+    (0x41, 0x0f, 30700),
+    (0xb4, 0x24, 30635),
+    (0x49, 0x83, 30576),
+    (0x4d, 0x89, 30132),
+    (0x54, 0x24, 29979),
+    (0x24, 0x18, 29584),
+    (0x8d, 0xbc, 29566),
+    (0x00, 0xe8, 29245),
+    (0x24, 0x30, 29150),
+    (0x89, 0x44, 29067),
+    (0xff, 0x0f, 27959),
+    (0xff, 0x49, 26769),
+    (0x89, 0x84, 26319),
+    (0x24, 0x48, 25818),
+    (0x08, 0x00, 25251),
+    (0x00, 0xff, 25133),
+    (0x24, 0x40, 25114),
+    (0x94, 0x24, 24504),
+    (0x0f, 0x83, 24175),
+    (0x8b, 0x84, 23819),
+    (0x24, 0x28, 23637),
+    (0x00, 0xe9, 22900),
+    (0x83, 0xf8, 22549),
+    (0x01, 0x0f, 22508),
+    (0x8b, 0x4c, 22417),
+    (0x8b, 0x7c, 22093),
+    (0x18, 0x48, 21981),
+    (0x45, 0x31, 21774),
+    (0x20, 0x48, 21754),
+    (0x24, 0x50, 21639),
+    (0x0f, 0x11, 20819),
+    (0x89, 0xdf, 20787),
+    (0xfe, 0x48, 20706),
+    (0x00, 0x66, 20440),
+    (0x8b, 0x74, 20353),
+    (0x00, 0x89, 20083),
+    (0x48, 0x01, 19806),
+    (0x24, 0x60, 19673),
+    (0x00, 0x4d, 19626),
+    (0x0f, 0xb6, 19263),
+    (0x89, 0xf7, 19079),
+    (0x24, 0x38, 18973),
+    (0x4d, 0x85, 18714),
+    (0x41, 0x89, 18572),
+    (0x6c, 0x24, 18368),
+    (0x0f, 0x6f, 18292),
+    (0xf3, 0x0f, 18190),
+    (0x00, 0x01, 18058),
+    (0x55, 0x41, 17990),
+    (0x4c, 0x39, 17839),
+    (0x00, 0x44, 17797),
+    (0x41, 0x8b, 17620),
+    (0x8d, 0x05, 17585),
+    (0x24, 0x70, 17567),
+    (0x8b, 0x54, 17555),
+    (0x8d, 0x3d, 17551),
+    (0x48, 0x0f, 17086),
+    (0x24, 0x80, 17002),
+    (0x49, 0x8d, 16864),
+    (0x5c, 0x24, 16652),
+    (0x4d, 0x8b, 16644),
+    (0x00, 0x8b, 16579),
+    (0x49, 0xc1, 16185),
+    (0x85, 0xc9, 16050),
+    (0x31, 0xc0, 16014),
+    (0x0f, 0x10, 15889),
+    (0x28, 0x48, 15805),
+    (0x8d, 0x15, 15690),
+    (0x30, 0x48, 15601),
+    (0x41, 0x83, 15560),
+    (0x80, 0x00, 15283),
+    (0x8d, 0x7c, 15185),
+    (0x24, 0x90, 15060),
+    (0x48, 0xff, 14917),
+    (0x31, 0xf6, 14825),
+    (0x0f, 0x7f, 14735),
+    (0x8d, 0x0d, 14513),
+    (0x24, 0x58, 14494),
+    (0x0f, 0x82, 14413),
+    (0x89, 0xc7, 14352),
+    (0xfd, 0xff, 14341),
+    (0x89, 0xc6, 14312),
+    (0x85, 0xff, 14108),
+    (0x83, 0xf9, 14008),
+    (0x85, 0xf6, 14004),
+    (0xfe, 0xe9, 13987),
+    (0x08, 0x4c, 13933),
+    (0x89, 0xff, 13866),
+    (0x48, 0x81, 13861),
+    (0x8d, 0xb4, 13821),
+    (0x00, 0x83, 13797),
+    (0x44, 0x8b, 13747),
+    (0x24, 0xa0, 13724),
+    (0x05, 0x00, 13676),
+    (0x8b, 0xbc, 13662),
+    (0xff, 0x41, 13444),
+    (0x31, 0xc9, 13373),
+    (0x02, 0x0f, 13234),
+    (0x89, 0xc3, 13162),
+    (0x24, 0xb0, 13125),
+    (0x8d, 0x04, 13046),
+    (0x0f, 0x87, 13035),
+    (0x06, 0x00, 13030),
+    (0x5b, 0x41, 12989),
+    (0x41, 0x5e, 12947),
+    (0x00, 0x31, 12905),
+    (0x49, 0x39, 12840),
+    (0x24, 0xd0, 12792),
+    (0x8b, 0x8c, 12701),
+    (0x89, 0xef, 12673),
+    (0x0f, 0xb7, 12622),
+    (0x41, 0x56, 12410),
+    (0x83, 0xc4, 12395),
+    (0xf0, 0xff, 12372),
+    (0x24, 0x68, 12367),
+    (0x00, 0x45, 12347),
+    (0x24, 0xc0, 12334),
+    (0x00, 0x80, 12270),
+    (0x89, 0xfe, 12254),
+    (0x24, 0x00, 12081),
+    (0xe0, 0x00, 11917),
+    (0x10, 0x00, 11895),
+    (0xc0, 0x48, 11883),
+    (0x5e, 0x41, 11754),
+    (0x41, 0x5f, 11734),
+    (0x40, 0x0f, 11659),
+    (0xf7, 0x48, 11631),
+    (0x9c, 0x24, 11630),
+    (0xb8, 0x00, 11621),
+    (0x0f, 0x1f, 11578),
+    (0x83, 0xc0, 11515),
+    (0x04, 0x24, 11488),
+    (0x89, 0x4c, 11469),
+    (0x40, 0x48, 11469),
+    (0xc0, 0x00, 11426),
+    (0xfa, 0x48, 11336),
+    (0x8d, 0x84, 11318),
+    (0xd0, 0x00, 11306),
+    (0x90, 0x00, 11304),
+    (0x41, 0x57, 11301),
+    (0xac, 0x24, 11274),
+    (0x57, 0x41, 11269),
+    (0x4d, 0x39, 11229),
+    (0x07, 0x00, 11132),
+    (0x53, 0x48, 11068),
+    (0x31, 0xd2, 10884),
+    (0x24, 0xf0, 10842),
+    (0x89, 0xe7, 10773),
+    (0x38, 0x48, 10755),
+    (0x24, 0xe0, 10713),
+    (0x89, 0xee, 10694),
+    (0x89, 0xf6, 10680),
+    (0x37, 0xff, 10677),
+    (0x84, 0x00, 10655),
+    (0x41, 0xff, 10654),
+    (0x08, 0x49, 10568),
+    (0x5c, 0x41, 10491),
+    (0x8b, 0x40, 10480),
+    (0x41, 0x5c, 10442),
+    (0x89, 0xc1, 10352),
+    (0xa0, 0x00, 10336),
+    (0x01, 0x4c, 10324),
+    (0xd8, 0x00, 10305),
+    (0x10, 0x4c, 10297),
+    (0x89, 0x8c, 10292),
+    (0x24, 0x78, 10220),
+    (0x89, 0xde, 10181),
+    (0xfc, 0xff, 10170),
+    (0xc1, 0xe0, 10139),
+    (0xc9, 0x0f, 10132),
+    (0x8b, 0x5c, 10128),
+    (0xf0, 0x48, 10078),
+    (0x56, 0x41, 10024),
+    (0x41, 0x54, 10017),
+    (0x88, 0x00, 9990),
+    (0x00, 0xf0, 9981),
+    (0xc3, 0x48, 9972),
+    (0x64, 0x24, 9942),
+    (0x84, 0xc0, 9939),
+    (0x01, 0xff, 9919),
+    (0x8b, 0xb4, 9902),
+    (0x54, 0x53, 9877),
+    (0xf6, 0x0f, 9779),
+    (0x83, 0xc7, 9766),
+    (0x0f, 0x86, 9758),
+    (0xc7, 0x84, 9738),
+    (0x24, 0x88, 9645),
+    (0x41, 0x80, 9538),
+    (0xc7, 0x44, 9535),
+    (0x89, 0x46, 9483),
+    (0x5f, 0x5d, 9427),
+    (0xd6, 0x00, 9426),
+    (0x10, 0x0f, 9364),
+    (0x5d, 0x41, 9270),
+    (0x49, 0xff, 9231),
+    (0x41, 0x5d, 9228),
+    (0x85, 0xd2, 9187),
+    (0x00, 0xf3, 9086),
+    (0x1f, 0x84, 9053),
+    (0x8b, 0x47, 9022),
+    (0x44, 0x0f, 9001),
+    (0xf6, 0xff, 8996),
+    (0x8b, 0x6c, 8976),
+    (0xb0, 0x00, 8956),
+    (0x41, 0x55, 8935),
+    (0x89, 0x54, 8915),
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Pattern;
+
+    fn anchors(patterns: &[&str], frequencies: &ByteFrequencies) -> Vec<usize> {
+        let patterns = patterns
+            .iter()
+            .map(|p| Pattern::new(p).unwrap())
+            .collect::<Vec<_>>();
+        group_patterns(&patterns.iter().collect::<Vec<_>>(), frequencies)
+            .into_iter()
+            .map(|p| p.offset)
+            .collect()
+    }
+
+    #[test]
+    fn test_from_data() {
+        let data = b"\xaa\xbb\xaa\xbb\xaa\xbb\x00\xcc\xdd";
+        assert_eq!(
+            vec![3],
+            anchors(&["aa bb ?? cc dd"], &ByteFrequencies::from_data(data))
+        );
+        let data = b"\xcc\xdd\xcc\xdd\xcc\xdd\x00\xaa\xbb";
+        assert_eq!(
+            vec![0],
+            anchors(&["aa bb ?? cc dd"], &ByteFrequencies::from_data(data))
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! Approximate matching allowing a number of mismatching bytes
+
+use crate::{Pattern, PatternSet};
+
+/// Match of a pattern with `mismatches` non-wildcard bytes differing from the data
+#[derive(Debug, Clone, Copy, Hash, Eq, Ord, PartialEq, PartialOrd)]
+pub struct FuzzyMatch {
+    pub address: usize,
+    pub mismatches: usize,
+}
+
+impl Pattern {
+    /// Match the pattern at `index` allowing up to `max_mismatches` mismatching bytes.
+    ///
+    /// Every non-wildcard byte, set of byte alternatives and xref which does not match counts as
+    /// one mismatch. Constraints must still hold. With jumps the shortest gaps within budget are
+    /// chosen, not the gaps with the fewest mismatches.
+    pub fn fuzzy_match(
+        &self,
+        data: &[u8],
+        base_address: usize,
+        index: usize,
+        max_mismatches: usize,
+    ) -> Option<FuzzyMatch> {
+        let mut gaps = Vec::with_capacity(self.jumps.len());
+        let mismatches =
+            self.fuzzy_segments(data, base_address, 0, index, max_mismatches, &mut gaps)?;
+        Some(FuzzyMatch {
+            address: base_address + index + self.gap_offset(&gaps, self.custom_offset, false),
+            mismatches,
+        })
+    }
+    /// Fuzzy version of `match_segments` returning the total mismatches of this and every
+    /// following segment
+    fn fuzzy_segments(
+        &self,
+        data: &[u8],
+        base_address: usize,
+        segment: usize,
+        index: usize,
+        budget: usize,
+        gaps: &mut Vec<usize>,
+    ) -> Option<usize> {
+        let start = segment
+            .checked_sub(1)
+            .map(|s| self.jumps[s].offset)
+            .unwrap_or(0);
+        let end = self
+            .jumps
+            .get(segment)
+            .map(|j| j.offset)
+            .unwrap_or(self.simple.len());
+        let mismatches = self.segment_mismatches(data, base_address, start..end, index, budget)?;
+        let Some(jump) = self.jumps.get(segment) else {
+            let match_index = index - start - gaps.iter().sum::<usize>();
+            return self
+                .is_constraint_match(data, base_address, match_index, gaps)
+                .then_some(mismatches);
+        };
+        for gap in jump.min..=jump.max {
+            gaps.push(gap);
+            if let Some(rest) = self.fuzzy_segments(
+                data,
+                base_address,
+                segment + 1,
+                index + end - start + gap,
+                budget - mismatches,
+                gaps,
+            ) {
+                return Some(mismatches + rest);
+            }
+            gaps.pop();
+        }
+        None
+    }
+    /// Number of mismatches of the fixed-width `range` of the pattern at `index` if within
+    /// `budget`
+    fn segment_mismatches(
+        &self,
+        data: &[u8],
+        base_address: usize,
+        range: std::ops::Range<usize>,
+        index: usize,
+        budget: usize,
+    ) -> Option<usize> {
+        let bytes = data.get(index..index + range.len())?;
+        let relative = |offset: usize| index + offset - range.start;
+
+        let mut mismatches = 0;
+        for (b, i) in bytes.iter().zip(range.clone()) {
+            // alternatives are counted once below
+            if b & self.simple.mask[i] != self.simple.sig[i]
+                && !self.alternatives.iter().any(|(offset, _)| *offset == i)
+            {
+                mismatches += 1;
+                if mismatches > budget {
+                    return None;
+                }
+            }
+        }
+        mismatches += self
+            .alternatives
+            .iter()
+            .filter(|(offset, _)| range.contains(offset))
+            .filter(|(offset, alts)| {
+                !alts
+                    .iter()
+                    .any(|(sig, mask)| data[relative(*offset)] & mask == *sig)
+            })
+            .count();
+        mismatches += self
+            .xrefs
+            .iter()
+            .filter(|(offset, _, _)| range.contains(offset))
+            .filter(|(offset, kind, xref)| {
+                !Self::is_xref_match(data, base_address, relative(*offset), *kind, xref)
+            })
+            .count();
+        (mismatches <= budget).then_some(mismatches)
+    }
+}
+
+impl PatternSet<'_> {
+    /// Scan `data` located at `base_address` for matches with up to `max_mismatches` mismatches,
+    /// see [`Pattern::fuzzy_match`].
+    ///
+    /// Unlike [`PatternSet::scan`] every position is checked against every pattern and match
+    /// limits and [`Pattern::function_start`] are ignored, so this is meant for finding sites a
+    /// pattern almost matches rather than for resolving.
+    pub fn scan_fuzzy(
+        &self,
+        base_address: usize,
+        data: &[u8],
+        max_mismatches: usize,
+    ) -> Vec<Vec<FuzzyMatch>> {
+        use rayon::prelude::*;
+
+        let mut bins = self.patterns.iter().map(|_| vec![]).collect::<Vec<_>>();
+
+        let matches = (0..data.len())
+            .into_par_iter()
+            .flat_map_iter(|i| {
+                self.patterns.iter().enumerate().filter_map(move |(pi, p)| {
+                    p.fuzzy_match(data, base_address, i, max_mismatches)
+                        .map(|m| (pi, m))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for (pi, m) in matches {
+            bins[pi].push(m);
+        }
+        bins
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fuzzy() {
+        let data = [
+            0x10, 0x48, 0x8b, 0x05, 0x00, 0xe8, 0x48, 0x89, 0x05, 0x01, 0xe9,
+        ];
+        let pattern = Pattern::new("48 8b 05 ?? | e8").unwrap();
+        let set = PatternSet::new(&[&pattern]);
+
+        let matches = |max| {
+            set.scan_fuzzy(0x1000, &data, max)
+                .into_iter()
+                .next()
+                .unwrap()
+                .into_iter()
+                .map(|m| (m.address, m.mismatches))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec![(0x1005, 0)], matches(0));
+        assert_eq!(vec![(0x1005, 0)], matches(1));
+        assert_eq!(vec![(0x1005, 0), (0x100a, 2)], matches(2));
+
+        // alternatives count once and jumps are followed
+        let pattern = Pattern::new("(48|49) 8b [0-2] e8").unwrap();
+        assert_eq!(
+            Some(FuzzyMatch {
+                address: 0x1001,
+                mismatches: 0
+            }),
+            pattern.fuzzy_match(&data, 0x1000, 1, 0)
+        );
+        let alternatives = Pattern::new("(4c|4d) 8b 05").unwrap();
+        assert_eq!(
+            Some(FuzzyMatch {
+                address: 0x1001,
+                mismatches: 1
+            }),
+            alternatives.fuzzy_match(&data, 0x1000, 1, 1)
+        );
+        assert_eq!(None, pattern.fuzzy_match(&data, 0x1000, 6, 1));
+        assert_eq!(
+            Some(FuzzyMatch {
+                address: 0x1006,
+                mismatches: 2
+            }),
+            pattern.fuzzy_match(&data, 0x1000, 6, 2)
+        );
+    }
+}
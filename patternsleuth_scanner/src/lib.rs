@@ -1,4 +1,15 @@
+mod candidates;
+mod format;
+mod frequency;
+mod fuzzy;
+mod search;
+mod stream;
+
 use anyhow::{bail, Context, Error, Result};
+use candidates::AnchorFilter;
+pub use format::PatternFormat;
+pub use frequency::ByteFrequencies;
+pub use fuzzy::FuzzyMatch;
 
 #[derive(Clone, Eq, PartialEq)]
 pub struct PatternSimple {
@@ -66,13 +77,132 @@ pub struct Pattern {
     pub simple: PatternSimple,
     pub custom_offset: usize,
     pub captures: Vec<std::ops::Range<usize>>,
-    pub xrefs: Vec<(usize, Xref)>,
+    pub xrefs: Vec<(usize, XrefKind, Xref)>,
+    pub jumps: Vec<Jump>,
+    pub alternatives: Vec<(usize, Vec<(u8, u8)>)>,
+    /// Constraints on captures (by capture index) which must hold for a match
+    pub constraints: Vec<(usize, Constraint)>,
+    /// Maximum number of matches to return, see [`Pattern::limit`]
+    pub limit: Option<usize>,
+    /// Required alignment of the match address, see [`Pattern::align`]
+    pub align: usize,
+    /// Whether the match address must be a function start, see [`Pattern::function_start`]
+    pub function_start: bool,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// Variable length gap of `min..=max` bytes inserted before byte `offset` of the pattern.
+///
+/// Offsets of captures, xrefs and `custom_offset` do not count skipped bytes. Positions that
+/// coincide with a jump refer to after the gap, except for capture ends which refer to before it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Jump {
+    pub offset: usize,
+    pub min: usize,
+    pub max: usize,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Capture<'data> {
     pub address: usize,
     pub data: &'data [u8],
+    /// Target of the capture if it were a rip-relative displacement (only for 4 byte captures)
+    pub rip: Option<usize>,
+}
+
+impl<'data> Capture<'data> {
+    fn new(address: usize, data: &'data [u8]) -> Self {
+        Self {
+            address,
+            data,
+            rip: rip(address, data),
+        }
+    }
+}
+
+fn rip(address: usize, data: &[u8]) -> Option<usize> {
+    <[u8; 4]>::try_from(data)
+        .ok()
+        .and_then(|d| (address + 4).checked_add_signed(i32::from_le_bytes(d) as isize))
+}
+
+/// Predicate on a capture which is evaluated by the scanner while matching
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Constraint {
+    /// Capture read as a little endian unsigned integer (1, 2, 4 or 8 bytes) must be within range
+    Value(std::ops::RangeInclusive<u64>),
+    /// rip-relative target of the capture must be inside one of the sorted, non-overlapping ranges
+    RipInRanges(Arc<[std::ops::Range<usize>]>),
+    /// rip-relative target of the capture must be one of the sorted addresses
+    RipInSet(Arc<[usize]>),
+}
+
+impl Constraint {
+    pub fn rip_in_ranges(ranges: impl IntoIterator<Item = std::ops::Range<usize>>) -> Self {
+        let mut ranges = ranges.into_iter().collect::<Vec<_>>();
+        ranges.sort_by_key(|r| r.start);
+        let mut merged: Vec<std::ops::Range<usize>> = vec![];
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        Self::RipInRanges(merged.into())
+    }
+    pub fn rip_in_set(addresses: impl IntoIterator<Item = usize>) -> Self {
+        let mut addresses = addresses.into_iter().collect::<Vec<_>>();
+        addresses.sort();
+        addresses.dedup();
+        Self::RipInSet(addresses.into())
+    }
+    fn is_match(&self, address: usize, data: &[u8]) -> bool {
+        match self {
+            Self::Value(range) => {
+                let value = match data.len() {
+                    1 => data[0] as u64,
+                    2 => u16::from_le_bytes(data.try_into().unwrap()) as u64,
+                    4 => u32::from_le_bytes(data.try_into().unwrap()) as u64,
+                    8 => u64::from_le_bytes(data.try_into().unwrap()),
+                    _ => return false,
+                };
+                range.contains(&value)
+            }
+            Self::RipInRanges(ranges) => rip(address, data)
+                .map(|target| {
+                    let i = ranges.partition_point(|r| r.end <= target);
+                    ranges.get(i).is_some_and(|r| r.contains(&target))
+                })
+                .unwrap_or(false),
+            Self::RipInSet(addresses) => rip(address, data)
+                .map(|target| addresses.binary_search(&target).is_ok())
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// A pattern match along with its captures
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Match<'data> {
+    /// Matched address including any custom offset
+    pub address: usize,
+    pub captures: Vec<Capture<'data>>,
+}
+
+/// Matches of a single pattern from a scan
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ScanMatches<T> {
+    pub matches: Vec<T>,
+    /// Whether the pattern has more matches than its limit which were discarded
+    pub limit_hit: bool,
+}
+
+impl<T> Default for ScanMatches<T> {
+    fn default() -> Self {
+        Self {
+            matches: vec![],
+            limit_hit: false,
+        }
+    }
 }
 
 impl TryFrom<String> for Pattern {
@@ -144,6 +274,37 @@ impl Pattern {
             .unwrap_or_else(|| s.parse())?)
     }
 
+    fn parse_byte_pattern(s: &str) -> Option<(u8, u8)> {
+        Self::parse_hex_pattern(s).or_else(|| Self::parse_binary_patern(s))
+    }
+
+    /// parse inner part of `[N-M]` or `[N]` jump
+    fn parse_jump(s: &str) -> Result<(usize, usize)> {
+        let (min, max) = match s.split_once('-') {
+            Some((min, max)) => (Self::parse_maybe_hex(min)?, Self::parse_maybe_hex(max)?),
+            None => {
+                let n = Self::parse_maybe_hex(s)?;
+                (n, n)
+            }
+        };
+        if min > max {
+            bail!("jump minimum is greater than maximum");
+        }
+        Ok((min, max))
+    }
+
+    /// parse inner part of `(A|B|..)` byte alternatives
+    fn parse_alternatives(s: &str) -> Result<Vec<(u8, u8)>> {
+        let alternatives = s
+            .split('|')
+            .map(|a| Self::parse_byte_pattern(a).with_context(|| format!("bad alternative {a}")))
+            .collect::<Result<Vec<_>>>()?;
+        if alternatives.len() < 2 {
+            bail!("expected at least two alternatives");
+        }
+        Ok(alternatives)
+    }
+
     pub fn new<S: AsRef<str>>(s: S) -> Result<Self> {
         let mut sig = vec![];
         let mut mask = vec![];
@@ -152,12 +313,12 @@ impl Pattern {
         let mut capture_stack = vec![];
         let mut captures = vec![];
         let mut xrefs = vec![];
+        let mut jumps = vec![];
+        let mut alternatives = vec![];
 
         let mut i = 0;
         for w in s.as_ref().split_whitespace() {
-            if let Some((s, m)) =
-                Self::parse_hex_pattern(w).or_else(|| Self::parse_binary_patern(w))
-            {
+            if let Some((s, m)) = Self::parse_byte_pattern(w) {
                 sig.push(s);
                 mask.push(m);
                 i += 1;
@@ -177,15 +338,39 @@ impl Pattern {
                         }
                     }
                     _ => {
-                        if let Some(xref) = w.strip_prefix('X').map(Self::parse_maybe_hex) {
-                            let xref =
-                                Xref(xref.with_context(|| format!("failed to parse xref {w}"))?);
-                            xrefs.push((sig.len(), xref));
-                            for _ in 0..4 {
+                        if let Some(jump) = w.strip_prefix('[').and_then(|w| w.strip_suffix(']')) {
+                            let (min, max) = Self::parse_jump(jump)
+                                .with_context(|| format!("failed to parse jump {w}"))?;
+                            jumps.push(Jump {
+                                offset: i,
+                                min,
+                                max,
+                            });
+                        } else if let Some(alts) =
+                            w.strip_prefix('(').and_then(|w| w.strip_suffix(')'))
+                        {
+                            let alts = Self::parse_alternatives(alts)
+                                .with_context(|| format!("failed to parse alternatives {w}"))?;
+                            // only bits shared by every alternative can be checked by the simple
+                            // pattern, the rest is verified by is_match
+                            let m = alts
+                                .iter()
+                                .fold(0xff, |m, (s, alt_m)| m & alt_m & !(s ^ alts[0].0));
+                            sig.push(alts[0].0 & m);
+                            mask.push(m);
+                            alternatives.push((i, alts));
+                            i += 1;
+                        } else if let Some((kind, xref)) = XrefKind::split_prefix(w) {
+                            let xref = Xref(
+                                Self::parse_maybe_hex(xref)
+                                    .with_context(|| format!("failed to parse xref {w}"))?,
+                            );
+                            xrefs.push((sig.len(), kind, xref));
+                            for _ in 0..kind.len() {
                                 sig.push(0);
                                 mask.push(0);
                             }
-                            i += 4;
+                            i += kind.len();
                         } else if w.starts_with("0x") {
                             sig.extend(u32::to_le_bytes(
                                 Self::parse_maybe_hex_u32(w)
@@ -206,14 +391,66 @@ impl Pattern {
         if sig.is_empty() {
             bail!("pattern must match at least one byte");
         }
+        if jumps.first().is_some_and(|j| j.offset == 0) {
+            bail!("pattern cannot start with a jump");
+        }
+        if jumps.last().is_some_and(|j| j.offset == sig.len()) {
+            bail!("pattern cannot end with a jump");
+        }
 
         Ok(Self {
             simple: PatternSimple { sig, mask },
             custom_offset,
             captures,
             xrefs,
+            jumps,
+            alternatives,
+            constraints: vec![],
+            limit: None,
+            align: 1,
+            function_start: false,
         })
     }
+    /// Add a constraint on capture at index `capture` that must hold for the pattern to match
+    pub fn constrain(mut self, capture: usize, constraint: Constraint) -> Result<Self> {
+        if capture >= self.captures.len() {
+            bail!(
+                "constraint on capture {capture} but pattern only has {} captures",
+                self.captures.len()
+            );
+        }
+        self.constraints.push((capture, constraint));
+        Ok(self)
+    }
+    /// Return at most `limit` matches (those at the lowest addresses). Scanning stops looking for
+    /// the pattern once it is known to have more matches which is reported by
+    /// [`ScanMatches::limit_hit`].
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+    /// Only return the first match, [`ScanMatches::limit_hit`] is set if the match is not unique
+    pub fn first_match(self) -> Self {
+        self.limit(1)
+    }
+    /// Only match at addresses (including any custom offset) which are a multiple of `align`
+    pub fn align(mut self, align: usize) -> Self {
+        self.align = align.max(1);
+        self
+    }
+    /// Only match at addresses (including any custom offset) which are the start of a function.
+    ///
+    /// **The scanner does not know where functions start.** This is only checked by a
+    /// [`PatternSet`] given the function starts with [`PatternSet::function_starts`] (including
+    /// [`PatternSet::scan_chunks`]). Everything else ignores it and returns matches which are not
+    /// function starts: a [`PatternSet`] without function starts, [`PatternSet::scan_fuzzy`],
+    /// [`Pattern::find_forward`], [`Pattern::find_backward`] and the single position checks
+    /// such as [`Pattern::is_match`]. `Image` and `AsyncContext` scans in `patternsleuth` supply
+    /// the function starts of the image.
+    pub fn function_start(mut self) -> Self {
+        self.function_start = true;
+        self
+    }
     /// Create a pattern from a literal `Vec<u8>` with `mask` filled with 0xff and `custom_offset = 0`.
     pub fn from_bytes(sig: Vec<u8>) -> Result<Self> {
         Ok(Self {
@@ -224,19 +461,169 @@ impl Pattern {
             custom_offset: 0,
             captures: vec![],
             xrefs: vec![],
+            jumps: vec![],
+            alternatives: vec![],
+            constraints: vec![],
+            limit: None,
+            align: 1,
+            function_start: false,
         })
     }
+    /// Minimum number of bytes spanned by a match
+    pub fn min_len(&self) -> usize {
+        self.simple.len() + self.jumps.iter().map(|j| j.min).sum::<usize>()
+    }
+    /// Maximum number of bytes spanned by a match
+    pub fn max_len(&self) -> usize {
+        self.simple.len() + self.jumps.iter().map(|j| j.max).sum::<usize>()
+    }
+    /// Length of the leading fixed-width part of the pattern (before the first jump)
+    pub fn prefix_len(&self) -> usize {
+        self.jumps
+            .first()
+            .map(|j| j.offset)
+            .unwrap_or(self.simple.len())
+    }
+    #[inline(always)]
+    fn is_xref_match(
+        data: &[u8],
+        base_address: usize,
+        index: usize,
+        kind: XrefKind,
+        xref: &Xref,
+    ) -> bool {
+        kind.resolve(data, base_address, index) == Some(xref.0)
+    }
     #[inline(always)]
     pub fn is_match(&self, data: &[u8], base_address: usize, index: usize) -> bool {
+        if !self.jumps.is_empty() {
+            return self.match_gaps(data, base_address, index).is_some();
+        }
         self.simple.is_match(data, index)
-            && self.xrefs.iter().all(|(offset, xref)| {
-                (base_address + index + offset + 4)
-                    .checked_add_signed(i32::from_le_bytes(
-                        data[index + offset..index + offset + 4].try_into().unwrap(),
-                    ) as isize)
-                    .map(|x| x == xref.0)
-                    .unwrap_or(false)
+            && self.alternatives.iter().all(|(offset, alts)| {
+                alts.iter()
+                    .any(|(sig, mask)| data[index + offset] & mask == *sig)
+            })
+            && self.xrefs.iter().all(|(offset, kind, xref)| {
+                Self::is_xref_match(data, base_address, index + offset, *kind, xref)
             })
+            && self.is_constraint_match(data, base_address, index, &[])
+    }
+    /// Check constraints for a match at `index` with the given jump gaps
+    fn is_constraint_match(
+        &self,
+        data: &[u8],
+        base_address: usize,
+        index: usize,
+        gaps: &[usize],
+    ) -> bool {
+        self.constraints.iter().all(|(capture, constraint)| {
+            let range = self.capture_range(gaps, index, &self.captures[*capture]);
+            constraint.is_match(base_address + range.start, &data[range])
+        })
+    }
+    /// Match the pattern at `index` and return the length chosen for each jump (shortest first)
+    fn match_gaps(&self, data: &[u8], base_address: usize, index: usize) -> Option<Vec<usize>> {
+        if self.jumps.is_empty() {
+            return self.is_match(data, base_address, index).then(Vec::new);
+        }
+        let mut gaps = Vec::with_capacity(self.jumps.len());
+        self.match_segments(data, base_address, 0, index, &mut gaps)
+            .then_some(gaps)
+    }
+    fn match_segments(
+        &self,
+        data: &[u8],
+        base_address: usize,
+        segment: usize,
+        index: usize,
+        gaps: &mut Vec<usize>,
+    ) -> bool {
+        let start = segment
+            .checked_sub(1)
+            .map(|s| self.jumps[s].offset)
+            .unwrap_or(0);
+        let end = self
+            .jumps
+            .get(segment)
+            .map(|j| j.offset)
+            .unwrap_or(self.simple.len());
+        if !self.is_segment_match(data, base_address, start..end, index) {
+            return false;
+        }
+        let Some(jump) = self.jumps.get(segment) else {
+            // constraints are checked last so a failing capture can backtrack to other gaps
+            let match_index = index - start - gaps.iter().sum::<usize>();
+            return self.is_constraint_match(data, base_address, match_index, gaps);
+        };
+        for gap in jump.min..=jump.max {
+            gaps.push(gap);
+            if self.match_segments(
+                data,
+                base_address,
+                segment + 1,
+                index + end - start + gap,
+                gaps,
+            ) {
+                return true;
+            }
+            gaps.pop();
+        }
+        false
+    }
+    /// Bounds checked match of the fixed-width `range` of the pattern at `index`
+    fn is_segment_match(
+        &self,
+        data: &[u8],
+        base_address: usize,
+        range: std::ops::Range<usize>,
+        index: usize,
+    ) -> bool {
+        let Some(bytes) = data.get(index..index + range.len()) else {
+            return false;
+        };
+        let relative = |offset: usize| index + offset - range.start;
+        bytes
+            .iter()
+            .zip(range.clone())
+            .all(|(b, i)| b & self.simple.mask[i] == self.simple.sig[i])
+            && self
+                .alternatives
+                .iter()
+                .filter(|(offset, _)| range.contains(offset))
+                .all(|(offset, alts)| {
+                    alts.iter()
+                        .any(|(sig, mask)| data[relative(*offset)] & mask == *sig)
+                })
+            && self
+                .xrefs
+                .iter()
+                .filter(|(offset, _, _)| range.contains(offset))
+                .all(|(offset, kind, xref)| {
+                    Self::is_xref_match(data, base_address, relative(*offset), *kind, xref)
+                })
+    }
+    /// Range of data covered by capture for a match at `index`
+    fn capture_range(
+        &self,
+        gaps: &[usize],
+        index: usize,
+        capture: &std::ops::Range<usize>,
+    ) -> std::ops::Range<usize> {
+        let start = index + self.gap_offset(gaps, capture.start, false);
+        let end = (index + self.gap_offset(gaps, capture.end, true)).max(start);
+        start..end
+    }
+    /// Translate an offset into the pattern to an offset from the match start
+    fn gap_offset(&self, gaps: &[usize], offset: usize, end: bool) -> usize {
+        offset
+            + self
+                .jumps
+                .iter()
+                .zip(gaps)
+                .take_while(|(j, _)| j.offset < offset || (!end && j.offset == offset))
+                .map(|(_, gap)| gap)
+                .sum::<usize>()
     }
     pub fn captures<'data>(
         &self,
@@ -244,44 +631,162 @@ impl Pattern {
         base_address: usize,
         index: usize,
     ) -> Option<Vec<Capture<'data>>> {
-        self.is_match(data, base_address, index).then(|| {
-            self.captures
+        self.match_captures(data, base_address, index)
+            .map(|m| m.captures)
+    }
+    /// is_match, compute_result and captures in a single pass
+    pub fn match_captures<'data>(
+        &self,
+        data: &'data [u8],
+        base_address: usize,
+        index: usize,
+    ) -> Option<Match<'data>> {
+        let gaps = self.match_gaps(data, base_address, index)?;
+        let address = base_address + index + self.gap_offset(&gaps, self.custom_offset, false);
+        self.is_aligned(address).then(|| Match {
+            address,
+            captures: self
+                .captures
                 .iter()
-                .map(|c| Capture {
-                    address: base_address + index + c.start,
-                    data: &data[c.start + index..c.end + index],
+                .map(|c| {
+                    let range = self.capture_range(&gaps, index, c);
+                    Capture::new(base_address + range.start, &data[range])
                 })
-                .collect()
+                .collect(),
         })
     }
     /// compute virtual address from address relative to section as well as account for
     /// custom_offset
-    pub fn compute_result(&self, _data: &[u8], base_address: usize, index: usize) -> usize {
-        base_address + index + self.custom_offset
+    pub fn compute_result(&self, data: &[u8], base_address: usize, index: usize) -> usize {
+        let gaps = if self.jumps.is_empty() {
+            vec![]
+        } else {
+            self.match_gaps(data, base_address, index)
+                .unwrap_or_default()
+        };
+        base_address + index + self.gap_offset(&gaps, self.custom_offset, false)
+    }
+    /// is_match and compute_result in a single pass
+    #[inline(always)]
+    fn match_result(&self, data: &[u8], base_address: usize, index: usize) -> Option<usize> {
+        if self.jumps.is_empty() {
+            self.is_match(data, base_address, index)
+                .then(|| base_address + index + self.custom_offset)
+        } else {
+            self.match_gaps(data, base_address, index).map(|gaps| {
+                base_address + index + self.gap_offset(&gaps, self.custom_offset, false)
+            })
+        }
+        .filter(|address| self.is_aligned(*address))
+    }
+    #[inline(always)]
+    fn is_aligned(&self, address: usize) -> bool {
+        address.is_multiple_of(self.align)
     }
 }
 
+/// Single byte of a pattern formatted as in pattern syntax
+struct PatternByte(u8, u8);
+impl Display for PatternByte {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_byte(f, self.0, self.1)
+    }
+}
+
+/// Formats the pattern such that it can be parsed back by [`Pattern::new`] into an identical
+/// pattern, excluding constraints, limit, alignment and [`Pattern::function_start`] which have no
+/// text syntax
 impl Display for Pattern {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:02X}", self.simple.sig[0])?;
-        let mut iter = self.simple.iter().enumerate().skip(1);
-        while let Some((i, (sig, mask))) = iter.next() {
-            if i == self.custom_offset {
-                write!(f, " |")?;
+        let mut words: Vec<String> = vec![];
+        let len = self.simple.len();
+        let mut i = 0;
+        loop {
+            // captures are pushed as they are closed so close in order and open in reverse,
+            // empty captures are closed along with the others to keep their order
+            for capture in self.captures.iter() {
+                if capture.end == i {
+                    if capture.start == i {
+                        words.extend(["[".into(), "]".into()]);
+                    } else {
+                        words.push("]".into());
+                    }
+                }
             }
-            write!(f, " ")?;
-            if *mask == 0 {
-                if let Some((_offset, xref)) =
-                    self.xrefs.iter().find(|(offset, _xref)| *offset == i)
-                {
-                    write!(f, "X0x{:X}", xref.0)?;
-                    iter.nth(2); // skip 3
-                    continue;
+            for jump in self.jumps.iter().filter(|j| j.offset == i) {
+                if jump.min == jump.max {
+                    words.push(format!("[{}]", jump.min));
+                } else {
+                    words.push(format!("[{}-{}]", jump.min, jump.max));
                 }
             }
-            fmt_byte(f, *sig, *mask)?;
+            if i == self.custom_offset && i != 0 {
+                words.push("|".into());
+            }
+            for capture in self.captures.iter().rev() {
+                if capture.start == i && capture.end != i {
+                    words.push("[".into());
+                }
+            }
+
+            if i == len {
+                break;
+            }
+
+            let (sig, mask) = (self.simple.sig[i], self.simple.mask[i]);
+            if let Some((_offset, kind, xref)) = self
+                .xrefs
+                .iter()
+                .find(|(offset, _, _)| *offset == i && mask == 0)
+            {
+                words.push(format!("{}0x{:X}", kind.prefix(), xref.0));
+                i += kind.len();
+                continue;
+            }
+            if let Some((_offset, alts)) = self.alternatives.iter().find(|(offset, _)| *offset == i)
+            {
+                let alts = alts
+                    .iter()
+                    .map(|(sig, mask)| PatternByte(*sig, *mask).to_string())
+                    .collect::<Vec<_>>();
+                words.push(format!("({})", alts.join("|")));
+            } else {
+                words.push(PatternByte(sig, mask).to_string());
+            }
+            i += 1;
         }
-        Ok(())
+        write!(f, "{}", words.join(" "))
+    }
+}
+impl std::str::FromStr for Pattern {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Self::new(s)
+    }
+}
+
+/// Serialized as pattern syntax, fails for patterns with constraints, a limit, an alignment or
+/// [`Pattern::function_start`] as they cannot be represented
+#[cfg(feature = "serde")]
+impl serde::Serialize for Pattern {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !self.constraints.is_empty()
+            || self.limit.is_some()
+            || self.align != 1
+            || self.function_start
+        {
+            return Err(serde::ser::Error::custom(format!(
+                "cannot serialize constraints, limit, alignment or function start of pattern {self}"
+            )));
+        }
+        serializer.collect_str(self)
+    }
+}
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Pattern {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Self::new(s).map_err(serde::de::Error::custom)
     }
 }
 impl std::fmt::Debug for Pattern {
@@ -291,12 +796,73 @@ impl std::fmt::Debug for Pattern {
 }
 
 #[derive(Debug, Clone, Copy, Hash, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Xref(pub usize);
 
-use std::{
-    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
-    fmt::Display,
-};
+/// Encoding of a reference to an [`Xref`] address
+#[derive(Debug, Default, Clone, Copy, Hash, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum XrefKind {
+    /// 32-bit displacement relative to the end of the displacement (`X0x...`)
+    #[default]
+    Rel32,
+    /// 8-bit displacement relative to the end of the displacement as used by short jumps
+    /// (`S0x...`)
+    Rel8,
+    /// 32-bit absolute immediate (`I0x...`)
+    Abs32,
+    /// 64-bit absolute pointer (`A0x...`)
+    Abs64,
+}
+
+impl XrefKind {
+    pub const ALL: [XrefKind; 4] = [Self::Rel32, Self::Rel8, Self::Abs32, Self::Abs64];
+
+    /// Number of bytes occupied by the reference
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(self) -> usize {
+        match self {
+            Self::Rel32 => 4,
+            Self::Rel8 => 1,
+            Self::Abs32 => 4,
+            Self::Abs64 => 8,
+        }
+    }
+    /// Pattern token prefix
+    pub fn prefix(self) -> char {
+        match self {
+            Self::Rel32 => 'X',
+            Self::Rel8 => 'S',
+            Self::Abs32 => 'I',
+            Self::Abs64 => 'A',
+        }
+    }
+    /// Split a token such as `A0x1234` into its kind and address
+    pub fn split_prefix(s: &str) -> Option<(Self, &str)> {
+        let mut chars = s.chars();
+        let prefix = chars.next()?;
+        Self::ALL
+            .into_iter()
+            .find(|k| k.prefix() == prefix)
+            .map(|k| (k, chars.as_str()))
+    }
+    /// Address referenced by the data at `index` if it were encoded as this kind
+    #[inline(always)]
+    pub fn resolve(self, data: &[u8], base_address: usize, index: usize) -> Option<usize> {
+        let bytes = data.get(index..index + self.len())?;
+        let end = base_address + index + self.len();
+        match self {
+            Self::Rel32 => {
+                end.checked_add_signed(i32::from_le_bytes(bytes.try_into().unwrap()) as isize)
+            }
+            Self::Rel8 => end.checked_add_signed(bytes[0] as i8 as isize),
+            Self::Abs32 => Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize),
+            Self::Abs64 => usize::try_from(u64::from_le_bytes(bytes.try_into().unwrap())).ok(),
+        }
+    }
+}
+
+use std::{collections::HashMap, fmt::Display, sync::Arc};
 
 #[derive(Debug, Eq, PartialEq)]
 struct PatternPair<'p> {
@@ -306,224 +872,330 @@ struct PatternPair<'p> {
 }
 impl PatternPair<'_> {
     #[inline(always)]
-    fn add_match(
+    fn add_match<T>(
         &self,
         data: &[u8],
-        base_address: usize,
         offset: usize,
         pattern_index: usize,
-        matches: &mut Vec<(usize, usize)>,
-    ) {
-        if self.partial.is_match(data, offset)
-            && offset >= self.offset
-            && self
-                .pattern
-                .is_match(data, base_address, offset - self.offset)
-        {
-            matches.push((
-                pattern_index,
-                self.pattern
-                    .compute_result(data, base_address, offset - self.offset),
-            ));
+        matches: &mut Vec<(usize, T)>,
+        f: &impl Fn(&Pattern, usize) -> Option<T>,
+    ) -> bool {
+        if self.partial.is_match(data, offset) && offset >= self.offset {
+            if let Some(result) = f(self.pattern, offset - self.offset) {
+                matches.push((pattern_index, result));
+                return true;
+            }
         }
+        false
     }
 }
 
-fn group_patterns<'p>(patterns: &[&'p Pattern]) -> Vec<PatternPair<'p>> {
-    let mut pattern_pairs: Vec<Option<PatternPair>> = patterns.iter().map(|_| None).collect();
-
-    // common first bytes that should be avoided if possible
-    let bans = [0x00, 0x24, 0x48, 0xff];
+const WIDE1: usize = 2;
+const WIDE2: usize = 4;
+
+/// A set of patterns compiled once and scanned together, similar to `regex::RegexSet`
+///
+/// Compiling picks and bins an anchor for each pattern which is relatively expensive so reuse the
+/// set when scanning many blocks of memory with the same patterns. Cloning shares the compiled
+/// anchors, e.g. to give each scanned image its own [`PatternSet::function_starts`].
+///
+/// **[`Pattern::function_start`] is not checked unless the set is given function starts**, see
+/// [`PatternSet::function_starts`].
+#[derive(Clone)]
+pub struct PatternSet<'p> {
+    patterns: Vec<&'p Pattern>,
+    pattern_pairs: Arc<[PatternPair<'p>]>,
+    filter: Arc<AnchorFilter>,
+    short_bins: Arc<HashMap<u8, Vec<usize>>>,
+    wide1_bins: Arc<HashMap<[u8; WIDE1], Vec<usize>>>,
+    wide2_bins: Arc<HashMap<[u8; WIDE2], Vec<usize>>>,
+    max: usize,
+    /// Sorted function starts to check [`Pattern::function_start`] against
+    function_starts: Option<Arc<[usize]>>,
+}
 
-    #[derive(Debug, Default, Eq, PartialEq)]
-    struct ByteSelector {
-        is_banned: bool,
-        position_score: usize,
-        pattern_indexes: BTreeSet<usize>,
-    }
-    impl std::cmp::PartialOrd for ByteSelector {
-        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-            Some(self.cmp(other))
-        }
+impl<'p> PatternSet<'p> {
+    /// Compile `patterns` anchored for scanning x86-64 code
+    pub fn new(patterns: &[&'p Pattern]) -> Self {
+        Self::with_frequencies(patterns, ByteFrequencies::x86_64())
     }
-    impl std::cmp::Ord for ByteSelector {
-        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-            self.is_banned
-                .cmp(&other.is_banned)
-                .reverse()
-                .then(self.pattern_indexes.len().cmp(&other.pattern_indexes.len()))
-                .then(self.position_score.cmp(&other.position_score).reverse())
-        }
-    }
-
-    let mut patterns: Vec<Option<&Pattern>> = patterns.iter().map(|p| Some(*p)).collect();
-    while patterns.iter().any(Option::is_some) {
-        let mut counts: BTreeMap<u8, ByteSelector> = Default::default();
-        for (pi, p) in patterns
-            .iter()
-            .enumerate()
-            .filter_map(|(i, p)| p.map(|p| (i, &p.simple)))
-        {
-            let unique = p
-                .iter()
-                .enumerate()
-                .filter_map(|(i, (sig, mask))| (*mask == 0xff).then_some((*sig, i)))
-                .rev()
-                .collect::<HashMap<u8, usize>>();
-            for (u, i) in unique {
-                let counts = counts.entry(u).or_insert_with(|| ByteSelector {
-                    is_banned: bans.contains(&u),
-                    ..Default::default()
-                });
-                // TODO potentially score by distance from end instead of distance from start
-                counts.position_score += i;
-                counts.pattern_indexes.insert(pi);
-            }
-        }
-        let (
-            max_key,
-            ByteSelector {
-                pattern_indexes, ..
-            },
-        ) = counts.iter().max_by_key(|a| a.1).unwrap();
-        for (i, p) in patterns.iter().enumerate() {
-            if let Some(p) = p {
-                if pattern_indexes.contains(&i) {
-                    let pos = p
-                        .simple
-                        .iter()
-                        .enumerate()
-                        .find_map(|(i, (sig, mask))| (sig == max_key && *mask == 0xff).then_some(i))
-                        .unwrap();
-
-                    pattern_pairs[i] = Some(PatternPair {
-                        pattern: p,
-                        partial: PatternSimple {
-                            sig: p.simple.sig[pos..].to_vec(),
-                            mask: p.simple.mask[pos..].to_vec(),
-                        },
-                        offset: pos,
-                    });
+    /// Compile `patterns` anchored on the bytes least frequent according to `frequencies`, e.g.
+    /// [`ByteFrequencies::from_data`] of the data about to be scanned
+    pub fn with_frequencies(patterns: &[&'p Pattern], frequencies: &ByteFrequencies) -> Self {
+        let pattern_pairs = frequency::group_patterns(patterns, frequencies);
+
+        let mut short_bins: HashMap<u8, Vec<_>> = Default::default();
+        let mut wide1_bins: HashMap<[u8; WIDE1], Vec<_>> = Default::default();
+        let mut wide2_bins: HashMap<[u8; WIDE2], Vec<_>> = Default::default();
+        for (pi, pair) in pattern_pairs.iter().enumerate() {
+            let p = &pair.partial;
+
+            if p.mask.iter().take(WIDE2).filter(|m| **m == 0xff).count() == WIDE2 {
+                let mut buf = [0; WIDE2];
+                buf.copy_from_slice(&p.sig[0..WIDE2]);
+                wide2_bins.entry(buf).or_default().push(pi);
+            } else if p.mask.iter().take(WIDE1).filter(|m| **m == 0xff).count() == WIDE1 {
+                let mut buf = [0; WIDE1];
+                buf.copy_from_slice(&p.sig[0..WIDE1]);
+                wide1_bins.entry(buf).or_default().push(pi);
+            } else {
+                // anchors without a fixed byte are binned under every byte they match
+                for b in (0..=255).filter(|b| b & p.mask[0] == p.sig[0]) {
+                    short_bins.entry(b).or_default().push(pi);
                 }
             }
         }
 
-        for take in pattern_indexes {
-            patterns[*take].take();
+        let filter = AnchorFilter::new(
+            short_bins.keys().copied(),
+            wide1_bins
+                .keys()
+                .map(|k| &k[..])
+                .chain(wide2_bins.keys().map(|k| &k[..])),
+        );
+
+        Self {
+            patterns: patterns.to_vec(),
+            filter: filter.into(),
+            pattern_pairs: pattern_pairs.into(),
+            short_bins: short_bins.into(),
+            wide1_bins: wide1_bins.into(),
+            wide2_bins: wide2_bins.into(),
+            max: patterns.iter().map(|p| p.simple.len()).max().unwrap_or(0),
+            function_starts: None,
         }
     }
-    pattern_pairs.into_iter().flatten().collect()
-}
+    /// Set the addresses of function starts so matches of patterns requiring
+    /// [`Pattern::function_start`] are only returned at one of them. Without function starts
+    /// such patterns match anywhere.
+    pub fn function_starts(mut self, starts: impl IntoIterator<Item = usize>) -> Self {
+        let mut starts = starts.into_iter().collect::<Vec<_>>();
+        starts.sort();
+        starts.dedup();
+        self.function_starts = Some(starts.into());
+        self
+    }
+    pub fn patterns(&self) -> &[&'p Pattern] {
+        &self.patterns
+    }
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+    /// Scan `data` located at `base_address`, returning matches for each pattern in the set
+    pub fn scan(&self, base_address: usize, data: &[u8]) -> Vec<Vec<usize>> {
+        self.scan_limited(base_address, data)
+            .into_iter()
+            .map(|m| m.matches)
+            .collect()
+    }
+    /// Same as [`PatternSet::scan`] but also reports which patterns hit their match limit
+    pub fn scan_limited(&self, base_address: usize, data: &[u8]) -> Vec<ScanMatches<usize>> {
+        self.scan_with(data, |p, i| p.match_result(data, base_address, i))
+    }
+    /// Scan `data` located at `base_address`, returning matches along with their captures for
+    /// each pattern in the set
+    pub fn scan_captures<'data>(
+        &self,
+        base_address: usize,
+        data: &'data [u8],
+    ) -> Vec<Vec<Match<'data>>> {
+        self.scan_captures_limited(base_address, data)
+            .into_iter()
+            .map(|m| m.matches)
+            .collect()
+    }
+    /// Same as [`PatternSet::scan_captures`] but also reports which patterns hit their match limit
+    pub fn scan_captures_limited<'data>(
+        &self,
+        base_address: usize,
+        data: &'data [u8],
+    ) -> Vec<ScanMatches<Match<'data>>> {
+        self.scan_with(data, |p, i| p.match_captures(data, base_address, i))
+    }
+    /// Scan `data` calling `f` to verify and build the result for each candidate index
+    fn scan_with<T: Send + MatchAddress>(
+        &self,
+        data: &[u8],
+        f: impl Fn(&Pattern, usize) -> Option<T> + Sync,
+    ) -> Vec<ScanMatches<T>> {
+        use rayon::prelude::*;
+        use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub fn scan_pattern(patterns: &[&Pattern], base_address: usize, data: &[u8]) -> Vec<Vec<usize>> {
-    use rayon::prelude::*;
+        // rejected before counting towards the match limit
+        let f = |p: &Pattern, i| f(p, i).filter(|m| self.is_function_start(p, m.address()));
 
-    let mut result_bins = patterns.iter().map(|_| vec![]).collect::<Vec<_>>();
+        let mut result_bins = self
+            .patterns
+            .iter()
+            .map(|_| ScanMatches::default())
+            .collect::<Vec<_>>();
 
-    if patterns.is_empty() {
-        return result_bins;
-    }
-
-    const WIDE1: usize = 2;
-    const WIDE2: usize = 4;
-
-    let pattern_pairs = group_patterns(patterns);
-
-    let mut all_bins = HashSet::new();
-    let mut short_bins: HashMap<u8, Vec<_>> = Default::default();
-    let mut wide1_bins: HashMap<[u8; WIDE1], Vec<_>> = Default::default();
-    let mut wide2_bins: HashMap<[u8; WIDE2], Vec<_>> = Default::default();
-    for (pi, pair) in pattern_pairs.iter().enumerate() {
-        let p = &pair.partial;
-
-        all_bins.insert(p.sig[0]);
-        if p.mask.iter().take(WIDE2).filter(|m| **m == 0xff).count() == WIDE2 {
-            let mut buf = [0; WIDE2];
-            buf.copy_from_slice(&p.sig[0..WIDE2]);
-            wide2_bins.entry(buf).or_default().push((pi, pair));
-        } else if p.mask.iter().take(WIDE1).filter(|m| **m == 0xff).count() == WIDE1 {
-            let mut buf = [0; WIDE1];
-            buf.copy_from_slice(&p.sig[0..WIDE1]);
-            wide1_bins.entry(buf).or_default().push((pi, pair));
-        } else {
-            short_bins.entry(p.sig[0]).or_default().push((pi, pair));
+        if self.patterns.is_empty() {
+            return result_bins;
         }
-    }
-    let all_bins = Vec::from_iter(all_bins);
 
-    let max = patterns.iter().map(|p| p.simple.len()).max().unwrap();
+        let pattern_pairs = &self.pattern_pairs;
 
-    // cut middle short such that even the longest pattern doesn't have to bounds check
-    let middle = &data[0..data.len().saturating_sub(max)];
-
-    let mut matches = vec![];
-
-    // middle
-    let chunk_size = (middle.len()
-        / std::thread::available_parallelism().unwrap_or(std::num::NonZeroUsize::new(1).unwrap()))
-    .max(1);
-    let chunks: Vec<_> = middle.chunks(chunk_size).enumerate().collect();
-    matches.append(
-        &mut chunks
-            .par_iter()
-            .map(|(index, chunk)| {
-                let mut matches = vec![];
-                let offset = index * chunk_size;
-
-                for first in &all_bins {
-                    for i in memchr::memchr_iter(*first, chunk) {
-                        let j = offset + i;
-                        if let Some(patterns) = short_bins.get(first) {
-                            for (pi, p) in patterns.iter() {
-                                p.add_match(data, base_address, j, *pi, &mut matches)
+        // one more than the limit is needed to know whether the limit was hit
+        let caps = self
+            .patterns
+            .iter()
+            .map(|p| p.limit.map(|l| l + 1))
+            .collect::<Vec<_>>();
+        // index of the first chunk which found enough matches of each pattern on its own, later
+        // chunks can skip the pattern
+        let saturated = self
+            .patterns
+            .iter()
+            .map(|_| AtomicUsize::new(usize::MAX))
+            .collect::<Vec<_>>();
+
+        // cut middle short such that even the longest pattern doesn't have to bounds check
+        let middle = &data[0..data.len().saturating_sub(self.max)];
+
+        let mut matches = vec![];
+
+        // middle
+        let chunk_size = (middle.len()
+            / std::thread::available_parallelism()
+                .unwrap_or(std::num::NonZeroUsize::new(1).unwrap()))
+        .max(1);
+        let chunks: Vec<_> = middle.chunks(chunk_size).enumerate().collect();
+        matches.append(
+            &mut chunks
+                .par_iter()
+                .map(|(index, chunk)| {
+                    let mut matches = vec![];
+                    let offset = index * chunk_size;
+
+                    let mut counts = vec![0; caps.len()];
+                    // stop early once every pattern has been limited
+                    let mut active = caps.len();
+
+                    self.filter
+                        .for_each_candidate(data, offset..offset + chunk.len(), |j| {
+                            let mut add_match = |pi: usize| {
+                                let Some(cap) = caps[pi] else {
+                                    pattern_pairs[pi].add_match(data, j, pi, &mut matches, &f);
+                                    return;
+                                };
+                                if counts[pi] >= cap {
+                                    return;
+                                }
+                                if saturated[pi].load(Ordering::Relaxed) < *index {
+                                    counts[pi] = cap;
+                                    active -= 1;
+                                    return;
+                                }
+                                if pattern_pairs[pi].add_match(data, j, pi, &mut matches, &f) {
+                                    counts[pi] += 1;
+                                    if counts[pi] == cap {
+                                        saturated[pi].fetch_min(*index, Ordering::Relaxed);
+                                        active -= 1;
+                                    }
+                                }
+                            };
+                            if let Some(patterns) = self.short_bins.get(&data[j]) {
+                                for pi in patterns.iter() {
+                                    add_match(*pi)
+                                }
                             }
-                        }
-                        if !wide2_bins.is_empty() {
-                            let mut buf = [0; WIDE2];
-                            buf.copy_from_slice(&data[j..j + WIDE2]);
-                            if let Some(patterns) = wide2_bins.get(&buf) {
-                                for (pi, p) in patterns.iter() {
-                                    p.add_match(data, base_address, j, *pi, &mut matches)
+                            if !self.wide2_bins.is_empty() {
+                                let mut buf = [0; WIDE2];
+                                buf.copy_from_slice(&data[j..j + WIDE2]);
+                                if let Some(patterns) = self.wide2_bins.get(&buf) {
+                                    for pi in patterns.iter() {
+                                        add_match(*pi)
+                                    }
                                 }
                             }
-                        }
-                        if !wide1_bins.is_empty() {
-                            let mut buf = [0; WIDE1];
-                            buf.copy_from_slice(&data[j..j + WIDE1]);
-                            if let Some(patterns) = wide1_bins.get(&buf) {
-                                for (pi, p) in patterns.iter() {
-                                    p.add_match(data, base_address, j, *pi, &mut matches)
+                            if !self.wide1_bins.is_empty() {
+                                let mut buf = [0; WIDE1];
+                                buf.copy_from_slice(&data[j..j + WIDE1]);
+                                if let Some(patterns) = self.wide1_bins.get(&buf) {
+                                    for pi in patterns.iter() {
+                                        add_match(*pi)
+                                    }
                                 }
                             }
-                        }
-                    }
+                            active > 0
+                        });
+                    matches
+                })
+                .flatten()
+                .collect(),
+        );
+
+        // suffix
+        let start = middle.len();
+        for (pi, p) in pattern_pairs.iter().enumerate() {
+            for i in (start.saturating_sub(p.offset))
+                ..start + (data.len() - middle.len()).saturating_sub(p.pattern.simple.len() - 1)
+            {
+                if let Some(result) = f(p.pattern, i) {
+                    matches.push((pi, result));
                 }
-                matches
-            })
-            .flatten()
-            .collect(),
-    );
-
-    // suffix
-    let start = middle.len();
-    for (pi, p) in pattern_pairs.iter().enumerate() {
-        for i in (start.saturating_sub(p.offset))
-            ..start + (data.len() - middle.len()).saturating_sub(p.pattern.simple.len() - 1)
-        {
-            if p.pattern.is_match(data, base_address, i) {
-                matches.push((pi, base_address + i));
             }
         }
+
+        // chunks are in order so the first matches of each pattern are kept
+        for (pi, m) in matches {
+            let bin = &mut result_bins[pi];
+            if caps[pi].is_some_and(|cap| bin.matches.len() + 1 >= cap) {
+                bin.limit_hit = true;
+            } else {
+                bin.matches.push(m);
+            }
+        }
+
+        result_bins
+    }
+    fn is_function_start(&self, pattern: &Pattern, address: usize) -> bool {
+        !pattern.function_start
+            || self
+                .function_starts
+                .as_ref()
+                .map(|starts| starts.binary_search(&address).is_ok())
+                .unwrap_or(true)
     }
+}
 
-    for (pi, addr) in matches {
-        result_bins[pi].push(addr);
+/// Scan result which knows its address
+trait MatchAddress {
+    fn address(&self) -> usize;
+}
+impl MatchAddress for usize {
+    fn address(&self) -> usize {
+        *self
+    }
+}
+impl MatchAddress for Match<'_> {
+    fn address(&self) -> usize {
+        self.address
     }
+}
+
+pub fn scan_pattern(patterns: &[&Pattern], base_address: usize, data: &[u8]) -> Vec<Vec<usize>> {
+    PatternSet::new(patterns).scan(base_address, data)
+}
 
-    result_bins
+pub fn scan_pattern_captures<'data>(
+    patterns: &[&Pattern],
+    base_address: usize,
+    data: &'data [u8],
+) -> Vec<Vec<Match<'data>>> {
+    PatternSet::new(patterns).scan_captures(base_address, data)
 }
 
-pub fn scan_xref(patterns: &[&Xref], base_address: usize, data: &[u8]) -> Vec<Vec<usize>> {
+/// Scan for references to each xref encoded as the given kind
+pub fn scan_xref(
+    patterns: &[(XrefKind, &Xref)],
+    base_address: usize,
+    data: &[u8],
+) -> Vec<Vec<usize>> {
     use rayon::prelude::*;
 
     let mut bins = patterns.iter().map(|_| vec![]).collect::<Vec<_>>();
@@ -532,67 +1204,46 @@ pub fn scan_xref(patterns: &[&Xref], base_address: usize, data: &[u8]) -> Vec<Ve
         return bins;
     }
 
-    let mut patterns = patterns.to_vec();
-    patterns.sort();
-
-    let mut matches = vec![];
-
-    let width = 4;
+    // sorted (address, pattern index) for each kind present
+    let kinds = XrefKind::ALL
+        .into_iter()
+        .filter_map(|kind| {
+            let mut targets = patterns
+                .iter()
+                .enumerate()
+                .filter(|(_, (k, _))| *k == kind)
+                .map(|(i, (_, xref))| (xref.0, i))
+                .collect::<Vec<_>>();
+            targets.sort();
+            (!targets.is_empty()).then_some((kind, targets))
+        })
+        .collect::<Vec<_>>();
 
-    let first_byte_data = &data[0..data.len().saturating_sub(width - 1)];
-    let chunk_size = (first_byte_data.len()
+    let chunk_size = (data.len()
         / std::thread::available_parallelism().unwrap_or(std::num::NonZeroUsize::new(1).unwrap()))
     .max(1);
 
-    let chunks: Vec<_> = first_byte_data.chunks(chunk_size).enumerate().collect();
-    matches.append(
-        &mut chunks
-            .par_iter()
-            .map(|(chunk_index, chunk)| {
-                let mut matches = vec![];
-                let offset = chunk_index * chunk_size;
-
-                for j in offset..offset + chunk.len() {
-                    if let Some(address) = (base_address + width + j).checked_add_signed(
-                        i32::from_le_bytes(data[j..j + width].try_into().unwrap())
-                            .try_into()
-                            .unwrap(),
-                    ) {
-                        if let Ok(i) = patterns.binary_search_by_key(&address, |p| p.0) {
-                            // match found
-                            let addr = base_address + j;
-                            {
-                                // walk backwards until unequal
-                                let mut i = i;
-                                while let Some(prev) =
-                                    (i > 0).then(|| patterns.get(i - 1)).flatten()
-                                {
-                                    if prev.0 != address {
-                                        break;
-                                    }
-                                    matches.push((i - 1, addr));
-                                    i -= 1;
-                                }
-                            }
-                            {
-                                // walk forwards until unequal
-                                let mut i = i;
-                                while let Some(next) = patterns.get(i) {
-                                    if next.0 != address {
-                                        break;
-                                    }
-                                    matches.push((i, addr));
-                                    i += 1;
-                                }
-                            }
+    let chunks: Vec<_> = data.chunks(chunk_size).enumerate().collect();
+    let matches = chunks
+        .par_iter()
+        .map(|(chunk_index, chunk)| {
+            let mut matches = vec![];
+            let offset = chunk_index * chunk_size;
+
+            for j in offset..offset + chunk.len() {
+                for (kind, targets) in &kinds {
+                    if let Some(address) = kind.resolve(data, base_address, j) {
+                        let start = targets.partition_point(|(t, _)| *t < address);
+                        for (_, pi) in targets[start..].iter().take_while(|(t, _)| *t == address) {
+                            matches.push((*pi, base_address + j));
                         }
                     }
                 }
-                matches
-            })
-            .flatten()
-            .collect::<Vec<_>>(),
-    );
+            }
+            matches
+        })
+        .flatten()
+        .collect::<Vec<_>>();
 
     for (pi, addr) in matches {
         bins[pi].push(addr);
@@ -644,6 +1295,12 @@ mod test {
                 custom_offset: 0,
                 captures: vec![],
                 xrefs: vec![],
+                jumps: vec![],
+                alternatives: vec![],
+                constraints: vec![],
+                limit: None,
+                align: 1,
+                function_start: false,
             },
             Pattern::new("00 ??").unwrap()
         );
@@ -656,6 +1313,12 @@ mod test {
                 custom_offset: 0,
                 captures: vec![],
                 xrefs: vec![],
+                jumps: vec![],
+                alternatives: vec![],
+                constraints: vec![],
+                limit: None,
+                align: 1,
+                function_start: false,
             },
             Pattern::new("10 ??").unwrap()
         );
@@ -668,6 +1331,12 @@ mod test {
                 custom_offset: 0,
                 captures: vec![],
                 xrefs: vec![],
+                jumps: vec![],
+                alternatives: vec![],
+                constraints: vec![],
+                limit: None,
+                align: 1,
+                function_start: false,
             },
             Pattern::new("10 ?? 01?10?11").unwrap()
         );
@@ -715,6 +1384,47 @@ mod test {
             Pattern::new("12 ??100??1 45").unwrap().simple.to_string(),
             "12 ??100??1 45"
         );
+
+        // round trip including captures
+        for p in [
+            "48 8D 05 [ ?? ?? ?? ?? ] E8 [ X0x10 ]",
+            "[ 10 [ 20 ] 30 ] [ ] 40",
+            "[ [ 10 20 ] ] | [ 30 ]",
+            "10 [ 20 ] [1-4] [ 30 (4?|5?) ] 40 |",
+            "E8 [ ?? ?? ?? ?? ] | S0x20 A0x1234567890",
+        ] {
+            let pattern = p.parse::<Pattern>().unwrap();
+            assert_eq!(pattern.to_string(), p);
+            assert_eq!(pattern, Pattern::new(pattern.to_string()).unwrap());
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_pattern() {
+        for p in [
+            "12 34 | 56",
+            "48 8d ?? X0x1234 [ ?? ?? ] (48|4C) [2-4] ?3",
+            "e8 S0x20 | [ c3 ] 01?10?11",
+            "00 [ ?? [ ] ] [ 10 20 ]",
+        ] {
+            let pattern = Pattern::new(p).unwrap();
+            let json = serde_json::to_string(&pattern).unwrap();
+            assert_eq!(pattern, serde_json::from_str::<Pattern>(&json).unwrap());
+        }
+        assert!(serde_json::from_str::<Pattern>("\"12 zz\"").is_err());
+
+        // constraints, limits, alignment and function starts have no syntax so would be silently
+        // lost
+        let constrained = Pattern::new("[ ?? ]")
+            .unwrap()
+            .constrain(0, Constraint::Value(1..=2))
+            .unwrap();
+        assert!(serde_json::to_string(&constrained).is_err());
+        assert!(serde_json::to_string(&Pattern::new("12").unwrap().limit(1)).is_err());
+        assert!(serde_json::to_string(&Pattern::new("12").unwrap().align(16)).is_err());
+        assert!(serde_json::to_string(&Pattern::new("12").unwrap().function_start()).is_err());
+        assert!(serde_json::to_string(&Pattern::new("12").unwrap().align(1)).is_ok());
     }
 
     #[test]
@@ -731,6 +1441,12 @@ mod test {
                 custom_offset: 0,
                 captures: vec![2..2, 1..2, 2..4],
                 xrefs: vec![],
+                jumps: vec![],
+                alternatives: vec![],
+                constraints: vec![],
+                limit: None,
+                align: 1,
+                function_start: false,
             },
             Pattern::new("00 [ ?? [ ] ] [ 10 20 ]").unwrap()
         );
@@ -738,7 +1454,8 @@ mod test {
         assert_eq!(
             Some(vec![Capture {
                 address: 100 + 3,
-                data: &[0x99]
+                data: &[0x99],
+                rip: None
             }]),
             Pattern::new("10 20 30 [ ?? ]")
                 .unwrap()
@@ -748,7 +1465,8 @@ mod test {
         assert_eq!(
             Some(vec![Capture {
                 address: 100 + 2,
-                data: &[0x30]
+                data: &[0x30],
+                rip: None
             }]),
             Pattern::new("20 [ ?? ]")
                 .unwrap()
@@ -756,19 +1474,85 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_jumps_and_alternatives() {
+        assert!(Pattern::new("[2-4] 10").is_err());
+        assert!(Pattern::new("10 [2-4]").is_err());
+        assert!(Pattern::new("10 [4-2] 20").is_err());
+        assert!(Pattern::new("10 (48) 20").is_err());
+        assert!(Pattern::new("10 (48|zz) 20").is_err());
+        assert_eq!(
+            Pattern {
+                simple: PatternSimple {
+                    sig: vec![0x10, 0x48, 0x20],
+                    mask: vec![0xff, 0xfb, 0xff],
+                },
+                custom_offset: 2,
+                captures: vec![],
+                xrefs: vec![],
+                jumps: vec![Jump {
+                    offset: 2,
+                    min: 2,
+                    max: 8
+                }],
+                alternatives: vec![(1, vec![(0x48, 0xff), (0x4c, 0xff)])],
+                constraints: vec![],
+                limit: None,
+                align: 1,
+                function_start: false,
+            },
+            Pattern::new("10 (48|4C) [2-8] | 20").unwrap()
+        );
+
+        for p in [
+            "10 (48|4C) [2-8] | 20",
+            "(48|4C) 8B [3] 05",
+            "E8 [1-2] X0x10 (4?|0101??1?)",
+        ] {
+            assert_eq!(Pattern::new(p).unwrap().to_string(), p);
+        }
+
+        let pattern = Pattern::new("10 (48|4C) [1-3] [ 20 ] | 30").unwrap();
+        let data = b"\x10\x4c\x00\x00\x20\x30\x10\x48\x20\x30\x10\x48\x20";
+        assert!(pattern.is_match(data, 0, 0));
+        assert!(!pattern.is_match(data, 0, 6));
+        assert!(!pattern.is_match(data, 0, 10));
+        assert_eq!(
+            Some(vec![Capture {
+                address: 100 + 4,
+                data: &[0x20],
+                rip: None
+            }]),
+            pattern.captures(data, 100, 0)
+        );
+        assert_eq!(105, pattern.compute_result(data, 100, 0));
+
+        assert_eq!(
+            vec![vec![105, 118]],
+            scan_pattern(
+                &[&pattern],
+                100,
+                b"\x10\x48\x00\x00\x20\x30\x10\x4c\x00\x00\x00\x00\x20\x30\x10\x4c\x00\x20\x30"
+            )
+        );
+    }
+
     #[test]
     fn test_group_patterns() {
         // simple
         assert_eq!(
-            group_patterns(&[
-                &Pattern::new("12 34").unwrap(),
-                &Pattern::new("34 56").unwrap(),
-            ]),
+            frequency::group_patterns(
+                &[
+                    &Pattern::new("12 34").unwrap(),
+                    &Pattern::new("34 56").unwrap(),
+                ],
+                ByteFrequencies::x86_64()
+            ),
             vec![
                 PatternPair {
                     pattern: &Pattern::new("12 34").unwrap(),
-                    partial: Pattern::new("34").unwrap().simple,
-                    offset: 1,
+                    partial: Pattern::new("12 34").unwrap().simple,
+                    offset: 0,
                 },
                 PatternPair {
                     pattern: &Pattern::new("34 56").unwrap(),
@@ -778,72 +1562,54 @@ mod test {
             ]
         );
 
-        // duplicate bytes
+        // common bytes are avoided
         assert_eq!(
-            group_patterns(&[
-                &Pattern::new("12 12 12 34").unwrap(),
-                &Pattern::new("34 56 12").unwrap(),
-            ]),
-            vec![
-                PatternPair {
-                    pattern: &Pattern::new("12 12 12 34").unwrap(),
-                    partial: Pattern::new("12 12 12 34").unwrap().simple,
-                    offset: 0,
-                },
-                PatternPair {
-                    pattern: &Pattern::new("34 56 12").unwrap(),
-                    partial: Pattern::new("12").unwrap().simple,
-                    offset: 2,
-                },
-            ]
+            frequency::group_patterns(
+                &[&Pattern::new("00 00 48 8b 05 9a").unwrap(),],
+                ByteFrequencies::x86_64()
+            ),
+            vec![PatternPair {
+                pattern: &Pattern::new("00 00 48 8b 05 9a").unwrap(),
+                partial: Pattern::new("05 9a").unwrap().simple,
+                offset: 4,
+            },]
         );
 
-        // multiple possible groupings (use first)
+        // anchor must be before the first jump
         assert_eq!(
-            group_patterns(&[
-                &Pattern::new("12 34 56").unwrap(),
-                &Pattern::new("34 56").unwrap(),
-            ]),
-            vec![
-                PatternPair {
-                    pattern: &Pattern::new("12 34 56").unwrap(),
-                    partial: Pattern::new("34 56").unwrap().simple,
-                    offset: 1,
-                },
-                PatternPair {
-                    pattern: &Pattern::new("34 56").unwrap(),
-                    partial: Pattern::new("34 56").unwrap().simple,
-                    offset: 0,
-                },
-            ]
+            frequency::group_patterns(
+                &[&Pattern::new("12 34 [1-2] 9a bc").unwrap(),],
+                ByteFrequencies::x86_64()
+            ),
+            vec![PatternPair {
+                pattern: &Pattern::new("12 34 [1-2] 9a bc").unwrap(),
+                partial: Pattern::new("12 34").unwrap().simple,
+                offset: 0,
+            },]
         );
 
-        // test bans
+        // single fixed byte
         assert_eq!(
-            group_patterns(&[
-                &Pattern::new("12 00").unwrap(),
-                &Pattern::new("00 56").unwrap(),
-            ]),
-            vec![
-                PatternPair {
-                    pattern: &Pattern::new("12 00").unwrap(),
-                    partial: Pattern::new("12 00").unwrap().simple,
-                    offset: 0,
-                },
-                PatternPair {
-                    pattern: &Pattern::new("00 56").unwrap(),
-                    partial: Pattern::new("56").unwrap().simple,
-                    offset: 1,
-                },
-            ]
+            frequency::group_patterns(
+                &[&Pattern::new("00 ?? 9a").unwrap(),],
+                ByteFrequencies::x86_64()
+            ),
+            vec![PatternPair {
+                pattern: &Pattern::new("00 ?? 9a").unwrap(),
+                partial: Pattern::new("9a").unwrap().simple,
+                offset: 2,
+            },]
         );
 
-        // test bans
+        // no fixed byte
         assert_eq!(
-            group_patterns(&[&Pattern::new("00").unwrap(),]),
+            frequency::group_patterns(
+                &[&Pattern::new("4? ?8").unwrap(),],
+                ByteFrequencies::x86_64()
+            ),
             vec![PatternPair {
-                pattern: &Pattern::new("00").unwrap(),
-                partial: Pattern::new("00").unwrap().simple,
+                pattern: &Pattern::new("4? ?8").unwrap(),
+                partial: Pattern::new("4? ?8").unwrap().simple,
                 offset: 0,
             },]
         );
@@ -852,13 +1618,83 @@ mod test {
     type PatternScanFn =
         fn(patterns: &[&Pattern], base_address: usize, data: &[u8]) -> Vec<Vec<usize>>;
 
-    type XrefScanFn = fn(patterns: &[&Xref], base_address: usize, data: &[u8]) -> Vec<Vec<usize>>;
+    type XrefScanFn =
+        fn(patterns: &[(XrefKind, &Xref)], base_address: usize, data: &[u8]) -> Vec<Vec<usize>>;
 
     #[test]
     fn test_scan_pattern() {
         test_scan_algo(scan_pattern);
     }
 
+    #[test]
+    fn test_scan_pattern_set() {
+        test_scan_algo(|patterns, base_address, data| {
+            PatternSet::new(patterns).scan(base_address, data)
+        });
+
+        let patterns = [
+            &Pattern::new("01 02").unwrap(),
+            &Pattern::new("02 ?? 01").unwrap(),
+        ];
+        let set = PatternSet::new(&patterns);
+        let data = [1, 2, 3].repeat(8);
+        for base_address in [0, 0x1000] {
+            assert_eq!(
+                scan_pattern(&patterns, base_address, &data),
+                set.scan(base_address, &data)
+            );
+        }
+        assert!(PatternSet::new(&[]).scan(0, &data).is_empty());
+    }
+
+    #[test]
+    fn test_scan_pattern_captures() {
+        test_scan_algo(|patterns, base_address, data| {
+            scan_pattern_captures(patterns, base_address, data)
+                .into_iter()
+                .map(|m| m.into_iter().map(|m| m.address).collect())
+                .collect()
+        });
+
+        let pattern = Pattern::new("e8 [ ?? ?? ?? ?? ] | [ c3 ]").unwrap();
+        let data = b"\x90\xe8\x10\x00\x00\x00\xc3\xe8\xf0\xff\xff\xff\xc3";
+        assert_eq!(
+            vec![vec![
+                Match {
+                    address: 106,
+                    captures: vec![
+                        Capture {
+                            address: 102,
+                            data: &[0x10, 0, 0, 0],
+                            rip: Some(106 + 0x10),
+                        },
+                        Capture {
+                            address: 106,
+                            data: &[0xc3],
+                            rip: None,
+                        },
+                    ],
+                },
+                Match {
+                    address: 112,
+                    captures: vec![
+                        Capture {
+                            address: 108,
+                            data: &[0xf0, 0xff, 0xff, 0xff],
+                            rip: Some(112 - 0x10),
+                        },
+                        Capture {
+                            address: 112,
+                            data: &[0xc3],
+                            rip: None,
+                        },
+                    ],
+                },
+            ]],
+            scan_pattern_captures(&[&pattern], 100, data)
+        );
+    }
+
     fn test_scan_algo(scan: PatternScanFn) {
         let patterns = [&Pattern::new("01").unwrap()];
 
@@ -900,14 +1736,192 @@ mod test {
 
     fn test_scan_xref_algo(scan: XrefScanFn) {
         let scans = [
-            &Xref(0x504030a),
-            &Xref(0x504030a),
-            &Xref(0x504030a),
-            &Xref(0x504030a),
+            (XrefKind::Rel32, &Xref(0x504030a)),
+            (XrefKind::Rel32, &Xref(0x504030a)),
+            (XrefKind::Rel32, &Xref(0x504030a)),
+            (XrefKind::Rel32, &Xref(0x504030a)),
         ];
 
         let mut res = scan(&scans, 3, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
         res.sort();
         assert_eq!(vec![vec![4], vec![4], vec![4], vec![4]], res);
+
+        let data = [
+            0x10, 0x20, 0x30, 0x40, 0x50, 0x60, 0x70, 0x80, 0xfe, 0x00, 0x00, 0x00,
+        ];
+        let scans = [
+            (XrefKind::Abs64, &Xref(0x8070605040302010)),
+            (XrefKind::Rel8, &Xref(100 + 9 - 2)),
+            (XrefKind::Abs32, &Xref(0x80706050)),
+            (XrefKind::Rel32, &Xref(100 + 12 + 0xfe)),
+            (XrefKind::Rel8, &Xref(100 + 1 + 0x10)),
+        ];
+        assert_eq!(
+            vec![vec![100], vec![108], vec![104], vec![108], vec![100]],
+            scan(&scans, 100, &data)
+        );
+    }
+
+    #[test]
+    fn test_xref_kinds() {
+        let pattern = Pattern::new("e8 X0x10 eb S0x20 b8 I0x30 | A0x40 c3").unwrap();
+        assert_eq!(
+            vec![
+                (1, XrefKind::Rel32, Xref(0x10)),
+                (6, XrefKind::Rel8, Xref(0x20)),
+                (8, XrefKind::Abs32, Xref(0x30)),
+                (12, XrefKind::Abs64, Xref(0x40)),
+            ],
+            pattern.xrefs
+        );
+        assert_eq!(21, pattern.simple.len());
+        assert_eq!(12, pattern.custom_offset);
+        assert_eq!(pattern.to_string(), "E8 X0x10 EB S0x20 B8 I0x30 | A0x40 C3");
+        assert!(Pattern::new("S0xzz").is_err());
+
+        let data = [
+            [0xe8, 0x0b, 0, 0, 0].as_slice(),
+            &[0xeb, 0x19],
+            &[0xb8, 0x30, 0, 0, 0],
+            &0x40u64.to_le_bytes(),
+            &[0xc3],
+        ]
+        .concat();
+        assert!(pattern.is_match(&data, 0, 0));
+        assert!(!pattern.is_match(&data, 1, 0));
+        assert_eq!(vec![vec![12]], scan_pattern(&[&pattern], 0, &data));
+    }
+
+    #[test]
+    fn test_constraints() {
+        assert!(Pattern::new("c7 03 [ ?? ]")
+            .unwrap()
+            .constrain(1, Constraint::Value(0..=1))
+            .is_err());
+
+        let pattern = Pattern::new("c7 03 | [ ?? ?? ] [ ?? ?? ]")
+            .unwrap()
+            .constrain(0, Constraint::Value(4..=5))
+            .unwrap()
+            .constrain(1, Constraint::Value(0..=27))
+            .unwrap();
+        let data = b"\xc7\x03\x04\x00\x1b\x00\xc7\x03\x06\x00\x01\x00\xc7\x03\x05\x00\x1c\x00";
+        assert_eq!(vec![vec![2]], scan_pattern(&[&pattern], 0, data));
+
+        let data = b"\xe8\x10\x00\x00\x00\xe8\x20\x00\x00\x00\xe8\x30\x00\x00\x00";
+        let pattern = Pattern::new("e8 [ ?? ?? ?? ?? ]").unwrap();
+        let ranges = pattern
+            .clone()
+            .constrain(
+                0,
+                Constraint::rip_in_ranges([0x40..0x50, 0x1a..0x20, 0x1f..0x22]),
+            )
+            .unwrap();
+        let set = pattern
+            .constrain(0, Constraint::rip_in_set([0x34, 0x15]))
+            .unwrap();
+        assert_eq!(
+            vec![vec![10, 20], vec![15]],
+            scan_pattern(&[&ranges, &set], 10, data)
+        );
+
+        // constraints are checked before accepting a gap so other gaps are still tried
+        let pattern = Pattern::new("10 [0-2] [ ?? ] 20")
+            .unwrap()
+            .constrain(0, Constraint::Value(5..=5))
+            .unwrap();
+        assert_eq!(
+            Some(vec![Capture {
+                address: 3,
+                data: &[0x05],
+                rip: None,
+            }]),
+            pattern.captures(b"\x10\x01\x20\x05\x20", 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut data = vec![0; 100_000];
+        let positions = (0..data.len() - 2).step_by(997).collect::<Vec<_>>();
+        for &i in &positions {
+            data[i..i + 2].copy_from_slice(&[0x12, 0x34]);
+        }
+        data[500..502].copy_from_slice(&[0x56, 0x78]);
+
+        let pattern = Pattern::new("12 34").unwrap();
+        let unique = Pattern::new("56 78").unwrap();
+        let patterns = [
+            pattern.clone(),
+            pattern.clone().limit(5),
+            pattern.clone().first_match(),
+            pattern.clone().limit(positions.len()),
+            pattern.limit(0),
+            unique.first_match(),
+        ];
+        let set = PatternSet::new(&patterns.iter().collect::<Vec<_>>());
+
+        let limited = |matches: &[usize], limit_hit| ScanMatches {
+            matches: matches.to_vec(),
+            limit_hit,
+        };
+        assert_eq!(
+            vec![
+                limited(&positions, false),
+                limited(&positions[..5], true),
+                limited(&positions[..1], true),
+                limited(&positions, false),
+                limited(&[], true),
+                limited(&[500], false),
+            ],
+            set.scan_limited(0, &data)
+        );
+    }
+
+    #[test]
+    fn test_no_fixed_bytes() {
+        let data = b"xxABxabxAbxx";
+        // case insensitive "ab", no byte of either pattern is fully fixed
+        let pattern = Pattern::new("01?00001 01?00010").unwrap();
+        let nibbles = Pattern::new("?1 6?").unwrap();
+        let fixed = Pattern::new("62").unwrap();
+        let set = PatternSet::new(&[&pattern, &nibbles, &fixed]);
+        assert_eq!(
+            vec![vec![2, 5, 8], vec![5, 8], vec![6, 9]],
+            set.scan(0, data)
+        );
+    }
+
+    #[test]
+    fn test_align_function_start() {
+        let mut data = vec![0; 40];
+        for i in [3, 8, 16, 21] {
+            data[i..i + 3].copy_from_slice(&[0xe8, 0x12, 0x34]);
+        }
+        let base_address = 0x1000;
+
+        let aligned = Pattern::new("e8 12 34").unwrap().align(8);
+        let offset = Pattern::new("e8 | 12 34").unwrap().align(4);
+        let start = Pattern::new("e8 12 34").unwrap().function_start().limit(1);
+        let patterns = [&aligned, &offset, &start];
+
+        // function starts are unknown so the requirement is documented to be unchecked
+        assert_eq!(
+            vec![vec![0x1008, 0x1010], vec![0x1004], vec![0x1003]],
+            PatternSet::new(&patterns).scan(base_address, &data)
+        );
+        assert_eq!(
+            ScanMatches {
+                matches: vec![0x1010],
+                limit_hit: true,
+            },
+            PatternSet::new(&patterns)
+                .function_starts([0x1100, 0x1015, 0x1010])
+                .scan_limited(base_address, &data)[2]
+        );
+        assert_eq!(
+            Some(0x1010),
+            aligned.find_forward(&data, base_address, 9, 100)
+        );
     }
 }
@@ -0,0 +1,97 @@
+//! Bounded searches for the nearest match from a position
+
+use crate::Pattern;
+
+impl Pattern {
+    /// Find the first match starting at or after `index` and at most `max_distance` bytes past
+    /// it. Returns the address of the match as [`Pattern::compute_result`] would.
+    ///
+    /// [`Pattern::function_start`] is not checked.
+    pub fn find_forward(
+        &self,
+        data: &[u8],
+        base_address: usize,
+        index: usize,
+        max_distance: usize,
+    ) -> Option<usize> {
+        let end = index
+            .saturating_add(max_distance)
+            .min(self.last_start(data)?);
+        (index..=end).find_map(|i| self.match_result(data, base_address, i))
+    }
+    /// Find the closest match starting at or before `index` and at most `max_distance` bytes
+    /// before it. Returns the address of the match as [`Pattern::compute_result`] would.
+    ///
+    /// [`Pattern::function_start`] is not checked.
+    pub fn find_backward(
+        &self,
+        data: &[u8],
+        base_address: usize,
+        index: usize,
+        max_distance: usize,
+    ) -> Option<usize> {
+        let start = index.saturating_sub(max_distance);
+        let end = index.min(self.last_start(data)?);
+        (start..=end)
+            .rev()
+            .find_map(|i| self.match_result(data, base_address, i))
+    }
+    /// Last index a match could start at without running past the end of `data`
+    fn last_start(&self, data: &[u8]) -> Option<usize> {
+        data.len().checked_sub(self.min_len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let data = [
+            0xcc, 0xcc, 0x48, 0x89, 0x5c, 0x24, 0x08, 0xcc, 0xcc, 0xcc, 0x48, 0x89, 0x5c, 0x24,
+            0x10, 0xc3,
+        ];
+        let base_address = 0x1000;
+        let padding = Pattern::new("cc cc").unwrap();
+        let prologue = Pattern::new("cc | 48 89 5c 24 ??").unwrap();
+
+        assert_eq!(
+            Some(0x1000),
+            padding.find_forward(&data, base_address, 0, 0)
+        );
+        assert_eq!(
+            Some(0x1007),
+            padding.find_forward(&data, base_address, 1, 6)
+        );
+        assert_eq!(None, padding.find_forward(&data, base_address, 1, 5));
+        assert_eq!(None, padding.find_forward(&data, base_address, 9, 100));
+
+        assert_eq!(
+            Some(0x1008),
+            padding.find_backward(&data, base_address, 15, 100)
+        );
+        assert_eq!(None, padding.find_backward(&data, base_address, 6, 4));
+        assert_eq!(
+            Some(0x1000),
+            padding.find_backward(&data, base_address, 6, 6)
+        );
+
+        // results honor the custom offset
+        assert_eq!(
+            Some(0x100a),
+            prologue.find_forward(&data, base_address, 2, 100)
+        );
+        assert_eq!(
+            Some(0x1002),
+            prologue.find_backward(&data, base_address, 8, 100)
+        );
+
+        // jumps are bounds checked at the end of data
+        let jump = Pattern::new("24 [0-4] c3").unwrap();
+        assert_eq!(
+            Some(0x100d),
+            jump.find_backward(&data, base_address, 15, 15)
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! Scanning memory provided as a sequence of chunks rather than one contiguous slice
+
+use crate::{PatternSet, ScanMatches};
+
+impl PatternSet<'_> {
+    /// Scan memory provided as `(address, chunk)` blocks in ascending address order, such as the
+    /// readable pages of a process, without materialising it as one slice.
+    ///
+    /// Chunks which directly follow each other are treated as contiguous so matches spanning
+    /// chunk boundaries are found by carrying over the last `max_len - 1` bytes of each chunk.
+    /// Nothing matches across a gap between chunks. Match limits apply to the whole scan.
+    pub fn scan_chunks<B: AsRef<[u8]>>(
+        &self,
+        chunks: impl IntoIterator<Item = (usize, B)>,
+    ) -> Vec<ScanMatches<usize>> {
+        let overlap = self
+            .patterns
+            .iter()
+            .map(|p| p.max_len())
+            .max()
+            .unwrap_or(0)
+            .saturating_sub(1);
+
+        let mut results = self
+            .patterns
+            .iter()
+            .map(|_| ScanMatches::default())
+            .collect::<Vec<_>>();
+        // tail of the contiguous run which has not been scanned yet
+        let mut buffer: Vec<u8> = vec![];
+        let mut buffer_address = 0;
+        for (address, chunk) in chunks {
+            if buffer_address + buffer.len() != address {
+                self.scan_block(buffer_address, &buffer, buffer.len(), &mut results);
+                buffer.clear();
+                buffer_address = address;
+            }
+            buffer.extend_from_slice(chunk.as_ref());
+
+            // only matches starting before the overlap are known to be complete
+            let end = buffer.len().saturating_sub(overlap);
+            self.scan_block(buffer_address, &buffer, end, &mut results);
+            buffer.drain(..end);
+            buffer_address += end;
+        }
+        self.scan_block(buffer_address, &buffer, buffer.len(), &mut results);
+
+        results
+    }
+    /// Scan `data` for matches starting before `end` and append them to `results`
+    fn scan_block(
+        &self,
+        base_address: usize,
+        data: &[u8],
+        end: usize,
+        results: &mut [ScanMatches<usize>],
+    ) {
+        if end == 0 {
+            return;
+        }
+        let found = self.scan_with(data, |p, i| {
+            (i < end)
+                .then(|| p.match_result(data, base_address, i))
+                .flatten()
+        });
+        for ((result, found), pattern) in results.iter_mut().zip(found).zip(&self.patterns) {
+            result.matches.extend(found.matches);
+            result.limit_hit |= found.limit_hit;
+            if let Some(limit) = pattern.limit {
+                if result.matches.len() > limit {
+                    result.matches.truncate(limit);
+                    result.limit_hit = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Pattern;
+
+    use super::*;
+
+    #[test]
+    fn test_scan_chunks() {
+        let mut data = (0..20_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect::<Vec<_>>();
+        for i in (100..data.len() - 100).step_by(331) {
+            data[i..i + 6].copy_from_slice(&[0x48, 0x8b, 0x05, 0x11, 0x22, 0xe8]);
+        }
+
+        let patterns = [
+            Pattern::new("48 8b 05 ?? ?? e8").unwrap(),
+            Pattern::new("8b [0-3] e8").unwrap(),
+            Pattern::new("48 8b 05 | 11 22").unwrap().limit(10),
+        ];
+        let set = PatternSet::new(&patterns.iter().collect::<Vec<_>>());
+        let base_address = 0x1000;
+        let expected = set.scan_limited(base_address, &data);
+        assert!(expected[2].limit_hit);
+
+        for chunk_size in [1, 7, 331, 4096, data.len()] {
+            let chunks = data
+                .chunks(chunk_size)
+                .enumerate()
+                .map(|(i, c)| (base_address + i * chunk_size, c));
+            assert_eq!(expected, set.scan_chunks(chunks), "chunk size {chunk_size}");
+        }
+
+        // nothing matches across a gap, limited pattern is skipped as it would find a later match
+        let split = 100 + 331 * 3 + 2;
+        let chunks = [
+            (base_address, &data[..split]),
+            (base_address + split + 1, &data[split + 1..]),
+        ];
+        let expected = expected[..2]
+            .iter()
+            .cloned()
+            .map(|mut result| {
+                result
+                    .matches
+                    .retain(|m| !(base_address + split - 6..base_address + split + 6).contains(m));
+                result
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(expected, set.scan_chunks(chunks)[..2]);
+    }
+}